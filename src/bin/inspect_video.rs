@@ -2,9 +2,8 @@ extern crate structopt;
 use std::fs::File;
 use structopt::StructOpt;
 use std::path::PathBuf;
-use std::io::Read;
 use bitstream_io::{BitReader, BigEndian};
-use h264_glitcher::h264::{NalIterator, NalUnit, NALUnitType, SliceHeader, Sps, Pps};
+use h264_glitcher::h264::{NalReader, NalUnit, NALUnitType, ParameterSets, SliceHeader, Sps, Pps, Sei};
 
 
 #[derive(Debug, StructOpt)]
@@ -25,11 +24,15 @@ fn main() -> std::io::Result<()> {
     let input_file = File::open(opt.input)?;
     let file = std::io::BufReader::with_capacity(1<<20, input_file);
 
-    let it = NalIterator::new(file.bytes().map(|x| x.unwrap()));
-    let it = it.map(|v| NalUnit::from_bytes(&v));
+    let it = NalReader::new(file);
+    let it = it.map(|v| NalUnit::from_bytes(&v?));
 
     let it = it.take(opt.limit.unwrap_or(usize::MAX));
 
+    let mut parameter_sets = ParameterSets::new();
+    // SEI messages refer to the SPS which is active, assume it is the last one sent
+    let mut last_sps_id = None;
+
     for nal_unit in it {
         match nal_unit {
             Err(e) => println!("Failed to parse NAL: {:?}", e),
@@ -37,23 +40,41 @@ fn main() -> std::io::Result<()> {
                 println!("{}", nal_unit);
                 match nal_unit.nal_unit_type {
                     NALUnitType::Sps => {
-                        let sps = Sps::read(&mut BitReader::endian(nal_unit.rbsp.as_slice(), BigEndian));
-                        println!("{:?}", nal_unit.rbsp);
+                        let sps = Sps::read(&mut BitReader::endian(nal_unit.rbsp(), BigEndian));
+                        println!("{:?}", nal_unit.rbsp());
                         match sps {
                             Err(e) => println!("Failed to parse SPS: {:?}", e),
-                            Ok(sps) => println!("{:?}", sps)
+                            Ok(sps) => {
+                                println!("{:?}", sps);
+                                last_sps_id = Some(sps.seq_parameter_set_id);
+                                parameter_sets.insert_sps(sps);
+                            }
                         }
                     }
                     NALUnitType::Pps => {
-                        let pps = Pps::read(&mut BitReader::endian(nal_unit.rbsp.as_slice(), BigEndian));
-                        println!("{:?}", nal_unit.rbsp);
+                        let pps = Pps::read(&mut BitReader::endian(nal_unit.rbsp(), BigEndian), &parameter_sets);
+                        println!("{:?}", nal_unit.rbsp());
                         match pps {
-                            Err(e) => println!("Failed to parse SPS: {:?}", e),
-                            Ok(sps) => println!("{:?}", sps)
+                            Err(e) => println!("Failed to parse PPS: {:?}", e),
+                            Ok(pps) => {
+                                println!("{:?}", pps);
+                                parameter_sets.insert_pps(pps);
+                            }
+                        }
+                    }
+                    NALUnitType::Sei => {
+                        let sps = last_sps_id.and_then(|id| parameter_sets.sps(id));
+                        match Sei::from_bytes(nal_unit.rbsp(), sps) {
+                            Err(e) => println!("Failed to parse SEI: {:?}", e),
+                            Ok(sei) => {
+                                for message in sei.messages {
+                                    println!("{}", message);
+                                }
+                            }
                         }
                     }
                     NALUnitType::CodedSliceIdr | NALUnitType::CodedSliceNonIdr => {
-                        let header = SliceHeader::from_bytes(&nal_unit.rbsp);
+                        let header = SliceHeader::from_bytes(nal_unit.rbsp(), nal_unit.nal_unit_type, nal_unit.nal_ref_idc, &parameter_sets);
                         match header {
                             Err(e) => println!("Failed to parse slice header: {:?}", e),
                             Ok(header) => println!("{}", header)
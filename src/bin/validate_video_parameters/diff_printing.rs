@@ -53,6 +53,22 @@ fn remove_same_tuple(value: Tuple) -> Option<Tuple> {
     }
 }
 
+fn remove_same_sequence(elements: Vec<Element>) -> Option<Vec<Element>> {
+    let elements: Vec<_> = elements
+        .into_iter()
+        .filter_map(|e| match e {
+            Element::Both(v) => remove_same(v).map(Element::Both),
+            _ => Some(e),
+        })
+        .collect();
+
+    if !elements.is_empty() {
+        Some(elements)
+    } else {
+        None
+    }
+}
+
 fn remove_same(value: Value) -> Option<Value> {
     match value {
         Value::Same(_, _) => None,
@@ -60,6 +76,7 @@ fn remove_same(value: Value) -> Option<Value> {
         Value::Struct(s) => remove_same_struct(s).map(|v| Value::Struct(v)),
         Value::Tuple(t) => remove_same_tuple(t).map(|v| Value::Tuple(v)),
         Value::Enum(e) => remove_same_enum(e).map(|v| Value::Enum(v)),
+        Value::Sequence(s) => remove_same_sequence(s).map(Value::Sequence),
         _ => unimplemented!(),
     }
 }
@@ -79,6 +96,7 @@ fn print_value(value: Value, name: Option<&str>, indent: usize) {
         Value::Struct(s) => print_struct(s, indent),
         Value::Tuple(t) => print_tuple(t, indent),
         Value::Enum(e) => print_enum(e, name.unwrap(), indent),
+        Value::Sequence(s) => print_sequence(s, name.unwrap(), indent),
         _ => unimplemented!(),
     }
 }
@@ -128,3 +146,15 @@ fn print_tuple(value: Tuple, indent: usize) {
     }
     println!("{:indent$})", "", indent = indent);
 }
+
+fn print_sequence(elements: Vec<Element>, name: &str, indent: usize) {
+    println!("{:indent$}{}: [", "", name, indent = indent);
+    for e in elements {
+        match e {
+            Element::LeftOnly(a) => println!("{:indent$}{}", "", a.green(), indent = indent + 1),
+            Element::RightOnly(b) => println!("{:indent$}{}", "", b.red(), indent = indent + 1),
+            Element::Both(v) => print_value(v, Some("-"), indent + 1),
+        }
+    }
+    println!("{:indent$}]", "", indent = indent);
+}
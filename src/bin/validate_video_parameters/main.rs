@@ -1,10 +1,8 @@
 extern crate structopt;
-use bitstream_io::{BigEndian, BitReader};
 use colored::Colorize;
-use h264_glitcher::h264::{NALUnitType, NalIterator, NalUnit, Pps, Sps};
+use h264_glitcher::h264::{NalReader, NalUnit, ParameterSets, ParseError, Sps};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 mod diff_printing;
@@ -41,71 +39,91 @@ fn check_for_assumptions(sps: &Sps) {
             "separate_colour_plane_flag is set. We are assuming it is not set.".red()
         );
     }
-    if sps.log2_max_frame_num_minus4 != 0 {
+    if !sps.frame_mbs_only_flag {
         println!(
             "{}",
-            "log2_max_frame_num_minus4 is != 0. We are assuming it is 0.".red()
+            "frame_mbs_only_flag is not set. We are assuming there are no field pictures.".red()
         );
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let opt = Opt::from_args();
-
-    let mut reference_sps = None;
-    let mut reference_pps = None;
+fn open_video_file(path: &Path) -> std::io::Result<impl Iterator<Item = Result<NalUnit, ParseError>>> {
+    let input_file = File::open(path)?;
+    let file = std::io::BufReader::with_capacity(1 << 20, input_file);
 
-    let open_video_file = |path| -> Result<_, std::io::Error> {
-        let input_file = File::open(path)?;
-        let file = std::io::BufReader::with_capacity(1 << 20, input_file);
+    let it = NalReader::new(file);
+    Ok(it.map(|v| NalUnit::from_bytes(&v?)))
+}
 
-        let it = NalIterator::new(file.bytes().map(|x| x.unwrap()));
-        Ok(it.map(|v| NalUnit::from_bytes(&v)))
-    };
+/// Collects the parameter sets of a video.
+///
+/// Also checks that all slice headers can be parsed with the parameter sets they refer to.
+fn load_parameter_sets(path: &Path) -> std::io::Result<ParameterSets> {
+    let mut parameter_sets = ParameterSets::new();
+    let mut num_slices = 0;
+    let mut num_failed_slices = 0;
 
-    for nal_unit in open_video_file(opt.reference.clone())? {
+    for nal_unit in open_video_file(path)? {
         match nal_unit {
             Err(e) => println!("Failed to parse NAL: {:?}", e),
-            Ok(nal_unit) => match nal_unit.nal_unit_type {
-                NALUnitType::Sps => {
-                    let sps =
-                        Sps::read(&mut BitReader::endian(nal_unit.rbsp.as_slice(), BigEndian));
-                    match sps {
-                        Err(e) => println!("Failed to parse reference SPS: {:?}", e),
-                        Ok(sps) => {
-                            println!(
-                                "Reference video file {:?}: {}",
-                                opt.reference,
-                                "Found reference SPS".green()
-                            );
-                            if opt.diff {
-                                println!("{:#?}", sps);
-                            }
-                            check_for_assumptions(&sps);
-                            reference_sps = Some(sps);
+            Ok(nal_unit) => {
+                if nal_unit.nal_unit_type.is_picture_data() {
+                    num_slices += 1;
+                    if let Err(e) = parameter_sets.slice_header(&nal_unit) {
+                        if num_failed_slices == 0 {
+                            println!("Failed to parse slice header: {:?}", e);
                         }
+                        num_failed_slices += 1;
                     }
+                } else if let Err(e) = parameter_sets.insert_nal_unit(&nal_unit) {
+                    println!("Failed to parse {:?}: {:?}", nal_unit.nal_unit_type, e);
                 }
-                NALUnitType::Pps => {
-                    let pps =
-                        Pps::read(&mut BitReader::endian(nal_unit.rbsp.as_slice(), BigEndian));
-                    match pps {
-                        Err(e) => println!("Failed to parse reference PPS: {:?}", e),
-                        Ok(pps) => {
-                            println!(
-                                "Reference video file {:?}: {}",
-                                opt.reference,
-                                "Found reference PPS".green()
-                            );
-                            if opt.diff {
-                                println!("{:#?}", pps);
-                            }
-                            reference_pps = Some(pps);
-                        }
-                    }
-                }
-                _ => {}
-            },
+            }
+        }
+    }
+
+    if num_failed_slices > 0 {
+        println!(
+            "Video file {:?}: {}",
+            path,
+            format!(
+                "{} of {} slice headers could not be parsed",
+                num_failed_slices, num_slices
+            )
+            .red()
+        );
+    }
+
+    Ok(parameter_sets)
+}
+
+fn main() -> std::io::Result<()> {
+    let opt = Opt::from_args();
+
+    let reference_parameter_sets = load_parameter_sets(&opt.reference)?;
+
+    let reference_sps = reference_parameter_sets.all_sps().next().cloned();
+    let reference_pps = reference_parameter_sets.all_pps().next().cloned();
+
+    if let Some(sps) = &reference_sps {
+        println!(
+            "Reference video file {:?}: {}",
+            opt.reference,
+            "Found reference SPS".green()
+        );
+        if opt.diff {
+            println!("{:#?}", sps);
+        }
+        check_for_assumptions(sps);
+    }
+    if let Some(pps) = &reference_pps {
+        println!(
+            "Reference video file {:?}: {}",
+            opt.reference,
+            "Found reference PPS".green()
+        );
+        if opt.diff {
+            println!("{:#?}", pps);
         }
     }
     println!("------");
@@ -114,62 +132,47 @@ fn main() -> std::io::Result<()> {
         println!("Showing diff as {}, {}", "reference".green(), "value".red());
     }
 
-    let reference_sps = reference_sps.unwrap();
-    let reference_pps = reference_pps.unwrap();
+    let reference_sps = reference_sps.expect("No SPS found in reference video");
+    let reference_pps = reference_pps.expect("No PPS found in reference video");
 
     // Ignore directories
     let paths: Vec<PathBuf> = opt.input.into_iter().filter(|p| p.is_file()).collect();
 
     for path in paths {
-        for nal_unit in open_video_file(path.clone())? {
-            match nal_unit {
-                Err(e) => println!("Failed to parse NAL: {:?}", e),
-                Ok(nal_unit) => match nal_unit.nal_unit_type {
-                    NALUnitType::Sps => {
-                        let sps =
-                            Sps::read(&mut BitReader::endian(nal_unit.rbsp.as_slice(), BigEndian));
-                        match sps {
-                            Err(e) => println!("Failed to parse SPS: {:?}", e),
-                            Ok(sps) => {
-                                if sps != reference_sps {
-                                    println!(
-                                        "Video file {:?}: {}",
-                                        path,
-                                        "SPS differs from reference".red()
-                                    );
-                                    if opt.diff {
-                                        diff_printing::print_diff(&reference_sps, &sps);
-                                    }
-                                } else {
-                                    println!("Video file {:?}: {}", path, "Same SPS".green());
-                                }
-                                check_for_assumptions(&sps);
-                            }
-                        }
-                    }
-                    NALUnitType::Pps => {
-                        let pps =
-                            Pps::read(&mut BitReader::endian(nal_unit.rbsp.as_slice(), BigEndian));
-                        match pps {
-                            Err(e) => println!("Failed to parse PPS: {:?}", e),
-                            Ok(pps) => {
-                                if pps != reference_pps {
-                                    println!(
-                                        "Video file {:?}: {}",
-                                        path,
-                                        "PPS differs from reference".red()
-                                    );
-                                    if opt.diff {
-                                        diff_printing::print_diff(&reference_pps, &pps);
-                                    }
-                                } else {
-                                    println!("Video file {:?}: {}", path, "Same PPS".green());
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                },
+        let parameter_sets = load_parameter_sets(&path)?;
+
+        if parameter_sets.is_empty() {
+            println!("Video file {:?}: {}", path, "No parameter sets found".red());
+        }
+
+        for sps in parameter_sets.all_sps() {
+            if *sps != reference_sps {
+                println!(
+                    "Video file {:?}: {}",
+                    path,
+                    "SPS differs from reference".red()
+                );
+                if opt.diff {
+                    diff_printing::print_diff(&reference_sps, sps);
+                }
+            } else {
+                println!("Video file {:?}: {}", path, "Same SPS".green());
+            }
+            check_for_assumptions(sps);
+        }
+
+        for pps in parameter_sets.all_pps() {
+            if *pps != reference_pps {
+                println!(
+                    "Video file {:?}: {}",
+                    path,
+                    "PPS differs from reference".red()
+                );
+                if opt.diff {
+                    diff_printing::print_diff(&reference_pps, pps);
+                }
+            } else {
+                println!("Video file {:?}: {}", path, "Same PPS".green());
             }
         }
     }
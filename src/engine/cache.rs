@@ -0,0 +1,147 @@
+use crate::engine::LoadedVideo;
+use std::sync::Arc;
+
+struct CacheEntry {
+    video_num: usize,
+    video: Arc<LoadedVideo>,
+    size: usize,
+}
+
+impl CacheEntry {
+    /// The engine holds another reference while the video is playing
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.video) > 1
+    }
+}
+
+/// Loaded videos kept within a memory budget, the least recently used ones are dropped first.
+///
+/// Videos which are still in use are never dropped, so the budget can be exceeded by them.
+pub struct VideoCache {
+    budget: usize,
+    size: usize,
+    /// Least recently used first
+    entries: Vec<CacheEntry>,
+}
+
+impl VideoCache {
+    pub fn new(budget: usize) -> Self {
+        Self { budget, size: 0, entries: Vec::new() }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    /// Bytes of all videos in the cache
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, video_num: usize) -> bool {
+        self.entries.iter().any(|entry| entry.video_num == video_num)
+    }
+
+    /// Returns the video and marks it as most recently used
+    pub fn get(&mut self, video_num: usize) -> Option<Arc<LoadedVideo>> {
+        let index = self.entries.iter().position(|entry| entry.video_num == video_num)?;
+        let entry = self.entries.remove(index);
+        let video = entry.video.clone();
+        self.entries.push(entry);
+        Some(video)
+    }
+
+    /// Whether a video of `size` bytes fits without dropping videos which are in use or in `keep`
+    pub fn has_room(&self, size: usize, keep: &[usize]) -> bool {
+        let droppable: usize = self.entries.iter()
+            .filter(|entry| !entry.in_use() && !keep.contains(&entry.video_num))
+            .map(|entry| entry.size)
+            .sum();
+        self.size - droppable + size <= self.budget
+    }
+
+    /// Inserts the video as most recently used and drops videos until the cache is within budget.
+    /// Videos in `keep`, most likely wanted first, are only dropped after all others and the least
+    /// likely wanted ones first.
+    pub fn insert(&mut self, video_num: usize, video: Arc<LoadedVideo>, keep: &[usize]) {
+        let size = video.size();
+        self.entries.retain(|entry| entry.video_num != video_num);
+        self.entries.push(CacheEntry { video_num, video, size });
+        self.size = self.entries.iter().map(|entry| entry.size).sum();
+
+        let mut i = 0;
+        // The inserted video is the last entry and stays
+        while self.size > self.budget && i + 1 < self.entries.len() {
+            let entry = &self.entries[i];
+            if entry.in_use() || keep.contains(&entry.video_num) {
+                i += 1;
+            } else {
+                self.remove(i);
+            }
+        }
+        for &kept in keep.iter().rev() {
+            if self.size <= self.budget {
+                break;
+            }
+            let index = self.entries.iter()
+                .position(|entry| entry.video_num == kept && kept != video_num && !entry.in_use());
+            if let Some(index) = index {
+                self.remove(index);
+            }
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        let entry = self.entries.remove(index);
+        self.size -= entry.size;
+        eprintln!("Dropped video {} from the cache", entry.video_num);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::{AccessUnit, NALUnitType, NalUnit};
+
+    fn video(size: usize) -> Arc<LoadedVideo> {
+        let nal_unit = NalUnit::new(3, NALUnitType::CodedSliceIdr, vec![0x88; size]);
+        Arc::new(LoadedVideo::new(vec![AccessUnit { nal_units: vec![nal_unit] }]))
+    }
+
+    #[test]
+    fn test_least_recently_used_dropped() {
+        let mut cache = VideoCache::new(300);
+        cache.insert(0, video(100), &[]);
+        cache.insert(1, video(100), &[]);
+        cache.insert(2, video(100), &[]);
+        assert!(cache.get(0).is_some());
+        cache.insert(3, video(100), &[]);
+        assert!(!cache.contains(1));
+        assert!(cache.contains(0) && cache.contains(2) && cache.contains(3));
+        assert_eq!(cache.size(), 300);
+
+        // Wanted videos are dropped last
+        cache.insert(4, video(100), &[2]);
+        assert!(cache.contains(2) && !cache.contains(0));
+
+        // The least likely wanted video is dropped first
+        cache.insert(5, video(100), &[2, 3, 4]);
+        assert!(cache.contains(2) && cache.contains(3) && !cache.contains(4));
+    }
+
+    #[test]
+    fn test_in_use_kept() {
+        let mut cache = VideoCache::new(200);
+        cache.insert(0, video(100), &[]);
+        let playing = cache.get(0).unwrap();
+        cache.insert(1, video(100), &[]);
+        assert!(!cache.has_room(100, &[1]));
+        assert!(cache.has_room(100, &[]));
+
+        cache.insert(2, video(150), &[]);
+        assert!(cache.contains(0) && !cache.contains(1) && cache.contains(2));
+        assert_eq!(cache.size(), 250);
+        drop(playing);
+        assert!(cache.has_room(50, &[2]));
+    }
+}
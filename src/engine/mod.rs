@@ -0,0 +1,662 @@
+pub mod cache;
+pub mod state;
+pub mod video;
+
+pub use cache::*;
+pub use state::*;
+pub use video::*;
+
+use crate::fps_loop::LoopTimer;
+use crate::h264::{read_ue, AccessUnit, NALUnitType, NalUnit, ParameterSets, Sei, SeiPayload, SliceHeader, Sps, StreamRewriter};
+use crate::mux::{Muxer, ParameterSetRepeater};
+use crate::sigma_delta::SigmaDelta;
+use bitstream_io::{BigEndian, BitReader};
+use rand::Rng;
+use rosc::OscMessage;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Identifies the SEI user data written with `EngineConfig::embed_metadata`
+pub const GLITCH_METADATA_UUID: [u8; 16] = [
+    0x3c, 0x9d, 0x1e, 0x52, 0x8a, 0x47, 0x4f, 0x0b, 0x9e, 0x26, 0xd1, 0x7a, 0x55, 0xc3, 0x08, 0xe4,
+];
+
+/// Paces the output and provides the presentation timestamps
+pub trait Clock {
+    /// Called before the engine picks the next frame
+    fn begin_frame(&mut self);
+    /// Presentation time of the frame which is sent now
+    fn presentation_time(&self) -> Duration;
+    /// Waits until the next frame is due
+    fn end_frame(&mut self);
+    fn set_fps(&mut self, fps: f32);
+}
+
+impl Clock for LoopTimer {
+    fn begin_frame(&mut self) {
+        self.begin_loop();
+    }
+
+    fn presentation_time(&self) -> Duration {
+        LoopTimer::presentation_time(self)
+    }
+
+    fn end_frame(&mut self) {
+        self.end_loop();
+    }
+
+    fn set_fps(&mut self, fps: f32) {
+        LoopTimer::set_fps(self, fps);
+    }
+}
+
+/// Advances by a fixed duration per frame without waiting, for tests and offline rendering
+pub struct ManualClock {
+    pub time: Duration,
+    pub frame_duration: Duration,
+}
+
+impl ManualClock {
+    pub fn new(frame_duration: Duration) -> Self {
+        Self {
+            time: Duration::ZERO,
+            frame_duration,
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn begin_frame(&mut self) {}
+
+    fn presentation_time(&self) -> Duration {
+        self.time
+    }
+
+    fn end_frame(&mut self) {
+        self.time += self.frame_duration;
+    }
+
+    fn set_fps(&mut self, fps: f32) {
+        self.frame_duration = Duration::from_secs_f64(1.0 / f64::from(fps));
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EngineConfig {
+    pub rewrite_frame_nums: bool,
+    /// Increasing pic_order_cnt with every frame, shows B frames in decoding order
+    pub rewrite_pic_order_cnts: bool,
+    /// Embed the glitch state into every frame as SEI user data
+    pub embed_metadata: bool,
+    /// Repeat SPS and PPS in the stream at this interval
+    pub parameter_set_interval: Option<Duration>,
+}
+
+/// Settings of the active slot for all repetitions of the current frame
+struct FrameSettings {
+    pass_iframe: bool,
+    byte_errors: f32,
+    metadata: Option<String>,
+}
+
+/// All SPS in the output have to agree on the frame_num width
+fn rewrite_sps_nal_unit(rewriter: &mut StreamRewriter, nal_unit: &mut NalUnit) {
+    match Sps::read(&mut BitReader::endian(nal_unit.rbsp(), BigEndian)) {
+        Ok(mut sps) => {
+            if rewriter.rewrite_sps(&mut sps) {
+                nal_unit.set_rbsp(sps.to_rbsp());
+            }
+        },
+        Err(e) => eprintln!("Failed to parse SPS: {:?}", e),
+    }
+}
+
+/// Sends commands to an `Engine` and reads the state it published after the last frame
+#[derive(Clone)]
+pub struct EngineHandle {
+    commands: Sender<Command>,
+    snapshots: SnapshotCell,
+}
+
+impl EngineHandle {
+    /// The command is applied before the next frame
+    pub fn send(&self, command: Command) {
+        // Nothing to control anymore if the engine is gone
+        let _ = self.commands.send(command);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.get()
+    }
+}
+
+type ParameterSetListener = Box<dyn FnMut(&[NalUnit])>;
+
+/// Plays the videos of a library according to its `StreamingParams` and writes the glitched
+/// stream to a sink.
+///
+/// Frames are pulled with `next_frame`, the clock decides their timestamps and pacing.
+/// The engine owns the params, other threads control it by sending `Command`s which are applied
+/// before every frame and read its state from the published `Snapshot`s.
+pub struct Engine<C: Clock, M: Muxer> {
+    library: VideoLibrary,
+    params: StreamingParams,
+    command_sender: Sender<Command>,
+    commands: Receiver<Command>,
+    snapshots: SnapshotCell,
+    clock: C,
+    sink: M,
+    config: EngineConfig,
+    rewriter: StreamRewriter,
+    rng: rand::rngs::ThreadRng,
+    sigma_delta: SigmaDelta,
+    parameter_set_repeater: Option<ParameterSetRepeater>,
+    parameter_set_listener: Option<ParameterSetListener>,
+    current_video_num: usize,
+    current_video: Arc<LoadedVideo>,
+    /// Videos last handed to the library for preloading
+    preloaded: Vec<usize>,
+    current_frame: usize,
+    repeats_left: usize,
+    frame_settings: FrameSettings,
+}
+
+impl<C: Clock, M: Muxer> Engine<C, M> {
+    /// Starts with the first video of the library
+    pub fn new(library: VideoLibrary, clock: C, sink: M, config: EngineConfig) -> io::Result<Self> {
+        let current_video = library.get(0)?;
+        let (command_sender, commands) = mpsc::channel();
+        Ok(Self {
+            library,
+            params: StreamingParams::default(),
+            command_sender,
+            commands,
+            snapshots: SnapshotCell::default(),
+            clock,
+            sink,
+            rewriter: StreamRewriter::new(config.rewrite_frame_nums, config.rewrite_pic_order_cnts),
+            rng: rand::thread_rng(),
+            sigma_delta: SigmaDelta::new(),
+            parameter_set_repeater: config.parameter_set_interval.map(ParameterSetRepeater::new),
+            parameter_set_listener: None,
+            config,
+            current_video_num: 0,
+            current_video,
+            preloaded: Vec::new(),
+            current_frame: 0,
+            repeats_left: 0,
+            frame_settings: FrameSettings {
+                pass_iframe: false,
+                byte_errors: 0.0,
+                metadata: None,
+            },
+        })
+    }
+
+    pub fn params(&self) -> &StreamingParams {
+        &self.params
+    }
+
+    /// Controls the engine from other threads
+    pub fn handle(&self) -> EngineHandle {
+        EngineHandle {
+            commands: self.command_sender.clone(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    /// Applies a command, returns false if it was not understood
+    pub fn apply(&mut self, command: Command) -> bool {
+        if !self.params.apply(command) {
+            return false;
+        }
+        if self.params.active_state().fps.changed_incoming {
+            self.clock.set_fps(*self.params.active_state().fps);
+            self.params.active_state_mut().fps.set_handled();
+        }
+        true
+    }
+
+    /// Applies all commands received from the `EngineHandle`s
+    pub fn process_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            if let Command::SetVar(msg) = &command {
+                if !self.apply(command.clone()) {
+                    eprintln!("Unhandled OSC address: {}", msg.addr);
+                    eprintln!("Unhandled OSC arguments: {:?}", msg.args);
+                }
+            } else {
+                self.apply(command);
+            }
+        }
+    }
+
+    /// Messages for the OSC vars which changed since the last call
+    pub fn changed_messages(&mut self) -> Vec<OscMessage> {
+        let mut messages = Vec::new();
+        self.params.collect_changed(&mut messages);
+        messages
+    }
+
+    pub fn clock_mut(&mut self) -> &mut C {
+        &mut self.clock
+    }
+
+    pub fn sink_mut(&mut self) -> &mut M {
+        &mut self.sink
+    }
+
+    pub fn current_video_num(&self) -> usize {
+        self.current_video_num
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.current_frame
+    }
+
+    /// Called with the parameter sets of every video switched to, as they appear in the output
+    pub fn set_parameter_set_listener<F: FnMut(&[NalUnit]) + 'static>(&mut self, listener: F) {
+        self.parameter_set_listener = Some(Box::new(listener));
+        self.notify_parameter_sets();
+    }
+
+    fn notify_parameter_sets(&mut self) {
+        if let Some(listener) = &mut self.parameter_set_listener {
+            let mut nal_units = self.current_video.parameter_set_nal_units();
+            for nal_unit in nal_units.iter_mut().filter(|n| n.nal_unit_type == NALUnitType::Sps) {
+                rewrite_sps_nal_unit(&mut self.rewriter, nal_unit);
+            }
+            listener(&nal_units);
+        }
+    }
+
+    /// Writes out frames until the first IDR picture so that decoders can start
+    pub fn start(&mut self) -> io::Result<()> {
+        let video = self.current_video.clone();
+        loop {
+            let access_unit = &video.frames[self.current_frame];
+            self.advance_frame();
+            self.write_access_unit(access_unit, 0.0, None)?;
+            if access_unit.is_idr() {
+                eprintln!("Got first I frame");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sends the next frame, skipping frames which are not shown.
+    /// The caller has to wait for the clock with `end_frame` before the next call.
+    pub fn next_frame(&mut self) -> io::Result<()> {
+        loop {
+            if self.repeats_left == 0 {
+                self.begin_frame()?;
+                continue;
+            }
+            self.repeats_left -= 1;
+
+            let video = self.current_video.clone();
+            let access_unit = &video.frames[self.current_frame];
+            // If pass_iframe is not activated, send only pictures which are not IDR
+            // Sending a new SPS without an Idr Slice seems to cause problems when switching between some videos
+            if !access_unit.is_idr() && !access_unit.has_parameter_sets() || self.frame_settings.pass_iframe {
+                let metadata = self.frame_settings.metadata.clone();
+                self.write_access_unit(access_unit, self.frame_settings.byte_errors, metadata.as_deref())?;
+                if !access_unit.has_picture() {
+                    continue; //Only sleep if the access unit contains a video frame
+                }
+            } else {
+                continue; //If we didn't send out frame don't sleep
+            }
+
+            let playhead = self.current_frame as f32 / video.frames.len() as f32;
+            self.params.active_state_mut().playhead.set(playhead);
+            self.snapshots.publish(self.params.snapshot());
+            return Ok(());
+        }
+    }
+
+    /// Waits until the next frame is due
+    pub fn end_frame(&mut self) {
+        self.clock.end_frame();
+    }
+
+    /// Processes the requests and picks the next frame of the video
+    fn begin_frame(&mut self) -> io::Result<()> {
+        self.clock.begin_frame();
+        self.process_commands();
+
+        // Switch video if requested, the current one keeps playing until the new one is loaded
+        let video_num = *self.params.active_state().video_num;
+        let requested = usize::try_from(video_num).ok().filter(|video_num| *video_num < self.library.len());
+        if let Some(video_num) = requested.filter(|video_num| *video_num != self.current_video_num) {
+            match self.library.try_get(video_num) {
+                Ok(Some(video)) => {
+                    self.current_video_num = video_num;
+                    self.current_video = video;
+                    self.notify_parameter_sets();
+                    self.current_frame = 0;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    eprintln!("Failed to switch to video {}: {}", video_num, err);
+                    self.params.active_state_mut().video_num.set(self.current_video_num as i32);
+                }
+            }
+        }
+        self.preload_likely_videos();
+
+        if self.params.restart_loop {
+            self.current_frame = 0; // Will be set to loop start by advance_frame(...)
+            self.params.restart_loop = false;
+        }
+
+        if let Some(skip) = self.params.skip_frames.take() {
+            for _ in 0..skip {
+                self.advance_frame(); //TODO advance n
+            }
+        }
+
+        // Now the state based stuff
+
+        let state = self.params.active_state();
+        self.repeats_left = usize::try_from(self.sigma_delta.put(*state.frame_repeat)).unwrap_or(0);
+        let (pass_iframe, byte_errors) = (*state.pass_iframe, *state.byte_errors);
+
+        // Restart video if at end
+        self.advance_frame();
+
+        self.frame_settings = FrameSettings {
+            pass_iframe,
+            byte_errors,
+            metadata: self.config.embed_metadata.then(|| {
+                format!("video={} frame={} slot={} beat={}", self.current_video_num, self.current_frame, *self.params.active_slot, self.params.beat_count)
+            }),
+        };
+        Ok(())
+    }
+
+    /// Lets the library load the videos which are likely played next: the switch history of the
+    /// active slot, the videos of the other slots and the neighbours of the current video
+    fn preload_likely_videos(&mut self) {
+        let active_state = self.params.active_state();
+        let slot_videos = self.params.state_slots.iter().filter_map(|state| usize::try_from(*state.video_num).ok());
+        let neighbours = [self.current_video_num + 1, self.current_video_num.wrapping_sub(1)];
+
+        let mut likely = Vec::new();
+        for video_num in active_state.switch_history.iter().copied().chain(slot_videos).chain(neighbours) {
+            if video_num < self.library.len() && video_num != self.current_video_num && !likely.contains(&video_num) {
+                likely.push(video_num);
+            }
+        }
+        if likely != self.preloaded {
+            self.library.preload(&likely);
+            self.preloaded = likely;
+        }
+    }
+
+    fn advance_frame(&mut self) {
+        let total_frames = self.current_video.frames.len();
+        let current_frame = &mut self.current_frame;
+        let (mut from_incl, mut to_excl) = (0, total_frames);
+        let params = &mut self.params;
+
+        if let Some(short_loop) = &mut params.short_loop {
+            if short_loop.first_frame.is_none() {
+                short_loop.first_frame = Some(*current_frame);
+            }
+
+            let loop_from = short_loop.first_frame.unwrap();
+            let loop_to = short_loop.first_frame.unwrap() + short_loop.len;
+
+            from_incl = usize::min(loop_from, total_frames.saturating_sub(2));
+            to_excl = usize::min(usize::max(from_incl + 1, loop_to), total_frames);
+        } else if let Some((loop_from, loop_to)) = params.active_state().loop_range.0 {
+            let loop_from = (total_frames as f32 * loop_from) as usize;
+            let loop_to = (total_frames as f32 * loop_to) as usize;
+
+            from_incl = usize::min(loop_from, total_frames.saturating_sub(2));
+            to_excl = usize::min(usize::max(from_incl + 1, loop_to), total_frames);
+        }
+
+        assert!(from_incl < to_excl);
+        assert!(to_excl <= total_frames);
+
+        if *current_frame < from_incl {
+            *current_frame = from_incl;
+        } else {
+            *current_frame += 1;
+            if *current_frame >= to_excl {
+                *current_frame = from_incl;
+            }
+        }
+    }
+
+    fn rewrite_nal_unit(&mut self, nal_unit: &NalUnit, parameter_sets: &ParameterSets, byte_errors: f32) -> NalUnit {
+        let rewriter = &mut self.rewriter;
+        let rng = &mut self.rng;
+        let mut nal_unit = nal_unit.clone();
+        match nal_unit.nal_unit_type {
+            NALUnitType::Sps => rewrite_sps_nal_unit(rewriter, &mut nal_unit),
+            NALUnitType::CodedSliceIdr | NALUnitType::CodedSliceNonIdr => {
+                let rewritten = parameter_sets.for_slice(&nal_unit).and_then(|(sps, pps)| {
+                    if byte_errors <= 0.0 && !rewriter.needs_rewrite(sps) {
+                        return Ok(());
+                    }
+                    let mut header = SliceHeader::from_bytes_without_data(nal_unit.rbsp(), nal_unit.nal_unit_type, nal_unit.nal_ref_idc, parameter_sets)?;
+
+                    // Just setting all frame nums to zero also seems to work.
+                    // Maybe mpv even crashes a bit less with just zero
+                    // I haven't observed a crash for a while though, maybe it was something else also
+                    //header.frame_num = 0;
+                    rewriter.rewrite_slice_header(&mut header, sps);
+                    let output_sps = rewriter.output_sps(sps);
+
+                    if byte_errors <= 0.0 {
+                        // Usually the header keeps its length, then the escaped slice data is reused
+                        if let Some(prefix) = header.rewrite_prefix(&output_sps, pps, nal_unit.rbsp())? {
+                            nal_unit.replace_rbsp_prefix(&prefix);
+                            return Ok(());
+                        }
+                    }
+                    header.data = nal_unit.rbsp().to_vec();
+
+                    if byte_errors > 0.0 {
+                        // Introduce random errors. Start some bytes into buffer so that we hopefully only
+                        // hit data, not the header
+                        let offset = 50;
+                        // let offset = 0; // Or maybe thats fun also?
+
+                        for b in header.data[offset..].iter_mut() {
+                            if rng.gen::<f32>() < byte_errors {
+                                *b = rng.gen()
+                            }
+                        }
+
+                        // Reasonable probability of errors seems to be around 0.0001
+                        // Weirder behaviour at higher error probabilities:
+                        // Why does the motion always go down ?
+                        // Why is the playhead not smooth anymore?
+                        // Does mpv hang and slow down the glitcher also (pipe gets full)?
+                        // Probably have to parse further down and destroy more controlled regions
+                        // Would be cool to destroy whole blocks, would look more glitchy maybe
+                    }
+                    nal_unit.set_rbsp(header.to_bytes_with(&output_sps, pps)?);
+                    Ok(())
+                });
+                if let Err(e) = rewritten {
+                    eprintln!("Failed to rewrite slice: {:?}", e);
+                }
+            },
+            _ => {},
+        }
+        nal_unit
+    }
+
+    fn write_access_unit(&mut self, access_unit: &AccessUnit, byte_errors: f32, metadata: Option<&str>) -> io::Result<()> {
+        let video = self.current_video.clone();
+        let pts = self.clock.presentation_time();
+        let mut nal_units = Vec::with_capacity(access_unit.nal_units.len() + 1);
+        for nal_unit in &access_unit.nal_units {
+            if let Some(metadata) = metadata {
+                // The SEI has to come before the first slice of the picture
+                let first_mb_in_slice = read_ue::<u32, _>(&mut BitReader::endian(nal_unit.rbsp(), BigEndian));
+                if nal_unit.nal_unit_type.is_picture_data() && matches!(first_mb_in_slice, Ok(0)) {
+                    let sei = Sei {
+                        messages: vec![SeiPayload::UserDataUnregistered {
+                            uuid: GLITCH_METADATA_UUID,
+                            data: metadata.as_bytes().to_vec(),
+                        }],
+                    };
+                    nal_units.push(sei.to_nal_unit(None)?);
+                }
+            }
+            nal_units.push(self.rewrite_nal_unit(nal_unit, &video.parameter_sets, byte_errors));
+        }
+        if let Some(repeater) = &mut self.parameter_set_repeater {
+            repeater.process(&mut nal_units, pts);
+        }
+        self.sink.write_access_unit(&nal_units, pts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rosc::OscType;
+    use std::thread;
+
+    /// Records the written access units as (video, frame) of their first NAL unit
+    #[derive(Default)]
+    struct RecordingMuxer {
+        frames: Vec<(u8, u8)>,
+        timestamps: Vec<Duration>,
+    }
+
+    impl Muxer for RecordingMuxer {
+        fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+            self.frames.push((nal_units[0].rbsp()[1], nal_units[0].rbsp()[2]));
+            self.timestamps.push(pts);
+            Ok(())
+        }
+    }
+
+    /// IDR picture followed by P pictures, every slice carries the video and frame number
+    fn video(video_num: u8, len: u8) -> LoadedVideo {
+        let frames = (0..len)
+            .map(|i| {
+                let bytes = if i == 0 { [0x65, 0x88, video_num, i] } else { [0x41, 0x9a, video_num, i] };
+                AccessUnit {
+                    nal_units: vec![NalUnit::from_bytes(&bytes).unwrap()],
+                }
+            })
+            .collect();
+        LoadedVideo::new(frames)
+    }
+
+    fn engine() -> Engine<ManualClock, RecordingMuxer> {
+        let library = VideoLibrary::from_videos(vec![video(0, 10), video(1, 10)]);
+        let clock = ManualClock::new(Duration::from_millis(40));
+        let mut engine = Engine::new(library, clock, RecordingMuxer::default(), EngineConfig::default()).unwrap();
+        engine.start().unwrap();
+        engine
+    }
+
+    fn run(engine: &mut Engine<ManualClock, RecordingMuxer>, frames: usize) -> Vec<(u8, u8)> {
+        let start = engine.sink_mut().frames.len();
+        for _ in 0..frames {
+            engine.next_frame().unwrap();
+            engine.end_frame();
+        }
+        engine.sink_mut().frames[start..].to_vec()
+    }
+
+    fn osc(addr: &str, args: Vec<OscType>) -> Command {
+        Command::SetVar(OscMessage { addr: addr.to_string(), args })
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut engine = engine();
+        assert_eq!(engine.sink_mut().frames, vec![(0, 0)]);
+        assert_eq!(run(&mut engine, 3), vec![(0, 2), (0, 3), (0, 4)]);
+
+        assert!(engine.apply(osc("/loop_range", vec![OscType::Float(0.5), OscType::Float(0.8)])));
+        assert_eq!(run(&mut engine, 4), vec![(0, 5), (0, 6), (0, 7), (0, 5)]);
+
+        // IDR pictures are skipped at the end of the loop
+        assert!(engine.apply(Command::ClearLoop));
+        assert_eq!(run(&mut engine, 5), vec![(0, 6), (0, 7), (0, 8), (0, 9), (0, 1)]);
+
+        assert!(engine.apply(Command::ShortLoop(2)));
+        assert_eq!(run(&mut engine, 3), vec![(0, 2), (0, 1), (0, 2)]);
+
+        // Every frame is shown twice
+        assert!(engine.apply(Command::ShortLoop(0)));
+        assert!(engine.apply(osc("/frame_repeat", vec![OscType::Float(2.0)])));
+        assert_eq!(run(&mut engine, 4), vec![(0, 3), (0, 3), (0, 4), (0, 4)]);
+        let timestamps = &engine.sink_mut().timestamps;
+        assert_eq!(timestamps[timestamps.len() - 1] - timestamps[timestamps.len() - 2], Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_one_picture_loops() {
+        let library = VideoLibrary::from_videos(vec![video(0, 1)]);
+        let clock = ManualClock::new(Duration::from_millis(40));
+        let mut engine = Engine::new(library, clock, RecordingMuxer::default(), EngineConfig::default()).unwrap();
+        engine.start().unwrap();
+
+        assert!(engine.apply(Command::ShortLoop(2)));
+        engine.advance_frame();
+        assert_eq!(engine.current_frame, 0);
+        assert!(engine.apply(Command::ShortLoop(0)));
+        assert!(engine.apply(osc("/loop_range", vec![OscType::Float(0.5), OscType::Float(0.8)])));
+        engine.advance_frame();
+        assert_eq!(engine.current_frame, 0);
+    }
+
+    #[test]
+    fn test_slots_and_switches() {
+        let mut engine = engine();
+        assert!(engine.apply(Command::VideoNum(1)));
+        assert_eq!(run(&mut engine, 2), vec![(1, 1), (1, 2)]);
+        assert_eq!(engine.current_video_num(), 1);
+
+        // Slot 1 plays the first video including its IDR picture
+        assert!(engine.apply(osc("/edit_slot", vec![OscType::Int(1)])));
+        assert!(engine.apply(osc("/pass_iframe", vec![OscType::Bool(true)])));
+        assert_eq!(run(&mut engine, 1), vec![(1, 3)]);
+        assert!(engine.apply(osc("/active_slot", vec![OscType::Int(1)])));
+        assert_eq!(run(&mut engine, 2), vec![(0, 1), (0, 2)]);
+        assert_eq!(run(&mut engine, 8)[6..], [(0, 9), (0, 0)]);
+
+        assert!(engine.apply(osc("/active_slot", vec![OscType::Int(0)])));
+        assert_eq!(run(&mut engine, 1), vec![(1, 1)]);
+        assert!(!engine.apply(osc("/unknown", vec![OscType::Int(0)])));
+    }
+
+    #[test]
+    fn test_commands_and_snapshots() {
+        let mut engine = engine();
+        let handle = engine.handle();
+        thread::spawn({
+            let handle = handle.clone();
+            move || {
+                handle.send(Command::VideoNum(1));
+                handle.send(osc("/fps", vec![OscType::Float(10.0)]));
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(run(&mut engine, 2), vec![(1, 1), (1, 2)]);
+        assert_eq!(handle.snapshot().playhead, 0.2);
+        assert_eq!(engine.clock_mut().frame_duration, Duration::from_millis(100));
+        let messages = engine.changed_messages();
+        assert!(messages.contains(&OscMessage { addr: "/video_num".to_string(), args: vec![OscType::Int(1)] }));
+        assert!(messages.contains(&OscMessage { addr: "/playhead".to_string(), args: vec![OscType::Float(0.2)] }));
+        assert!(engine.changed_messages().is_empty());
+    }
+}
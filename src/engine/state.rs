@@ -0,0 +1,387 @@
+use crate::osc_var::{LoopRange, OscVar};
+use rosc::OscMessage;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const STATE_SLOTS: usize = 6;
+/// Frames skipped on every beat with auto_skip
+const AUTO_SKIP_FRAMES: usize = 20;
+
+/// Playback settings of one slot
+#[derive(Clone)]
+pub struct State {
+    pub video_num: OscVar<i32>,
+    pub beat_multiplier: OscVar<i32>,
+    pub pass_iframe: OscVar<bool>,
+    pub playhead: OscVar<f32>,
+    pub loop_range: OscVar<LoopRange>,
+    pub auto_skip: OscVar<bool>,
+    pub frame_repeat: OscVar<f32>, // Values < 1 drop frames, non integer values drop / repeat frames sometimes
+    pub loop_to_beat: OscVar<bool>,
+    pub fps: OscVar<f32>,
+    pub auto_switch_n: OscVar<i32>,
+    pub switch_history: VecDeque<usize>,
+    pub byte_errors: OscVar<f32>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            video_num: OscVar::new("/video_num", 0),
+            beat_multiplier: OscVar::new("/beat_multiplier", 0),
+            pass_iframe: OscVar::new("/pass_iframe", false),
+            playhead: OscVar::new("/playhead", 0.0),
+            loop_range: OscVar::new("/loop_range", LoopRange(None)),
+            auto_skip: OscVar::new("/auto_skip", false),
+            frame_repeat: OscVar::new("/frame_repeat", 1.0),
+            loop_to_beat: OscVar::new("/loop_to_beat", false),
+            fps: OscVar::new("/fps", 30.0),
+            auto_switch_n: OscVar::new("/auto_switch", 0),
+            switch_history: VecDeque::with_capacity(5),
+            byte_errors: OscVar::new("/byte_errors", 0.0),
+        }
+    }
+}
+
+impl State {
+    /// Appends the messages for all vars which changed since the last call
+    pub fn collect_changed(&mut self, messages: &mut Vec<OscMessage>) {
+        messages.extend(self.video_num.take_changed());
+        messages.extend(self.beat_multiplier.take_changed());
+        messages.extend(self.pass_iframe.take_changed());
+        messages.extend(self.playhead.take_changed());
+        messages.extend(self.loop_range.take_changed());
+        messages.extend(self.auto_skip.take_changed());
+        messages.extend(self.frame_repeat.take_changed());
+        messages.extend(self.loop_to_beat.take_changed());
+        messages.extend(self.fps.take_changed());
+        messages.extend(self.auto_switch_n.take_changed());
+        messages.extend(self.byte_errors.take_changed());
+    }
+
+    pub fn set_changed(&mut self) {
+        self.video_num.set_changed();
+        self.beat_multiplier.set_changed();
+        self.pass_iframe.set_changed();
+        self.playhead.set_changed();
+        self.loop_range.set_changed();
+        self.auto_skip.set_changed();
+        self.frame_repeat.set_changed();
+        self.loop_to_beat.set_changed();
+        self.fps.set_changed();
+        self.auto_switch_n.set_changed();
+        self.byte_errors.set_changed();
+    }
+
+    /// Switches to another video and remembers it for auto_switch
+    pub fn switch_video(&mut self, video_num: i32) {
+        self.video_num.set(video_num);
+        if self.switch_history.len() == 5 {
+            self.switch_history.pop_back();
+        }
+        self.switch_history.push_front(video_num as usize);
+    }
+
+    pub fn handle_osc_message(&mut self, msg: &OscMessage) -> bool {
+        if self.video_num.handle_osc_message(msg) {
+            if self.switch_history.len() == 5 {
+                self.switch_history.pop_back();
+            }
+            self.switch_history.push_front(*self.video_num as usize);
+        }
+        self.beat_multiplier.handle_osc_message(msg) ||
+        self.pass_iframe.handle_osc_message(msg) ||
+        //self.playhead.handle_osc_message(msg) ||
+        self.loop_range.handle_osc_message(msg) ||
+        self.auto_skip.handle_osc_message(msg) ||
+        self.frame_repeat.handle_osc_message(msg) ||
+        self.loop_to_beat.handle_osc_message(msg) ||
+        self.fps.handle_osc_message(msg) ||
+        self.auto_switch_n.handle_osc_message(msg) ||
+        self.byte_errors.handle_osc_message(msg)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShortLoop {
+    pub first_frame: Option<usize>,
+    pub len: usize,
+}
+
+/// Control commands for the engine, usually received over OSC
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Sets one of the OSC variables, of the edited slot for per slot variables
+    SetVar(OscMessage),
+    SetClientAddress(SocketAddr),
+    /// Starts the loop at the playhead if true, ends it there otherwise
+    RecordLoop(bool),
+    ClearLoop,
+    /// Shortens the loop to the given fraction
+    CutLoop(f32),
+    SkipFrames(usize),
+    VideoNum(i32),
+    /// Loops over this many frames from the current one, 0 ends the short loop
+    ShortLoop(usize),
+    Reset,
+    /// Copies the active slot to the edited one, or to the next one if both are the same
+    CopyActive,
+    /// A beat of the beat predictor, runs auto_skip and auto_switch
+    Beat,
+}
+
+impl Command {
+    /// Parses the OSC messages which control the engine. Messages with an unknown address
+    /// become `SetVar`, `None` is returned if the arguments do not match.
+    pub fn from_osc(msg: &OscMessage) -> Option<Command> {
+        let arg = msg.args.first().cloned();
+        Some(match msg.addr.as_str() {
+            "/record_loop" => Command::RecordLoop(arg?.bool()?),
+            "/clear_loop" => Command::ClearLoop,
+            "/cut_loop" => Command::CutLoop(arg?.float()?),
+            "/skip_frames" => Command::SkipFrames(arg?.int()? as usize),
+            "/video_num" => Command::VideoNum(arg?.int()?),
+            "/short_loop" => Command::ShortLoop(usize::try_from(arg?.int()?).unwrap_or(0)),
+            "/reset" => Command::Reset,
+            "/copy_active" => Command::CopyActive,
+            _ => Command::SetVar(msg.clone()),
+        })
+    }
+
+    /// Whether the render loop should stop waiting for the next frame to apply the command
+    pub fn needs_wake_up(&self) -> bool {
+        matches!(self, Command::SkipFrames(_) | Command::VideoNum(_))
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamingParams {
+    pub restart_loop: bool,
+
+    pub skip_frames: Option<usize>,
+    pub client_addr: Option<SocketAddr>,
+
+    pub short_loop: Option<ShortLoop>,
+
+    pub use_external_beat: OscVar<bool>,
+    pub beat_offset: OscVar<Duration>,
+    pub beat_divider: u32,
+
+    pub state_slots: Vec<State>,
+    pub active_slot: OscVar<usize>,
+    pub edit_slot: OscVar<usize>,
+
+    pub is_live: OscVar<bool>,
+
+    pub beat_count: u64,
+    /// Position in the switch history of auto_switch
+    pub auto_switch_num: usize,
+}
+
+impl Default for StreamingParams {
+    fn default() -> Self {
+        Self {
+            restart_loop: false,
+            skip_frames: None,
+            client_addr: None,
+            short_loop: None,
+            use_external_beat: OscVar::new("/use_external_beat", false),
+            beat_offset: OscVar::new("/beat_offset", Duration::from_millis(0)),
+            beat_divider: 1,
+
+            state_slots: vec![State::default(); STATE_SLOTS],
+            active_slot: OscVar::new("/active_slot", 0),
+            edit_slot: OscVar::new("/edit_slot", 0),
+
+            is_live: OscVar::new("/is_live", true),
+
+            beat_count: 0,
+            auto_switch_num: 0,
+        }
+    }
+}
+
+impl StreamingParams {
+    pub fn set_active_slot(&mut self, slot: usize) {
+        assert!(slot < STATE_SLOTS);
+        self.active_slot.set(slot);
+        self.active_state_mut().set_changed();
+
+        self.is_live.set(*self.active_slot == *self.edit_slot);
+    }
+
+    pub fn set_edit_slot(&mut self, slot: usize) {
+        assert!(slot < STATE_SLOTS);
+        self.edit_slot.set(slot);
+        self.edit_state_mut().set_changed();
+
+        self.is_live.set(*self.active_slot == *self.edit_slot);
+    }
+
+    pub fn active_state(&self) -> &State {
+        &self.state_slots[*self.active_slot]
+    }
+
+    pub fn active_state_mut(&mut self) -> &mut State {
+        &mut self.state_slots[*self.active_slot]
+    }
+
+    pub fn edit_state(&self) -> &State {
+        &self.state_slots[*self.edit_slot]
+    }
+
+    pub fn edit_state_mut(&mut self) -> &mut State {
+        &mut self.state_slots[*self.edit_slot]
+    }
+
+    /// Appends the messages for all vars of the edited slot and the global vars which changed
+    /// since the last call
+    pub fn collect_changed(&mut self, messages: &mut Vec<OscMessage>) {
+        messages.extend(self.use_external_beat.take_changed());
+        messages.extend(self.beat_offset.take_changed());
+        messages.extend(self.active_slot.take_changed());
+        messages.extend(self.edit_slot.take_changed());
+        self.edit_state_mut().collect_changed(messages);
+        messages.extend(self.is_live.take_changed());
+    }
+
+    /// The part of the state which the control threads need
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.active_state();
+        Snapshot {
+            client_addr: self.client_addr,
+            use_external_beat: *self.use_external_beat,
+            beat_offset: *self.beat_offset,
+            beat_divider: self.beat_divider,
+            beat_multiplier: *state.beat_multiplier,
+            auto_skip: *state.auto_skip,
+            auto_switch: *state.auto_switch_n > 0,
+            playhead: *state.playhead,
+        }
+    }
+
+    pub fn handle_osc_message(&mut self, msg: &OscMessage) -> bool {
+        self.use_external_beat.handle_osc_message(msg) ||
+        self.beat_offset.handle_osc_message(msg) ||
+        self.active_slot.handle_osc_message(msg) ||
+        self.edit_slot.handle_osc_message(msg) ||
+        self.edit_state_mut().handle_osc_message(msg)
+    }
+
+    /// Applies a command, returns false if it was a `SetVar` for an unknown variable
+    pub fn apply(&mut self, command: Command) -> bool {
+        match command {
+            Command::SetVar(msg) => {
+                if !self.handle_osc_message(&msg) {
+                    return false;
+                }
+            }
+            Command::SetClientAddress(addr) => {
+                self.client_addr = Some(addr);
+                eprintln!("updated client_addr: {:?}", self.client_addr);
+            }
+            Command::RecordLoop(record_loop) => {
+                if record_loop {
+                    let from = *self.active_state().playhead;
+                    let (_, to) = self.active_state().loop_range.0.unwrap_or((0.0, 1.0));
+                    self.active_state_mut().loop_range.set(LoopRange(Some((from, to))));
+                } else {
+                    let (from, _) = self.active_state().loop_range.0.unwrap_or((0.0, 1.0));
+                    let to = *self.active_state().playhead;
+                    self.active_state_mut().loop_range.set(LoopRange(Some((from, to))));
+                }
+            }
+            Command::ClearLoop => self.edit_state_mut().loop_range.set(LoopRange(None)),
+            Command::CutLoop(fraction) => {
+                let loop_range = &mut self.edit_state_mut().loop_range;
+                let range = loop_range.0.unwrap_or((0.0, 1.0));
+                let new_range = (range.0, range.0 + (range.1 - range.0) * fraction);
+                loop_range.set(LoopRange(Some(new_range)));
+            }
+            Command::SkipFrames(frames) => self.skip_frames = Some(frames),
+            Command::VideoNum(video_num) => self.edit_state_mut().switch_video(video_num),
+            Command::ShortLoop(len) => {
+                self.short_loop = if len > 0 {
+                    Some(ShortLoop { first_frame: None, len })
+                } else {
+                    None
+                };
+            }
+            Command::Reset => {
+                *self.edit_state_mut() = State::default();
+                self.edit_state_mut().set_changed();
+            }
+            Command::CopyActive => {
+                if *self.active_slot == *self.edit_slot {
+                    self.edit_slot.set((*self.edit_slot + 1) % STATE_SLOTS);
+                }
+                *self.edit_state_mut() = self.active_state().clone();
+                self.edit_state_mut().set_changed();
+            }
+            Command::Beat => self.beat(),
+        }
+
+        if self.active_slot.changed_incoming {
+            self.set_active_slot(*self.active_slot);
+            self.active_slot.set_handled()
+        }
+        if self.edit_slot.changed_incoming {
+            self.set_edit_slot(*self.edit_slot);
+            self.edit_slot.set_handled()
+        }
+        true
+    }
+
+    fn beat(&mut self) {
+        self.beat_count += 1;
+        if *self.active_state().auto_skip {
+            self.skip_frames = Some(AUTO_SKIP_FRAMES);
+        }
+
+        if *self.active_state().auto_switch_n > 0 {
+            let switch_history = self.active_state().switch_history.clone();
+            self.auto_switch_num += 1;
+            if self.auto_switch_num >= switch_history.len() || self.auto_switch_num > *self.active_state().auto_switch_n as usize {
+                self.auto_switch_num = 0;
+            }
+            if !switch_history.is_empty() {
+                let video_num = switch_history[self.auto_switch_num] as i32;
+                self.active_state_mut().video_num.set(video_num);
+            }
+        }
+
+        if *self.active_state().loop_to_beat {
+            self.restart_loop = true;
+        }
+    }
+}
+
+/// Copy of the engine state for the control threads, cheap to publish every frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub client_addr: Option<SocketAddr>,
+    pub use_external_beat: bool,
+    pub beat_offset: Duration,
+    pub beat_divider: u32,
+    pub beat_multiplier: i32,
+    pub auto_skip: bool,
+    pub auto_switch: bool,
+    pub playhead: f32,
+}
+
+/// The latest `Snapshot` published by the render thread
+#[derive(Clone, Default)]
+pub struct SnapshotCell(Arc<Mutex<Snapshot>>);
+
+impl SnapshotCell {
+    pub fn publish(&self, snapshot: Snapshot) {
+        *self.0.lock().unwrap() = snapshot;
+    }
+
+    pub fn get(&self) -> Snapshot {
+        self.0.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,398 @@
+use crate::engine::VideoCache;
+use crate::h264::{map_file, split_nal_units, AccessUnit, AccessUnitIterator, NALUnitType, NalUnit, ParameterSets, ParseError};
+use crate::mp4::Mp4VideoTrack;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+pub const VIDEO_EXTENSIONS: [&str; 4] = ["h264", "mp4", "mov", "m4v"];
+
+pub fn is_mp4(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext != "h264")
+}
+
+/// A video split into access units
+#[derive(Clone)]
+pub struct LoadedVideo {
+    pub frames: Vec<AccessUnit>,
+    pub parameter_sets: ParameterSets,
+}
+
+impl LoadedVideo {
+    pub fn load(path: &Path) -> io::Result<LoadedVideo> {
+        eprintln!("Open file {:?}", path);
+        let data = map_file(path)?;
+        let frames = if is_mp4(path) {
+            let track = Mp4VideoTrack::read(&data).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read mp4 {:?}: {:?}", path, err))
+            })?;
+            Self::access_units(track.nal_units(&data))
+        } else {
+            Self::access_units(split_nal_units(&data).map(NalUnit::from_bytes))
+        };
+
+        // Loops need a picture to go back to, and the first IDR picture is skipped
+        if frames.len() < 2 {
+            let message = format!("{:?} has {} access units, at least 2 are needed", path, frames.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        let video = Self::new(frames);
+        if video.parameter_sets.is_empty() {
+            eprintln!("No parameter sets found in {:?}", path);
+        }
+        Ok(video)
+    }
+
+    /// Collects the parameter sets of the access units
+    pub fn new(frames: Vec<AccessUnit>) -> Self {
+        let mut parameter_sets = ParameterSets::new();
+        for nal_unit in frames.iter().flat_map(|access_unit| &access_unit.nal_units) {
+            if let Err(err) = parameter_sets.insert_nal_unit(nal_unit) {
+                eprintln!("Failed to parse parameter set: {:?}", err);
+            }
+        }
+        LoadedVideo { frames, parameter_sets }
+    }
+
+    fn access_units<I: Iterator<Item = Result<NalUnit, ParseError>>>(nal_units: I) -> Vec<AccessUnit> {
+        let it = nal_units.filter_map(|r| {
+            match r {
+                Ok(v) => Some(v),
+                Err(err) => {
+                    eprintln!("Failed to parse frame: {:?}", err);
+                    None
+                }
+            }
+        });
+        AccessUnitIterator::new(it).collect()
+    }
+
+    /// Bytes held for the NAL unit payloads
+    pub fn size(&self) -> usize {
+        self.frames.iter()
+            .flat_map(|access_unit| &access_unit.nal_units)
+            .map(|nal_unit| nal_unit.payload_size())
+            .sum()
+    }
+
+    /// Distinct SPS and PPS NAL units in stream order
+    pub fn parameter_set_nal_units(&self) -> Vec<NalUnit> {
+        let mut nal_units: Vec<NalUnit> = Vec::new();
+        for nal_unit in self.frames.iter().flat_map(|access_unit| &access_unit.nal_units) {
+            let is_parameter_set = matches!(nal_unit.nal_unit_type, NALUnitType::Sps | NALUnitType::Pps);
+            if is_parameter_set && !nal_units.iter().any(|n| n.nal_unit_type == nal_unit.nal_unit_type && n.rbsp() == nal_unit.rbsp()) {
+                nal_units.push(nal_unit.clone());
+            }
+        }
+        nal_units
+    }
+}
+
+/// A video which could not be loaded, it is not tried again until its file changes
+struct LoadFailure {
+    error: io::Error,
+    modified: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+struct LoaderState {
+    cache: VideoCache,
+    /// The video the engine waits for, loaded before the preloads
+    requested: Option<usize>,
+    /// Videos likely played next, most likely first
+    preload: Vec<usize>,
+    failed: HashMap<usize, LoadFailure>,
+    /// Sizes of videos loaded before, the escaped copies of NAL units make them larger than the files
+    sizes: HashMap<usize, usize>,
+    stopped: bool,
+}
+
+impl LoaderState {
+    /// Why the video could not be loaded, unless its file changed since
+    fn failure(&self, video_num: usize, path: &Path) -> Option<&io::Error> {
+        self.failed.get(&video_num)
+            .filter(|failure| failure.modified == modified(path))
+            .map(|failure| &failure.error)
+    }
+
+    /// The requested video or the first preload which fits into the cache
+    fn next_load(&self, paths: &[PathBuf]) -> Option<usize> {
+        if let Some(video_num) = self.requested.filter(|video_num| !self.cache.contains(*video_num)) {
+            return Some(video_num);
+        }
+        let video_num = *self.preload.iter()
+            .find(|video_num| !self.cache.contains(**video_num) && self.failure(**video_num, &paths[**video_num]).is_none())?;
+        // Until the video was loaded once its file size has to do as estimate
+        let size = self.sizes.get(&video_num).copied()
+            .unwrap_or_else(|| fs::metadata(&paths[video_num]).map_or(0, |metadata| metadata.len() as usize));
+        self.cache.has_room(size, self.preloads_before(video_num)).then_some(video_num)
+    }
+
+    /// Preloads more likely wanted than the video
+    fn preloads_before(&self, video_num: usize) -> &[usize] {
+        let end = self.preload.iter().position(|v| *v == video_num).unwrap_or(self.preload.len());
+        &self.preload[..end]
+    }
+}
+
+/// The videos the engine can switch between.
+///
+/// Videos are loaded by a background thread into a `VideoCache`, either when the engine requests
+/// them with `try_get` or ahead of time when they are likely played next.
+pub struct VideoLibrary {
+    paths: Vec<PathBuf>,
+    loader: Arc<(Mutex<LoaderState>, Condvar)>,
+}
+
+impl VideoLibrary {
+    /// Starts the loader thread, which keeps at most about `cache_size` bytes of videos in memory
+    pub fn new(paths: Vec<PathBuf>, cache_size: usize) -> Self {
+        let library = Self::with_cache(paths, VideoCache::new(cache_size));
+        thread::spawn({
+            let paths = library.paths.clone();
+            let loader = library.loader.clone();
+            move || run_loader(&paths, &loader)
+        });
+        library
+    }
+
+    /// Library of videos which are already in memory, e.g. for tests
+    pub fn from_videos(videos: Vec<LoadedVideo>) -> Self {
+        let paths = vec![PathBuf::new(); videos.len()];
+        let mut cache = VideoCache::new(usize::MAX);
+        for (video_num, video) in videos.into_iter().enumerate() {
+            cache.insert(video_num, Arc::new(video), &[]);
+        }
+        Self::with_cache(paths, cache)
+    }
+
+    fn with_cache(paths: Vec<PathBuf>, cache: VideoCache) -> Self {
+        let state = LoaderState {
+            cache,
+            requested: None,
+            preload: Vec::new(),
+            failed: HashMap::new(),
+            sizes: HashMap::new(),
+            stopped: false,
+        };
+        Self { paths, loader: Arc::new((Mutex::new(state), Condvar::new())) }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, LoaderState> {
+        self.loader.0.lock().unwrap()
+    }
+
+    /// Loads all videos into memory and lifts the memory budget
+    pub fn prefetch(&mut self) -> io::Result<()> {
+        self.state().cache.set_budget(usize::MAX);
+        for video_num in 0..self.len() {
+            self.get(video_num)?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn is_loaded(&self, video_num: usize) -> bool {
+        self.state().cache.contains(video_num)
+    }
+
+    /// Returns the video from memory or loads it from its file, blocking until it is loaded
+    pub fn get(&self, video_num: usize) -> io::Result<Arc<LoadedVideo>> {
+        if let Some(video) = self.state().cache.get(video_num) {
+            return Ok(video);
+        }
+        let video = Arc::new(LoadedVideo::load(&self.paths[video_num])?);
+        let mut state = self.state();
+        let preload = state.preload.clone();
+        state.cache.insert(video_num, video.clone(), &preload);
+        state.sizes.insert(video_num, video.size());
+        state.failed.remove(&video_num);
+        Ok(video)
+    }
+
+    /// Returns the video if it is in memory, otherwise lets the loader thread load it next instead
+    /// of the previously requested one.
+    /// Fails without loading again if the last attempt failed, until the file changes or `retry`.
+    pub fn try_get(&self, video_num: usize) -> io::Result<Option<Arc<LoadedVideo>>> {
+        let mut state = self.state();
+        if let Some(video) = state.cache.get(video_num) {
+            return Ok(Some(video));
+        }
+        if let Some(err) = state.failure(video_num, &self.paths[video_num]) {
+            return Err(io::Error::new(err.kind(), err.to_string()));
+        }
+        if state.requested != Some(video_num) {
+            state.requested = Some(video_num);
+            self.loader.1.notify_one();
+        }
+        Ok(None)
+    }
+
+    /// Forgets that the video failed to load, so that it is loaded again when requested
+    pub fn retry(&self, video_num: usize) {
+        self.state().failed.remove(&video_num);
+    }
+
+    /// Sets the videos to load in the background while there is room in the cache, most likely first
+    pub fn preload(&self, video_nums: &[usize]) {
+        let mut state = self.state();
+        state.preload = video_nums.to_vec();
+        self.loader.1.notify_one();
+    }
+}
+
+impl Drop for VideoLibrary {
+    fn drop(&mut self) {
+        self.state().stopped = true;
+        self.loader.1.notify_one();
+    }
+}
+
+fn run_loader(paths: &[PathBuf], loader: &(Mutex<LoaderState>, Condvar)) {
+    let (state, condvar) = loader;
+    loop {
+        let video_num = {
+            let mut state = state.lock().unwrap();
+            loop {
+                if state.stopped {
+                    return;
+                }
+                if let Some(video_num) = state.next_load(paths) {
+                    break video_num;
+                }
+                state = condvar.wait(state).unwrap();
+            }
+        };
+
+        let result = LoadedVideo::load(&paths[video_num]);
+
+        let mut state = state.lock().unwrap();
+        let requested = state.requested == Some(video_num);
+        if requested {
+            state.requested = None;
+        }
+        match result {
+            Ok(video) => {
+                let video = Arc::new(video);
+                state.sizes.insert(video_num, video.size());
+                state.failed.remove(&video_num);
+                // A preload must not push out more likely wanted videos
+                if !requested && !state.cache.has_room(video.size(), state.preloads_before(video_num)) {
+                    eprintln!("Video {:?} does not fit into the cache", paths[video_num]);
+                    continue;
+                }
+                let preload = state.preload.clone();
+                state.cache.insert(video_num, video, &preload);
+            }
+            Err(error) => {
+                eprintln!("Failed to load video {:?}: {}", paths[video_num], error);
+                let modified = modified(&paths[video_num]);
+                state.failed.insert(video_num, LoadFailure { error, modified });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_until<T>(mut poll: impl FnMut() -> Option<T>) -> T {
+        let start = Instant::now();
+        loop {
+            if let Some(value) = poll() {
+                return value;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_background_loading() {
+        let dir = std::env::temp_dir().join(format!("h264_glitcher_test_{}_library", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // 80 bytes files with 30 bytes of payloads
+        let mut paths: Vec<PathBuf> = (0..3u8).map(|video_num| {
+            let path = dir.join(format!("{}.h264", video_num));
+            let data: Vec<u8> = (0..10u8).flat_map(|i| [0, 0, 0, 1, 0x65, 0x88, video_num, i]).collect();
+            fs::write(&path, data).unwrap();
+            path
+        }).collect();
+        paths.push(dir.join("missing.h264"));
+        // A single picture can not be looped
+        let one_picture = dir.join("one_picture.h264");
+        fs::write(&one_picture, [0, 0, 0, 1, 0x65, 0x88, 0, 0]).unwrap();
+        paths.push(one_picture);
+
+        let library = VideoLibrary::new(paths, 130);
+        assert!(library.try_get(0).unwrap().is_none());
+        let playing = wait_until(|| library.try_get(0).unwrap());
+        assert_eq!(playing.frames.len(), 10);
+
+        // The second preload does not fit next to the first one
+        library.preload(&[1, 2]);
+        wait_until(|| library.is_loaded(1).then_some(()));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!library.is_loaded(2));
+
+        // Unless the first one is not wanted any more
+        library.preload(&[2]);
+        wait_until(|| library.is_loaded(2).then_some(()));
+
+        assert!(library.try_get(3).unwrap().is_none());
+        wait_until(|| library.try_get(3).err());
+        // The failure is kept until the file appears
+        assert!(library.try_get(3).is_err());
+        library.preload(&[3]);
+        assert!(library.try_get(3).is_err());
+        fs::copy(dir.join("0.h264"), dir.join("missing.h264")).unwrap();
+        assert!(library.try_get(3).unwrap().is_none());
+        wait_until(|| library.try_get(3).unwrap());
+
+        assert_eq!(library.get(4).err().map(|err| err.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_preload_larger_than_file() {
+        let dir = std::env::temp_dir().join(format!("h264_glitcher_test_{}_sizes", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // 220 bytes files, the escaped copies of the payloads make 300 bytes when loaded
+        let paths: Vec<PathBuf> = (0..2).map(|video_num| {
+            let path = dir.join(format!("{}.h264", video_num));
+            let nal_unit = [0, 0, 0, 1, 0x65, 0x88, 0, 0, 3, 1, 0, 0, 3, 1, 0, 0, 3, 1, 0, 0, 3, 1];
+            fs::write(&path, nal_unit.repeat(10)).unwrap();
+            path
+        }).collect();
+
+        let library = VideoLibrary::new(paths, 550);
+        library.preload(&[0, 1]);
+        wait_until(|| library.state().sizes.contains_key(&1).then_some(()));
+        assert_eq!(library.state().sizes[&0], 300);
+        // The second preload only fits by dropping the first one, so it is thrown away
+        thread::sleep(Duration::from_millis(50));
+        assert!(library.is_loaded(0));
+        assert!(!library.is_loaded(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
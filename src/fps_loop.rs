@@ -2,6 +2,7 @@ use std::time::{Instant, Duration};
 use std::sync::{Condvar, Mutex, Arc};
 
 pub struct LoopTimer {
+    start_time: Instant,
     loop_begin_time: Instant,
     state: Arc<(Mutex<SharedState>, Condvar)>,
 }
@@ -18,8 +19,10 @@ struct SharedState {
 
 impl LoopTimer {
     pub fn new() -> (Self, LoopController) {
+        let now = Instant::now();
         let fps_loop = Self {
-            loop_begin_time: Instant::now(),
+            start_time: now,
+            loop_begin_time: now,
             state: Arc::new((Mutex::new(SharedState {
                 fps: 30.0,
                 wake_up: false,
@@ -35,6 +38,17 @@ impl LoopTimer {
         self.loop_begin_time = Instant::now();
     }
 
+    /// Time from the creation of the timer to the beginning of the current loop iteration.
+    /// Used as presentation timestamp of the frame sent in this iteration.
+    pub fn presentation_time(&self) -> Duration {
+        self.loop_begin_time.saturating_duration_since(self.start_time)
+    }
+
+    pub fn set_fps(&mut self, fps: f32) {
+        let (mutex, _) = &*self.state;
+        mutex.lock().unwrap().fps = fps;
+    }
+
     pub fn end_loop(&mut self) {
         let (mutex, cvar) = &*self.state;
         let loop_time_left = |state: &SharedState| {
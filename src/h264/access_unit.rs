@@ -0,0 +1,277 @@
+use crate::h264::{read_ue, NALUnitType, NalUnit, ParameterSets, SliceHeader};
+use bitstream_io::{BigEndian, BitReader};
+
+/// All NAL units belonging to one primary coded picture, see 7.4.1.2.3
+#[derive(Clone, Debug, Default)]
+pub struct AccessUnit {
+    pub nal_units: Vec<NalUnit>,
+}
+
+impl AccessUnit {
+    pub fn is_idr(&self) -> bool {
+        self.nal_units
+            .iter()
+            .any(|nal_unit| nal_unit.nal_unit_type == NALUnitType::CodedSliceIdr)
+    }
+
+    pub fn has_picture(&self) -> bool {
+        self.nal_units
+            .iter()
+            .any(|nal_unit| nal_unit.nal_unit_type.is_picture_data())
+    }
+
+    pub fn has_parameter_sets(&self) -> bool {
+        self.nal_units.iter().any(|nal_unit| {
+            nal_unit.nal_unit_type == NALUnitType::Sps || nal_unit.nal_unit_type == NALUnitType::Pps
+        })
+    }
+}
+
+/// The slice header fields which differ between the first slices of two pictures, see 7.4.1.2.4
+#[derive(PartialEq)]
+struct PictureFields {
+    frame_num: u32,
+    pic_parameter_set_id: u8,
+    field_pic_flag: bool,
+    bottom_field_flag: bool,
+    is_reference: bool,
+    pic_order_cnt_lsb: u32,
+    delta_pic_order_cnt_bottom: i32,
+    delta_pic_order_cnt: [i32; 2],
+    idr_pic_id: Option<u32>,
+}
+
+impl PictureFields {
+    fn new(header: &SliceHeader, nal_ref_idc: u8) -> Self {
+        Self {
+            frame_num: header.frame_num,
+            pic_parameter_set_id: header.pic_parameter_set_id,
+            field_pic_flag: header.field_pic_flag,
+            bottom_field_flag: header.bottom_field_flag,
+            is_reference: nal_ref_idc != 0,
+            pic_order_cnt_lsb: header.pic_order_cnt_lsb,
+            delta_pic_order_cnt_bottom: header.delta_pic_order_cnt_bottom,
+            delta_pic_order_cnt: header.delta_pic_order_cnt,
+            idr_pic_id: header.idr_pic_id,
+        }
+    }
+}
+
+/// Groups NAL units into access units.
+///
+/// SPS and PPS are collected on the way so that slice headers can be compared.
+/// Slices whose header can not be parsed start a new picture if first_mb_in_slice is 0.
+pub struct AccessUnitIterator<I: Iterator<Item = NalUnit>> {
+    nal_units: I,
+    parameter_sets: ParameterSets,
+    current: AccessUnit,
+    current_has_picture: bool,
+    current_ended: bool,
+    last_picture: Option<PictureFields>,
+}
+
+impl<I: Iterator<Item = NalUnit>> AccessUnitIterator<I> {
+    pub fn new(nal_units: I) -> Self {
+        Self {
+            nal_units,
+            parameter_sets: ParameterSets::new(),
+            current: AccessUnit::default(),
+            current_has_picture: false,
+            current_ended: false,
+            last_picture: None,
+        }
+    }
+
+    /// Whether the slice is the first slice of a new primary coded picture
+    fn starts_picture(&mut self, nal_unit: &NalUnit) -> bool {
+        let header = if nal_unit.nal_unit_type.is_picture_data() {
+            self.parameter_sets.slice_header(nal_unit).ok()
+        } else {
+            None
+        };
+        match header {
+            Some(header) => {
+                // Redundant coded pictures belong to the primary picture
+                if header.redundant_pic_cnt > 0 {
+                    return false;
+                }
+                let fields = PictureFields::new(&header, nal_unit.nal_ref_idc);
+                let starts_picture = self.last_picture.as_ref() != Some(&fields);
+                self.last_picture = Some(fields);
+                starts_picture
+            }
+            None => {
+                self.last_picture = None;
+                let mut reader = BitReader::endian(nal_unit.rbsp(), BigEndian);
+                matches!(read_ue::<u32, _>(&mut reader), Ok(0))
+            }
+        }
+    }
+
+    fn starts_access_unit(&mut self, nal_unit: &NalUnit) -> bool {
+        match nal_unit.nal_unit_type {
+            NALUnitType::CodedSliceNonIdr
+            | NALUnitType::CodedSliceIdr
+            | NALUnitType::CodedSliceDataPartitionA => {
+                // NAL units before the first slice belong to its access unit already
+                self.starts_picture(nal_unit) && self.current_has_picture
+            }
+            // These may only follow the last VCL NAL unit of a picture
+            NALUnitType::Aud
+            | NALUnitType::Sps
+            | NALUnitType::Pps
+            | NALUnitType::Sei
+            | NALUnitType::PrefixNal
+            | NALUnitType::SubsetSps
+            | NALUnitType::Dps => self.current_has_picture,
+            _ => false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = NalUnit>> Iterator for AccessUnitIterator<I> {
+    type Item = AccessUnit;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(nal_unit) = self.nal_units.next() {
+            // If a parameter set fails to parse, slices referring to it are
+            // grouped by first_mb_in_slice only
+            let _ = self.parameter_sets.insert_nal_unit(&nal_unit);
+
+            let starts_access_unit = self.starts_access_unit(&nal_unit) || self.current_ended;
+            let finished = if starts_access_unit && !self.current.nal_units.is_empty() {
+                self.current_has_picture = false;
+                self.current_ended = false;
+                Some(std::mem::take(&mut self.current))
+            } else {
+                None
+            };
+
+            match nal_unit.nal_unit_type {
+                NALUnitType::EndOfSequence | NALUnitType::EndOfStream => self.current_ended = true,
+                t if t.is_picture_data() || t == NALUnitType::CodedSliceDataPartitionA => {
+                    self.current_has_picture = true
+                }
+                _ => {}
+            }
+            self.current.nal_units.push(nal_unit);
+
+            if finished.is_some() {
+                return finished;
+            }
+        }
+
+        if self.current.nal_units.is_empty() {
+            None
+        } else {
+            self.current_has_picture = false;
+            self.current_ended = false;
+            Some(std::mem::take(&mut self.current))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{pps_nal_unit, test_sps};
+    use crate::h264::{write_rbsp_trailing_bits, write_se, write_ue, PicOrderCntType, Sps};
+    use bitstream_io::{BitWrite, BitWriter};
+
+    fn nal_unit(nal_unit_type: NALUnitType, nal_ref_idc: u8, rbsp: Vec<u8>) -> NalUnit {
+        NalUnit::new(nal_ref_idc, nal_unit_type, rbsp)
+    }
+
+    /// I slice referring to the test SPS and PPS
+    fn slice(first_mb_in_slice: u32, frame_num: u32, pic_order_cnt_lsb: u32, idr: bool) -> NalUnit {
+        let sps = Sps::read(&mut BitReader::endian(sps().rbsp(), BigEndian)).unwrap();
+        let log2_max_pic_order_cnt_lsb = match sps.pic_order_cnt_type {
+            PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) => {
+                log2_max_pic_order_cnt_lsb_minus4 as u32 + 4
+            }
+            _ => unreachable!(),
+        };
+        assert!(sps.frame_mbs_only_flag);
+
+        let mut rbsp = Vec::new();
+        let mut writer = BitWriter::endian(&mut rbsp, BigEndian);
+        write_ue(&mut writer, first_mb_in_slice).unwrap();
+        write_ue(&mut writer, 7).unwrap(); // slice_type I
+        write_ue(&mut writer, 0).unwrap(); // pic_parameter_set_id
+        writer.write(sps.frame_num_bits(), frame_num).unwrap();
+        if idr {
+            write_ue(&mut writer, 0).unwrap(); // idr_pic_id
+        }
+        writer.write(log2_max_pic_order_cnt_lsb, pic_order_cnt_lsb).unwrap();
+        writer.write_bit(false).unwrap(); // no_output_of_prior_pics_flag / adaptive_ref_pic_marking_mode_flag
+        if idr {
+            writer.write_bit(false).unwrap(); // long_term_reference_flag
+        }
+        write_se(&mut writer, 0).unwrap(); // slice_qp_delta
+        writer.write(8, 0xaau8).unwrap();
+        write_rbsp_trailing_bits(&mut writer).unwrap();
+        let nal_unit_type = if idr {
+            NALUnitType::CodedSliceIdr
+        } else {
+            NALUnitType::CodedSliceNonIdr
+        };
+        nal_unit(nal_unit_type, 2, rbsp)
+    }
+
+    /// The usual test SPS, changed to pic_order_cnt_type 0
+    fn sps() -> NalUnit {
+        let mut sps = test_sps();
+        sps.pic_order_cnt_type = PicOrderCntType::Type0(2);
+        nal_unit(NALUnitType::Sps, 3, sps.to_rbsp())
+    }
+
+    fn group(nal_units: Vec<NalUnit>) -> Vec<Vec<NALUnitType>> {
+        AccessUnitIterator::new(nal_units.into_iter())
+            .map(|au| au.nal_units.iter().map(|n| n.nal_unit_type).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_multi_slice_pictures() {
+        use NALUnitType::*;
+        let nal_units = vec![
+            sps(),
+            pps_nal_unit(),
+            nal_unit(Sei, 0, vec![0x05, 0x00, 0x80]),
+            slice(0, 0, 0, true),
+            slice(10, 0, 0, true),
+            slice(0, 1, 2, false),
+            slice(10, 1, 2, false),
+            slice(20, 1, 2, false),
+            nal_unit(Aud, 0, vec![0x10]),
+            slice(0, 2, 4, false),
+            nal_unit(EndOfStream, 0, vec![]),
+        ];
+        assert_eq!(
+            group(nal_units),
+            vec![
+                vec![Sps, Pps, Sei, CodedSliceIdr, CodedSliceIdr],
+                vec![CodedSliceNonIdr, CodedSliceNonIdr, CodedSliceNonIdr],
+                vec![Aud, CodedSliceNonIdr, EndOfStream],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_same_first_mb_different_picture() {
+        use NALUnitType::*;
+        // Without parameter sets the slices are split by first_mb_in_slice
+        let nal_units = vec![slice(0, 0, 0, false), slice(3, 0, 0, false), slice(0, 0, 0, false)];
+        assert_eq!(
+            group(nal_units),
+            vec![vec![CodedSliceNonIdr, CodedSliceNonIdr], vec![CodedSliceNonIdr]]
+        );
+
+        // Slices only differing in pic_order_cnt_lsb belong to different pictures
+        let nal_units = vec![sps(), pps_nal_unit(), slice(3, 1, 2, false), slice(3, 1, 4, false)];
+        assert_eq!(
+            group(nal_units),
+            vec![vec![Sps, Pps, CodedSliceNonIdr], vec![CodedSliceNonIdr]]
+        );
+    }
+}
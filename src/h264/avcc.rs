@@ -0,0 +1,315 @@
+use crate::h264::{split_nal_units, NALUnitType, NalUnit, ParseError, Sps};
+use bitstream_io::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
+use std::convert::TryFrom;
+use std::io;
+
+// The AVC sample format of ISO/IEC 14496-15 prefixes each NAL unit with its length
+// instead of a start code. Containers and RTP use it, Annex B is only used for raw streams.
+
+const ANNEX_B_START_CODE: &[u8] = &[0x00, 0x00, 0x00, 0x01];
+
+/// Length fields can be 1, 2 or 4 bytes
+fn is_valid_length_size(length_size: u8) -> bool {
+    [1, 2, 4].contains(&length_size)
+}
+
+/// Splits length prefixed NAL units without copying.
+///
+/// Stops after the first NAL unit whose length exceeds the data,
+/// or right away with an error if the length size is invalid.
+pub struct AvccSlices<'a> {
+    data: &'a [u8],
+    length_size: usize,
+}
+
+/// `length_size` is the size of the length fields in bytes, 1, 2 or 4
+pub fn split_avcc_nal_units(data: &[u8], length_size: u8) -> AvccSlices<'_> {
+    AvccSlices {
+        data,
+        length_size: length_size as usize,
+    }
+}
+
+impl<'a> Iterator for AvccSlices<'a> {
+    type Item = Result<&'a [u8], ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        if !is_valid_length_size(self.length_size as u8) {
+            self.data = &[];
+            return Some(Err(ParseError::InvalidData));
+        }
+        let nal = self.data.get(..self.length_size).and_then(|length| {
+            let length = length.iter().fold(0usize, |acc, b| acc << 8 | *b as usize);
+            self.data.get(self.length_size..self.length_size + length)
+        });
+        match nal {
+            Some(nal) => {
+                self.data = &self.data[self.length_size + nal.len()..];
+                Some(Ok(nal))
+            }
+            None => {
+                self.data = &[];
+                Some(Err(ParseError::InvalidData))
+            }
+        }
+    }
+}
+
+/// Appends a NAL unit with its length prefix
+pub fn write_avcc_nal_unit(out: &mut Vec<u8>, nal: &[u8], length_size: u8) -> Result<(), ParseError> {
+    if !is_valid_length_size(length_size) || (nal.len() as u64) >> (length_size * 8) != 0 {
+        return Err(ParseError::InvalidData);
+    }
+    out.extend_from_slice(&(nal.len() as u32).to_be_bytes()[4 - length_size as usize..]);
+    out.extend_from_slice(nal);
+    Ok(())
+}
+
+/// Converts length prefixed NAL units to an Annex B byte stream with 4 byte start codes
+pub fn avcc_to_annex_b(data: &[u8], length_size: u8) -> Result<Vec<u8>, ParseError> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+    for nal in split_avcc_nal_units(data, length_size) {
+        out.extend_from_slice(ANNEX_B_START_CODE);
+        out.extend_from_slice(nal?);
+    }
+    Ok(out)
+}
+
+/// Converts an Annex B byte stream to length prefixed NAL units.
+///
+/// Fails if a NAL unit is too long for the length field.
+pub fn annex_b_to_avcc(data: &[u8], length_size: u8) -> Result<Vec<u8>, ParseError> {
+    let mut out = Vec::with_capacity(data.len());
+    for nal in split_nal_units(data) {
+        write_avcc_nal_unit(&mut out, nal, length_size)?;
+    }
+    Ok(out)
+}
+
+/// Fields which are only present for the high profiles
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvcHighProfileExtension {
+    pub chroma_format: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub sequence_parameter_set_exts: Vec<Vec<u8>>,
+}
+
+/// The avcC box contents, see ISO/IEC 14496-15 5.3.3.1.
+///
+/// Parameter sets are stored as NAL units including the header byte.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    pub length_size_minus_one: u8, // 0, 1 or 3
+    pub sequence_parameter_sets: Vec<Vec<u8>>,
+    pub picture_parameter_sets: Vec<Vec<u8>>,
+    pub high_profile_extension: Option<AvcHighProfileExtension>,
+}
+
+fn is_high_profile(profile_idc: u8) -> bool {
+    matches!(profile_idc, 100 | 110 | 122 | 144)
+}
+
+fn read_parameter_set_list(reader: &mut impl BitRead, count: u8) -> Result<Vec<Vec<u8>>, ParseError> {
+    (0..count)
+        .map(|_| {
+            let length = reader.read::<u16>(16)? as usize;
+            let mut data = vec![0; length];
+            reader.read_bytes(&mut data)?;
+            Ok(data)
+        })
+        .collect()
+}
+
+fn write_parameter_set_list(writer: &mut impl BitWrite, parameter_sets: &[Vec<u8>]) -> io::Result<()> {
+    for parameter_set in parameter_sets {
+        let length = u16::try_from(parameter_set.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "parameter set too long"))?;
+        writer.write(16, length)?;
+        writer.write_bytes(parameter_set)?;
+    }
+    Ok(())
+}
+
+impl AvcDecoderConfigurationRecord {
+    /// Builds the record for the given SPS and PPS NAL units
+    pub fn new(sps: &NalUnit, pps: &[NalUnit], length_size: u8) -> Result<Self, ParseError> {
+        if sps.nal_unit_type != NALUnitType::Sps || !is_valid_length_size(length_size) {
+            return Err(ParseError::InvalidData);
+        }
+        let parsed_sps = Sps::read(&mut BitReader::endian(sps.rbsp(), BigEndian))?;
+        let high_profile_extension = if is_high_profile(parsed_sps.profile_idc) {
+            Some(AvcHighProfileExtension {
+                chroma_format: parsed_sps.chroma_format_idc,
+                bit_depth_luma_minus8: parsed_sps.bit_depth_luma_minus8,
+                bit_depth_chroma_minus8: parsed_sps.bit_depth_chroma_minus8,
+                sequence_parameter_set_exts: Vec::new(),
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            // The bytes after the NAL header are profile_idc, the constraint flags and level_idc
+            profile_indication: sps.rbsp()[0],
+            profile_compatibility: sps.rbsp()[1],
+            level_indication: sps.rbsp()[2],
+            length_size_minus_one: length_size - 1,
+            sequence_parameter_sets: vec![sps.to_bytes()],
+            picture_parameter_sets: pps.iter().map(NalUnit::to_bytes).collect(),
+            high_profile_extension,
+        })
+    }
+
+    pub fn length_size(&self) -> u8 {
+        self.length_size_minus_one + 1
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = BitReader::endian(data, BigEndian);
+        let configuration_version: u8 = reader.read(8)?;
+        if configuration_version != 1 {
+            return Err(ParseError::Unimplemented);
+        }
+        let profile_indication = reader.read(8)?;
+        let profile_compatibility = reader.read(8)?;
+        let level_indication = reader.read(8)?;
+        reader.skip(6)?; // reserved
+        let length_size_minus_one = reader.read(2)?;
+        if length_size_minus_one == 2 {
+            return Err(ParseError::InvalidData);
+        }
+        reader.skip(3)?; // reserved
+        let num_sps = reader.read(5)?;
+        let sequence_parameter_sets = read_parameter_set_list(&mut reader, num_sps)?;
+        let num_pps = reader.read(8)?;
+        let picture_parameter_sets = read_parameter_set_list(&mut reader, num_pps)?;
+
+        let parsed_len = 7
+            + sequence_parameter_sets.iter().map(|s| s.len() + 2).sum::<usize>()
+            + picture_parameter_sets.iter().map(|s| s.len() + 2).sum::<usize>();
+        // Many writers leave out the extension even for high profiles
+        let high_profile_extension = if is_high_profile(profile_indication) && data.len() > parsed_len {
+            reader.skip(6)?;
+            let chroma_format = reader.read(2)?;
+            reader.skip(5)?;
+            let bit_depth_luma_minus8 = reader.read(3)?;
+            reader.skip(5)?;
+            let bit_depth_chroma_minus8 = reader.read(3)?;
+            let num_sps_ext = reader.read(8)?;
+            Some(AvcHighProfileExtension {
+                chroma_format,
+                bit_depth_luma_minus8,
+                bit_depth_chroma_minus8,
+                sequence_parameter_set_exts: read_parameter_set_list(&mut reader, num_sps_ext)?,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            profile_indication,
+            profile_compatibility,
+            level_indication,
+            length_size_minus_one,
+            sequence_parameter_sets,
+            picture_parameter_sets,
+            high_profile_extension,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl BitWrite) -> io::Result<()> {
+        if self.sequence_parameter_sets.len() > 31 || self.picture_parameter_sets.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many parameter sets"));
+        }
+        writer.write(8, 1)?; // configurationVersion
+        writer.write(8, self.profile_indication)?;
+        writer.write(8, self.profile_compatibility)?;
+        writer.write(8, self.level_indication)?;
+        writer.write(6, 0b111111)?;
+        writer.write(2, self.length_size_minus_one)?;
+        writer.write(3, 0b111)?;
+        writer.write(5, self.sequence_parameter_sets.len() as u8)?;
+        write_parameter_set_list(writer, &self.sequence_parameter_sets)?;
+        writer.write(8, self.picture_parameter_sets.len() as u8)?;
+        write_parameter_set_list(writer, &self.picture_parameter_sets)?;
+        if let Some(extension) = &self.high_profile_extension {
+            writer.write(6, 0b111111)?;
+            writer.write(2, extension.chroma_format)?;
+            writer.write(5, 0b11111)?;
+            writer.write(3, extension.bit_depth_luma_minus8)?;
+            writer.write(5, 0b11111)?;
+            writer.write(3, extension.bit_depth_chroma_minus8)?;
+            writer.write(8, extension.sequence_parameter_set_exts.len() as u8)?;
+            write_parameter_set_list(writer, &extension.sequence_parameter_set_exts)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.write(&mut BitWriter::endian(&mut data, BigEndian))?;
+        Ok(data)
+    }
+
+    /// The parameter sets as NAL units, SPS first
+    pub fn nal_units(&self) -> Result<Vec<NalUnit>, ParseError> {
+        self.sequence_parameter_sets
+            .iter()
+            .chain(&self.picture_parameter_sets)
+            .map(|data| NalUnit::from_bytes(data))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{pps_nal_unit, sps_nal_unit};
+
+    #[test]
+    fn test_annex_b_roundtrip() {
+        let (sps, pps) = (sps_nal_unit().to_bytes(), pps_nal_unit().to_bytes());
+        let annex_b: Vec<u8> = [ANNEX_B_START_CODE, &sps, ANNEX_B_START_CODE, &pps].concat();
+        for length_size in [1, 2, 4] {
+            let avcc = annex_b_to_avcc(&annex_b, length_size).unwrap();
+            assert_eq!(avcc.len(), sps.len() + pps.len() + 2 * length_size as usize);
+            assert_eq!(avcc_to_annex_b(&avcc, length_size).unwrap(), annex_b);
+        }
+
+        let long_nal = vec![0x65; 300];
+        assert!(annex_b_to_avcc(&[ANNEX_B_START_CODE, &long_nal].concat(), 1).is_err());
+        // Length exceeds the data
+        assert!(avcc_to_annex_b(&[0, 5, 0x65, 0x88], 2).is_err());
+        for length_size in [0, 3, 5] {
+            assert!(avcc_to_annex_b(&[0, 0, 0, 2, 0x65, 0x88], length_size).is_err());
+            assert_eq!(split_avcc_nal_units(&[0, 0, 0, 2, 0x65, 0x88], length_size).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_decoder_configuration_record() {
+        let sps = sps_nal_unit();
+        let pps = pps_nal_unit();
+        let record = AvcDecoderConfigurationRecord::new(&sps, &[pps], 4).unwrap();
+        assert_eq!(record.profile_indication, 100);
+        assert_eq!(record.level_indication, 40);
+        assert_eq!(record.high_profile_extension.as_ref().unwrap().chroma_format, 1);
+
+        let bytes = record.to_bytes().unwrap();
+        assert_eq!(&bytes[..6], &[1, 100, 0, 40, 0xff, 0xe1]);
+        assert_eq!(AvcDecoderConfigurationRecord::from_bytes(&bytes).unwrap(), record);
+
+        // Without the high profile extension
+        let short = &bytes[..bytes.len() - 4];
+        let parsed = AvcDecoderConfigurationRecord::from_bytes(short).unwrap();
+        assert_eq!(parsed.high_profile_extension, None);
+        assert_eq!(parsed.nal_units().unwrap().len(), 2);
+    }
+}
@@ -1,15 +1,29 @@
 pub mod h264;
 pub mod nal_iterator;
+pub mod avcc;
 pub mod parse_h264;
 pub mod nal;
 pub mod slice_header;
 pub mod sps;
 pub mod pps;
+pub mod scaling_matrix;
+pub mod parameter_sets;
+pub mod sei;
+pub mod access_unit;
+pub mod rewrite;
+#[cfg(test)]
+pub mod test_data;
 
 pub use nal_iterator::*;
+pub use avcc::*;
 pub use h264::*;
 pub use nal::*;
 pub use parse_h264::*;
 pub use slice_header::*;
 pub use sps::*;
-pub use pps::*;
\ No newline at end of file
+pub use pps::*;
+pub use scaling_matrix::*;
+pub use parameter_sets::*;
+pub use sei::*;
+pub use access_unit::*;
+pub use rewrite::*;
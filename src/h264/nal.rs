@@ -2,14 +2,21 @@ use crate::h264::ParseError;
 use crate::h264::NALUnitType;
 use enum_primitive::FromPrimitive;
 use std::fmt;
-use bitstream_io::{BigEndian, BitWriter, BitWrite, BitReader, BitRead};
+use std::io::{self, Write};
+use std::sync::Arc;
+use bitstream_io::{BigEndian, BitReader, BitRead};
 
 
+/// A NAL unit with its payload both as RBSP for parsing and escaped as it is written to the
+/// stream. The payload is shared between clones, so NAL units can be passed through without
+/// copying or escaping them again.
 #[derive(Clone, Debug)]
 pub struct NalUnit {
     pub nal_ref_idc : u8,
     pub nal_unit_type: NALUnitType,
-    pub rbsp: Vec<u8>,
+    rbsp: Arc<Vec<u8>>,
+    // None if the RBSP contains no emulation prevention bytes
+    escaped: Option<Arc<Vec<u8>>>,
 }
 
 impl fmt::Display for NalUnit {
@@ -40,6 +47,22 @@ fn decode_nal_to_rbsp(bytes: &[u8]) -> Vec<u8> {
     rbsp
 }
 
+/// Position in the escaped payload where the RBSP byte at `rbsp_position` starts
+fn escaped_position(escaped: &[u8], rbsp_position: usize) -> usize {
+    let mut i = 0;
+    let mut decoded = 0;
+    while decoded < rbsp_position {
+        if i + 2 < escaped.len() && escaped[i..=i+2] == [0x00, 0x00, 0x03] {
+            i += 3;
+            decoded += 2;
+        } else {
+            i += 1;
+            decoded += 1;
+        }
+    }
+    i
+}
+
 fn encode_rbsp_to_nal(bytes: &[u8]) -> Vec<u8> {
     let mut nal = Vec::with_capacity(bytes.len() * 3 / 2);
 
@@ -81,24 +104,90 @@ impl NalUnit {
         }
 
         let nal_unit_type = NALUnitType::from_u8(nal_unit_type).ok_or(ParseError::InvalidData)?;
-        let rbsp = decode_nal_to_rbsp(&bytes[nal_unit_header_bytes..]);
+        let escaped = &bytes[nal_unit_header_bytes..];
+        let rbsp = decode_nal_to_rbsp(escaped);
+        let escaped = (rbsp.len() != escaped.len()).then(|| Arc::new(escaped.to_vec()));
 
         Ok(Self {
             nal_ref_idc,
             nal_unit_type,
-            rbsp
+            rbsp: Arc::new(rbsp),
+            escaped,
         })
     }
 
+    pub fn new(nal_ref_idc: u8, nal_unit_type: NALUnitType, rbsp: Vec<u8>) -> Self {
+        let mut nal_unit = Self {
+            nal_ref_idc,
+            nal_unit_type,
+            rbsp: Arc::default(),
+            escaped: None,
+        };
+        nal_unit.set_rbsp(rbsp);
+        nal_unit
+    }
+
+    pub fn rbsp(&self) -> &[u8] {
+        &self.rbsp
+    }
+
+    pub fn set_rbsp(&mut self, rbsp: Vec<u8>) {
+        let escaped = encode_rbsp_to_nal(&rbsp);
+        self.escaped = (escaped.len() != rbsp.len()).then(|| Arc::new(escaped));
+        self.rbsp = Arc::new(rbsp);
+    }
+
+    /// Replaces the beginning of the RBSP, e.g. with a rewritten slice header of the same length.
+    /// Only the changed bytes are escaped again, the rest of the escaped payload is copied.
+    pub fn replace_rbsp_prefix(&mut self, prefix: &[u8]) {
+        let mut rbsp = Vec::with_capacity(self.rbsp.len());
+        rbsp.extend_from_slice(prefix);
+        rbsp.extend_from_slice(&self.rbsp[prefix.len()..]);
+
+        // Escaping only depends on the preceding zero bytes,
+        // so the old escaped payload is valid again after the first unchanged non zero byte
+        let split = match (prefix.len() + 1..=rbsp.len()).find(|&i| rbsp[i - 1] != 0) {
+            Some(split) => split,
+            None => return self.set_rbsp(rbsp),
+        };
+        let old_escaped = self.escaped_payload();
+        let suffix_start = match &self.escaped {
+            Some(escaped) => escaped_position(escaped, split),
+            None => split,
+        };
+        let mut escaped = encode_rbsp_to_nal(&rbsp[..split]);
+        escaped.extend_from_slice(&old_escaped[suffix_start..]);
+
+        self.escaped = (escaped.len() != rbsp.len()).then(|| Arc::new(escaped));
+        self.rbsp = Arc::new(rbsp);
+    }
+
+    /// The payload with emulation prevention bytes, without the NAL unit header
+    pub fn escaped_payload(&self) -> &[u8] {
+        self.escaped.as_deref().unwrap_or(&self.rbsp)
+    }
+
+    /// Bytes held for the payload, the escaped copy only counts if there is one
+    pub fn payload_size(&self) -> usize {
+        self.rbsp.len() + self.escaped.as_ref().map_or(0, |escaped| escaped.len())
+    }
+
+    fn header_byte(&self) -> u8 {
+        // forbidden_zero_bit is 0
+        (self.nal_ref_idc & 0x03) << 5 | self.nal_unit_type as u8
+    }
+
+    /// Writes the NAL unit header and the escaped payload
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&[self.header_byte()])?;
+        writer.write_all(self.escaped_payload())
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut vec = Vec::new();
-        let mut writer = BitWriter::endian(&mut vec, BigEndian);
-
-        writer.write_bit(false).unwrap();
-        writer.write(2, self.nal_ref_idc).unwrap();
-        writer.write(5, self.nal_unit_type as u8).unwrap();
-        assert!(writer.byte_aligned());
-        writer.write_bytes(&encode_rbsp_to_nal(&self.rbsp)).unwrap();
+        let payload = self.escaped_payload();
+        let mut vec = Vec::with_capacity(payload.len() + 1);
+        vec.push(self.header_byte());
+        vec.extend_from_slice(payload);
         vec
     }
 }
@@ -109,6 +198,22 @@ impl NalUnit {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_replace_rbsp_prefix() {
+        let rbsp = vec![0x9a, 0x00, 0x00, 0x01, 0x55, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x80];
+        let mut nal_unit = NalUnit::new(2, NALUnitType::CodedSliceNonIdr, rbsp.clone());
+        assert_eq!(NalUnit::from_bytes(&nal_unit.to_bytes()).unwrap().rbsp(), rbsp.as_slice());
+
+        for prefix in [&[0x00, 0x00][..], &[0x9b, 0x01], &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00], &[0x12; 12]].iter() {
+            let mut expected = rbsp.clone();
+            expected[..prefix.len()].copy_from_slice(prefix);
+            nal_unit.set_rbsp(rbsp.clone());
+            nal_unit.replace_rbsp_prefix(prefix);
+            assert_eq!(nal_unit.rbsp(), expected.as_slice());
+            assert_eq!(nal_unit.to_bytes(), NalUnit::new(2, NALUnitType::CodedSliceNonIdr, expected).to_bytes());
+        }
+    }
+
     #[test]
     fn test_encode_rbsp_to_nal() {
         assert_eq!(encode_rbsp_to_nal(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x01]), &[0x00, 0x01, 0x00, 0x00, 0x03, 0x00, 0x01]);
@@ -1,4 +1,9 @@
+use memchr::memmem;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read};
 use std::iter::Iterator;
+use std::path::Path;
 
 //Info on byte stream format
 //https://yumichan.net/video-processing/video-compression/introduction-to-h264-nal-unit/
@@ -65,9 +70,159 @@ where
     }
 }
 
+const START_CODE: &[u8] = &[0x00, 0x00, 0x01];
+
+/// Removes the leading zero of a 4 byte start code and trailing_zero_8bits.
+/// A NAL unit never ends with a zero byte.
+fn strip_trailing_zeros(nal: &[u8]) -> &[u8] {
+    let len = nal.iter().rposition(|b| *b != 0x00).map_or(0, |i| i + 1);
+    &nal[..len]
+}
+
+/// Splits an Annex B byte stream held in memory into NAL units without copying.
+///
+/// Bytes before the first start code are skipped. Unlike `NalIterator`, the last NAL unit
+/// is returned even if no start code follows it.
+pub struct NalSlices<'a> {
+    data: &'a [u8],
+    finder: memmem::Finder<'static>,
+    next_start: Option<usize>,
+}
+
+pub fn split_nal_units(data: &[u8]) -> NalSlices<'_> {
+    let finder = memmem::Finder::new(START_CODE);
+    let next_start = finder.find(data).map(|i| i + START_CODE.len());
+    NalSlices {
+        data,
+        finder,
+        next_start,
+    }
+}
+
+impl<'a> Iterator for NalSlices<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.next_start?;
+            let end = match self.finder.find(&self.data[start..]) {
+                Some(i) => {
+                    self.next_start = Some(start + i + START_CODE.len());
+                    start + i
+                }
+                None => {
+                    self.next_start = None;
+                    self.data.len()
+                }
+            };
+            let nal = strip_trailing_zeros(&self.data[start..end]);
+            if !nal.is_empty() {
+                return Some(nal);
+            }
+        }
+    }
+}
+
+/// Memory maps a file, to be split with `split_nal_units`.
+///
+/// The file must not be truncated while the map is alive.
+pub fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // Safety: the videos are only read, modifying them while playing is not supported
+    unsafe { Mmap::map(&file) }
+}
+
+/// Splits an Annex B byte stream from a reader into NAL units.
+///
+/// Yields the same NAL units as `split_nal_units` and stops after the first read error.
+pub struct NalReader<R: Read> {
+    reader: R,
+    finder: memmem::Finder<'static>,
+    buffer: Vec<u8>,
+    // Everything in the buffer before this position does not contain a start code
+    searched: usize,
+    found_first_start_code: bool,
+    eof: bool,
+}
+
+impl<R: Read> NalReader<R> {
+    const CHUNK_SIZE: usize = 1 << 16;
+
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            finder: memmem::Finder::new(START_CODE),
+            buffer: Vec::new(),
+            searched: 0,
+            found_first_start_code: false,
+            eof: false,
+        }
+    }
+
+    /// Reads the next chunk, returns false at the end of the stream
+    fn fill_buffer(&mut self) -> io::Result<bool> {
+        let len = self.buffer.len();
+        self.buffer.resize(len + Self::CHUNK_SIZE, 0);
+        loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(n) => {
+                    self.buffer.truncate(len + n);
+                    return Ok(n > 0);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.buffer.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for NalReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(i) = self.finder.find(&self.buffer[self.searched..]) {
+                let end = self.searched + i;
+                let nal = strip_trailing_zeros(&self.buffer[..end]).to_vec();
+                self.buffer.drain(..end + START_CODE.len());
+                self.searched = 0;
+                if !std::mem::replace(&mut self.found_first_start_code, true) || nal.is_empty() {
+                    continue;
+                }
+                return Some(Ok(nal));
+            }
+
+            if self.eof {
+                let nal = strip_trailing_zeros(&self.buffer).to_vec();
+                self.buffer.clear();
+                self.searched = 0;
+                if self.found_first_start_code && !nal.is_empty() {
+                    return Some(Ok(nal));
+                }
+                return None;
+            }
+
+            // A start code may be split between the old and the new data
+            self.searched = self.buffer.len().saturating_sub(START_CODE.len() - 1);
+            match self.fill_buffer() {
+                Ok(more) => self.eof = !more,
+                Err(e) => {
+                    self.eof = true;
+                    self.buffer.clear();
+                    self.searched = 0;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::NalIterator;
+    use super::{split_nal_units, NalIterator, NalReader};
     use std::io::Read;
     #[test]
     fn test_short_head() {
@@ -111,6 +266,53 @@ mod test {
         assert_eq!(items[1], packet);
     }
 
+    /// Returns the data in pieces of 3 bytes
+    struct SlowReader<'a>(&'a [u8]);
+
+    impl<'a> Read for SlowReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_split_nal_units() {
+        let data : &[u8] = &[0xaa, 0x00, 0x00, 0x00, 0x01, 0xbb, 0x00, 0x00, 0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0xcc, 0xcc, 0x00, 0x00, 0x00, 0x00, 0x01, 0xdd, 0x00, 0x01, 0x00];
+        let expected : Vec<&[u8]> = vec![&[0xbb, 0x00, 0x00, 0x03], &[0xcc, 0xcc], &[0xdd, 0x00, 0x01]];
+        let items: Vec<_> = split_nal_units(data).collect();
+        assert_eq!(items, expected);
+
+        let items: Vec<_> = NalReader::new(SlowReader(data)).map(|r| r.unwrap()).collect();
+        assert_eq!(items, expected);
+        let items: Vec<_> = NalReader::new(data).map(|r| r.unwrap()).collect();
+        assert_eq!(items, expected);
+    }
+
+    #[test]
+    fn test_split_without_start_code() {
+        let data : &[u8] = &[0xaa, 0x00, 0x00, 0x02, 0xbb];
+        assert_eq!(split_nal_units(data).count(), 0);
+        assert_eq!(NalReader::new(data).count(), 0);
+        assert_eq!(split_nal_units(&[]).count(), 0);
+    }
+
+    #[test]
+    fn test_nal_reader_error() {
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+        }
+        let data : &[u8] = &[0x00, 0x00, 0x01, 0xbb];
+        let mut it = NalReader::new(data.chain(FailingReader));
+        assert!(it.next().unwrap().is_err());
+        assert!(it.next().is_none());
+    }
+
     #[test]
     fn smoke_test() {
         let file = std::fs::File::open("./big_buck_bunny.h264").unwrap();
@@ -0,0 +1,151 @@
+use crate::h264::{read_ue, NALUnitType, NalUnit, ParseError, Pps, SliceHeader, Sps};
+use bitstream_io::{BigEndian, BitReader};
+use std::collections::HashMap;
+
+/// Sequence and picture parameter sets known for a stream, keyed by their id.
+///
+/// A parameter set sent again with the same id replaces the previous one.
+#[derive(Clone, Debug, Default)]
+pub struct ParameterSets {
+    sps: HashMap<u8, Sps>,
+    pps: HashMap<u8, Pps>,
+}
+
+impl ParameterSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Collects all parameter sets from a stream of NAL units
+    pub fn from_nal_units<'a, I: IntoIterator<Item = &'a NalUnit>>(
+        nal_units: I,
+    ) -> Result<Self, ParseError> {
+        let mut parameter_sets = Self::new();
+        for nal_unit in nal_units {
+            parameter_sets.insert_nal_unit(nal_unit)?;
+        }
+        Ok(parameter_sets)
+    }
+
+    /// Parses and stores the NAL unit if it is a SPS or PPS. Other NAL units are ignored.
+    ///
+    /// A PPS with 8x8 scaling lists can only be parsed after the SPS it refers to.
+    ///
+    /// Returns whether the NAL unit was a parameter set.
+    pub fn insert_nal_unit(&mut self, nal_unit: &NalUnit) -> Result<bool, ParseError> {
+        let mut reader = BitReader::endian(nal_unit.rbsp(), BigEndian);
+        match nal_unit.nal_unit_type {
+            NALUnitType::Sps => {
+                self.insert_sps(Sps::read(&mut reader)?);
+                Ok(true)
+            }
+            NALUnitType::Pps => {
+                let pps = Pps::read(&mut reader, self)?;
+                self.insert_pps(pps);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn insert_sps(&mut self, sps: Sps) {
+        self.sps.insert(sps.seq_parameter_set_id, sps);
+    }
+
+    pub fn insert_pps(&mut self, pps: Pps) {
+        self.pps.insert(pps.pic_parameter_set_id, pps);
+    }
+
+    pub fn sps(&self, seq_parameter_set_id: u8) -> Option<&Sps> {
+        self.sps.get(&seq_parameter_set_id)
+    }
+
+    pub fn pps(&self, pic_parameter_set_id: u8) -> Option<&Pps> {
+        self.pps.get(&pic_parameter_set_id)
+    }
+
+    /// All SPS ordered by id
+    pub fn all_sps(&self) -> impl Iterator<Item = &Sps> {
+        let mut all: Vec<_> = self.sps.values().collect();
+        all.sort_by_key(|sps| sps.seq_parameter_set_id);
+        all.into_iter()
+    }
+
+    /// All PPS ordered by id
+    pub fn all_pps(&self) -> impl Iterator<Item = &Pps> {
+        let mut all: Vec<_> = self.pps.values().collect();
+        all.sort_by_key(|pps| pps.pic_parameter_set_id);
+        all.into_iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sps.is_empty() && self.pps.is_empty()
+    }
+
+    /// Looks up a PPS and the SPS it refers to
+    pub fn get(&self, pic_parameter_set_id: u8) -> Result<(&Sps, &Pps), ParseError> {
+        let pps = self
+            .pps(pic_parameter_set_id)
+            .ok_or(ParseError::MissingParameterSet)?;
+        let sps = self
+            .sps(pps.seq_parameter_set_id)
+            .ok_or(ParseError::MissingParameterSet)?;
+        Ok((sps, pps))
+    }
+
+    /// Resolves the PPS and SPS a slice NAL unit refers to, without parsing the whole header
+    pub fn for_slice(&self, nal_unit: &NalUnit) -> Result<(&Sps, &Pps), ParseError> {
+        if !nal_unit.nal_unit_type.is_picture_data() {
+            return Err(ParseError::InvalidData);
+        }
+        let mut reader = BitReader::endian(nal_unit.rbsp(), BigEndian);
+        let _first_mb_in_slice: u32 = read_ue(&mut reader)?;
+        let _slice_type: u32 = read_ue(&mut reader)?;
+        let pic_parameter_set_id = read_ue(&mut reader)?;
+        self.get(pic_parameter_set_id)
+    }
+
+    /// Parses the header of a slice NAL unit
+    pub fn slice_header(&self, nal_unit: &NalUnit) -> Result<SliceHeader, ParseError> {
+        SliceHeader::from_bytes(
+            nal_unit.rbsp(),
+            nal_unit.nal_unit_type,
+            nal_unit.nal_ref_idc,
+            self,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{pps_nal_unit, sps_nal_unit};
+
+    #[test]
+    fn test_collect_and_resolve() {
+        let slice = NalUnit::new(0, NALUnitType::CodedSliceNonIdr, vec![0b1011_1000]);
+        let nal_units = vec![sps_nal_unit(), pps_nal_unit(), slice.clone()];
+        let parameter_sets = ParameterSets::from_nal_units(&nal_units).unwrap();
+
+        assert_eq!(parameter_sets.all_sps().count(), 1);
+        assert_eq!(parameter_sets.all_pps().count(), 1);
+
+        let (sps, pps) = parameter_sets.for_slice(&slice).unwrap();
+        assert_eq!(sps.seq_parameter_set_id, 0);
+        assert_eq!(pps.pic_parameter_set_id, 0);
+    }
+
+    #[test]
+    fn test_missing_sps() {
+        let nal_units = vec![pps_nal_unit()];
+        let parameter_sets = ParameterSets::from_nal_units(&nal_units).unwrap();
+        assert!(matches!(
+            parameter_sets.get(0),
+            Err(ParseError::MissingParameterSet)
+        ));
+        assert!(matches!(
+            parameter_sets.get(1),
+            Err(ParseError::MissingParameterSet)
+        ));
+    }
+}
@@ -8,6 +8,7 @@ pub enum ParseError {
     InvalidData,
     IoError(io::Error),
     Unimplemented,
+    MissingParameterSet,
 }
 
 impl From<io::Error> for ParseError {
@@ -61,6 +62,15 @@ pub fn write_ue<W: BitWrite>(writer: &mut W, value: u32) -> io::Result<()> {
     Ok(())
 }
 
+pub fn write_se<W: BitWrite>(writer: &mut W, value: i32) -> io::Result<()> {
+    let mapped = if value > 0 {
+        (value as u32) * 2 - 1
+    } else {
+        value.unsigned_abs() * 2
+    };
+    write_ue(writer, mapped)
+}
+
 pub fn read_optional<T, R, F>(reader: &mut R, read_contents: F) -> Result<Option<T>, ParseError>
 where
     R: BitRead,
@@ -173,6 +183,20 @@ mod test {
         }
     }
 
+    #[test]
+    fn write_se_test() {
+        for i in -1000..1000 {
+            let mut vec = Vec::new();
+            let mut writer = BitWriter::endian(&mut vec, BigEndian);
+            write_se(&mut writer, i).unwrap();
+            writer.byte_align().unwrap();
+            assert_eq!(
+                read_se::<i32, _>(&mut BitReader::endian(vec.as_slice(), BigEndian)).unwrap(),
+                i
+            );
+        }
+    }
+
     #[test]
     fn test_ue_barely_overflow() {
         for i in 0..u8::MAX {
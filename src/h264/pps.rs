@@ -1,17 +1,184 @@
 use crate::h264::{
-    more_rbsp_data, read_optional_unimplemented, read_rbsp_trailing_bits, read_se, read_ue,
-    ParseError,
+    more_rbsp_data, read_optional, read_rbsp_trailing_bits, read_se, read_ue, write_optional,
+    write_rbsp_trailing_bits, write_se, write_ue, ParameterSets, ParseError, ScalingMatrix,
 };
-use bitstream_io::BitRead;
+use bitstream_io::{BigEndian, BitRead, BitWrite, BitWriter};
 use visit_diff::Diff;
 
+/// slice_group_map_type and the parameters belonging to it
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub enum SliceGroupMapType {
+    /// 0, run_length_minus1 for each slice group
+    Interleaved { run_length_minus1: Vec<u32> },
+    /// 1
+    Dispersed,
+    /// 2, (top_left, bottom_right) for each slice group but the last
+    Foreground { rectangles: Vec<(u32, u32)> },
+    /// 3
+    BoxOut(SliceGroupChange),
+    /// 4
+    RasterScan(SliceGroupChange),
+    /// 5
+    Wipe(SliceGroupChange),
+    /// 6, slice_group_id for each map unit
+    Explicit { slice_group_id: Vec<u32> },
+}
+
+/// Parameters of the evolving slice group map types 3 to 5
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub struct SliceGroupChange {
+    pub slice_group_change_direction_flag: bool,
+    pub slice_group_change_rate_minus1: u32,
+}
+
+impl SliceGroupChange {
+    fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
+        Ok(Self {
+            slice_group_change_direction_flag: reader.read_bit()?,
+            slice_group_change_rate_minus1: read_ue(reader)?,
+        })
+    }
+
+    fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        writer.write_bit(self.slice_group_change_direction_flag)?;
+        write_ue(writer, self.slice_group_change_rate_minus1)
+    }
+}
+
+/// Flexible macroblock ordering, only present if num_slice_groups_minus1 > 0
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub struct SliceGroups {
+    pub num_slice_groups_minus1: u32, // 1 to 7
+    pub slice_group_map_type: SliceGroupMapType,
+}
+
+impl SliceGroups {
+    pub fn read(
+        reader: &mut impl BitRead,
+        num_slice_groups_minus1: u32,
+    ) -> Result<Self, ParseError> {
+        let slice_group_map_type = match read_ue::<u32, _>(reader)? {
+            0 => SliceGroupMapType::Interleaved {
+                run_length_minus1: (0..=num_slice_groups_minus1)
+                    .map(|_| read_ue(reader))
+                    .collect::<Result<_, _>>()?,
+            },
+            1 => SliceGroupMapType::Dispersed,
+            2 => SliceGroupMapType::Foreground {
+                rectangles: (0..num_slice_groups_minus1)
+                    .map(|_| Ok((read_ue(reader)?, read_ue(reader)?)))
+                    .collect::<Result<_, ParseError>>()?,
+            },
+            3 => SliceGroupMapType::BoxOut(SliceGroupChange::read(reader)?),
+            4 => SliceGroupMapType::RasterScan(SliceGroupChange::read(reader)?),
+            5 => SliceGroupMapType::Wipe(SliceGroupChange::read(reader)?),
+            6 => {
+                let pic_size_in_map_units_minus1: u32 = read_ue(reader)?;
+                let bits = Self::slice_group_id_bits(num_slice_groups_minus1);
+                SliceGroupMapType::Explicit {
+                    slice_group_id: (0..=pic_size_in_map_units_minus1)
+                        .map(|_| reader.read(bits))
+                        .collect::<Result<_, _>>()?,
+                }
+            }
+            _ => return Err(ParseError::InvalidData),
+        };
+        Ok(Self {
+            num_slice_groups_minus1,
+            slice_group_map_type,
+        })
+    }
+
+    /// Writes everything after num_slice_groups_minus1.
+    /// Fails if the number of entries does not match num_slice_groups_minus1.
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        let invalid_input = |msg| Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        match &self.slice_group_map_type {
+            SliceGroupMapType::Interleaved { run_length_minus1 } => {
+                if run_length_minus1.len() as u64 != self.num_slice_groups_minus1 as u64 + 1 {
+                    return invalid_input("one run_length_minus1 per slice group expected");
+                }
+                write_ue(writer, 0)?;
+                for run_length_minus1 in run_length_minus1 {
+                    write_ue(writer, *run_length_minus1)?;
+                }
+            }
+            SliceGroupMapType::Dispersed => write_ue(writer, 1)?,
+            SliceGroupMapType::Foreground { rectangles } => {
+                if rectangles.len() as u64 != self.num_slice_groups_minus1 as u64 {
+                    return invalid_input("one rectangle per slice group except the last expected");
+                }
+                write_ue(writer, 2)?;
+                for (top_left, bottom_right) in rectangles {
+                    write_ue(writer, *top_left)?;
+                    write_ue(writer, *bottom_right)?;
+                }
+            }
+            SliceGroupMapType::BoxOut(change) => {
+                write_ue(writer, 3)?;
+                change.write(writer)?;
+            }
+            SliceGroupMapType::RasterScan(change) => {
+                write_ue(writer, 4)?;
+                change.write(writer)?;
+            }
+            SliceGroupMapType::Wipe(change) => {
+                write_ue(writer, 5)?;
+                change.write(writer)?;
+            }
+            SliceGroupMapType::Explicit { slice_group_id } => {
+                // One slice_group_id per map unit
+                if slice_group_id.is_empty() {
+                    return invalid_input("slice_group_id is empty");
+                }
+                if slice_group_id.iter().any(|id| *id > self.num_slice_groups_minus1) {
+                    return invalid_input("slice_group_id out of range");
+                }
+                write_ue(writer, 6)?;
+                write_ue(writer, slice_group_id.len() as u32 - 1)?;
+                let bits = Self::slice_group_id_bits(self.num_slice_groups_minus1);
+                for id in slice_group_id {
+                    writer.write(bits, *id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ceil(Log2(num_slice_groups_minus1 + 1))
+    fn slice_group_id_bits(num_slice_groups_minus1: u32) -> u32 {
+        32 - num_slice_groups_minus1.leading_zeros()
+    }
+
+    /// Length of slice_group_change_cycle in slice headers, only present for map types 3 to 5
+    pub fn slice_group_change_cycle_bits(&self, pic_size_in_map_units: u32) -> Option<u32> {
+        let slice_group_change_rate = self.change()?.slice_group_change_rate_minus1 as u64 + 1;
+        // Ceil(Log2(PicSizeInMapUnits / SliceGroupChangeRate + 1)) without rounding the division
+        let mut bits = 0;
+        while (slice_group_change_rate << bits) < pic_size_in_map_units as u64 + slice_group_change_rate {
+            bits += 1;
+        }
+        Some(bits)
+    }
+
+    /// The slice group change parameters for map types 3 to 5
+    pub fn change(&self) -> Option<&SliceGroupChange> {
+        match &self.slice_group_map_type {
+            SliceGroupMapType::BoxOut(change)
+            | SliceGroupMapType::RasterScan(change)
+            | SliceGroupMapType::Wipe(change) => Some(change),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Diff, PartialEq)]
 pub struct Pps {
     pub pic_parameter_set_id: u8,
     pub seq_parameter_set_id: u8,
     pub entropy_coding_mode_flag: bool,
     pub bottom_field_pic_order_in_frame_present_flag: bool,
-    //pub slice_groups: Vec<SliceGroup>,
+    pub slice_groups: Option<SliceGroups>,
     pub num_ref_idx_l0_default_active_minus1: u8, // 0 to 31
     pub num_ref_idx_l1_default_active_minus1: u8, // 0 to 31
     pub weighted_pred_flag: bool,
@@ -26,16 +193,22 @@ pub struct Pps {
 }
 
 impl Pps {
-    pub fn read<R: BitRead + Clone>(reader: &mut R) -> Result<Self, ParseError> {
+    /// The SPS referred to is only needed if the PPS contains 8x8 scaling lists
+    pub fn read<R: BitRead + Clone>(
+        reader: &mut R,
+        parameter_sets: &ParameterSets,
+    ) -> Result<Self, ParseError> {
         let pic_parameter_set_id = read_ue(reader)?;
         let seq_parameter_set_id = read_ue(reader)?;
         let entropy_coding_mode_flag = reader.read_bit()?;
         let bottom_field_pic_order_in_frame_present_flag = reader.read_bit()?;
 
-        //TODO slice_groups
-        if read_ue::<u32, _>(reader)? != 0 {
-            return Err(ParseError::Unimplemented);
-        }
+        let num_slice_groups_minus1 = read_ue(reader)?;
+        let slice_groups = if num_slice_groups_minus1 > 0 {
+            Some(SliceGroups::read(reader, num_slice_groups_minus1)?)
+        } else {
+            None
+        };
 
         let num_ref_idx_l0_default_active_minus1 = read_ue(reader)?;
         let num_ref_idx_l1_default_active_minus1 = read_ue(reader)?;
@@ -50,7 +223,10 @@ impl Pps {
 
         let mut pps_more_data = None;
         if more_rbsp_data(reader)? {
-            pps_more_data = Some(PpsMoreData::read(reader)?);
+            let chroma_format_idc = parameter_sets
+                .sps(seq_parameter_set_id)
+                .map(|sps| sps.chroma_format_idc);
+            pps_more_data = Some(PpsMoreData::read(reader, chroma_format_idc)?);
         }
 
         read_rbsp_trailing_bits(reader)?;
@@ -60,6 +236,7 @@ impl Pps {
             seq_parameter_set_id,
             entropy_coding_mode_flag,
             bottom_field_pic_order_in_frame_present_flag,
+            slice_groups,
             num_ref_idx_l0_default_active_minus1,
             num_ref_idx_l1_default_active_minus1,
             weighted_pred_flag,
@@ -73,23 +250,167 @@ impl Pps {
             pps_more_data,
         })
     }
+
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        write_ue(writer, self.pic_parameter_set_id.into())?;
+        write_ue(writer, self.seq_parameter_set_id.into())?;
+        writer.write_bit(self.entropy_coding_mode_flag)?;
+        writer.write_bit(self.bottom_field_pic_order_in_frame_present_flag)?;
+
+        if let Some(slice_groups) = &self.slice_groups {
+            write_ue(writer, slice_groups.num_slice_groups_minus1)?;
+            slice_groups.write(writer)?;
+        } else {
+            write_ue(writer, 0)?;
+        }
+
+        write_ue(writer, self.num_ref_idx_l0_default_active_minus1.into())?;
+        write_ue(writer, self.num_ref_idx_l1_default_active_minus1.into())?;
+        writer.write_bit(self.weighted_pred_flag)?;
+        writer.write(2, self.weighted_bipred_idc)?;
+        write_se(writer, self.pic_init_qp_minus26.into())?;
+        write_se(writer, self.pic_init_qs_minus26.into())?;
+        write_se(writer, self.chroma_qp_index_offset.into())?;
+        writer.write_bit(self.deblocking_filter_control_present_flag)?;
+        writer.write_bit(self.constrained_intra_pred_flag)?;
+        writer.write_bit(self.redundant_pic_cnt_present_flag)?;
+
+        if let Some(pps_more_data) = &self.pps_more_data {
+            pps_more_data.write(writer)?;
+        }
+
+        write_rbsp_trailing_bits(writer)?;
+        Ok(())
+    }
+
+    pub fn to_rbsp(&self) -> Vec<u8> {
+        let mut rbsp = Vec::new();
+        self.write(&mut BitWriter::endian(&mut rbsp, BigEndian))
+            .expect("Writing to Vec failed");
+        rbsp
+    }
 }
 
 #[derive(Clone, Debug, Diff, PartialEq)]
 pub struct PpsMoreData {
     pub transform_8x8_mode_flag: bool,
-    // pic_scaling_matrix_present_flag,
+    pub pic_scaling_matrix: Option<ScalingMatrix>,
     pub second_chroma_qp_index_offset: i8,
 }
 
 impl PpsMoreData {
-    pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
+    /// `chroma_format_idc` comes from the SPS, None if it is not known
+    pub fn read(
+        reader: &mut impl BitRead,
+        chroma_format_idc: Option<u8>,
+    ) -> Result<Self, ParseError> {
         let transform_8x8_mode_flag = reader.read_bit()?;
-        read_optional_unimplemented(reader)?; //pic_scaling_matrix_present_flag
+        let pic_scaling_matrix = read_optional(reader, |r| {
+            let num_8x8_lists = match (transform_8x8_mode_flag, chroma_format_idc) {
+                (false, _) => 0,
+                (true, Some(3)) => 6,
+                (true, Some(_)) => 2,
+                (true, None) => return Err(ParseError::MissingParameterSet),
+            };
+            ScalingMatrix::read(r, num_8x8_lists)
+        })?;
         let second_chroma_qp_index_offset = read_se(reader)?;
         Ok(Self {
             transform_8x8_mode_flag,
+            pic_scaling_matrix,
             second_chroma_qp_index_offset,
         })
     }
+
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        writer.write_bit(self.transform_8x8_mode_flag)?;
+        write_optional(writer, &self.pic_scaling_matrix, |w, m| m.write(w))?;
+        write_se(writer, self.second_chroma_qp_index_offset.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitstream_io::BitReader;
+
+    fn reencode(pps: &Pps) -> Pps {
+        let rbsp = pps.to_rbsp();
+        Pps::read(
+            &mut BitReader::endian(rbsp.as_slice(), BigEndian),
+            &ParameterSets::new(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pps_reencode() {
+        let rbsp: &[u8] = &[0xce, 0x38, 0x80];
+        let pps = Pps::read(&mut BitReader::endian(rbsp, BigEndian), &ParameterSets::new()).unwrap();
+        assert_eq!(rbsp, pps.to_rbsp().as_slice());
+    }
+
+    #[test]
+    fn test_slice_groups_reencode() {
+        let rbsp: &[u8] = &[0xce, 0x38, 0x80];
+        let mut pps = Pps::read(&mut BitReader::endian(rbsp, BigEndian), &ParameterSets::new()).unwrap();
+        pps.pic_init_qp_minus26 = -3;
+        pps.chroma_qp_index_offset = 2;
+
+        let change = SliceGroupChange {
+            slice_group_change_direction_flag: true,
+            slice_group_change_rate_minus1: 9,
+        };
+        let map_types = vec![
+            SliceGroupMapType::Interleaved { run_length_minus1: vec![3, 0, 7] },
+            SliceGroupMapType::Dispersed,
+            SliceGroupMapType::Foreground { rectangles: vec![(0, 12), (5, 30)] },
+            SliceGroupMapType::BoxOut(change.clone()),
+            SliceGroupMapType::RasterScan(change.clone()),
+            SliceGroupMapType::Wipe(change),
+            SliceGroupMapType::Explicit { slice_group_id: vec![0, 1, 2, 2, 1, 0, 0] },
+        ];
+        for slice_group_map_type in map_types {
+            pps.slice_groups = Some(SliceGroups {
+                num_slice_groups_minus1: 2,
+                slice_group_map_type,
+            });
+            assert_eq!(pps, reencode(&pps));
+        }
+    }
+
+    #[test]
+    fn test_invalid_slice_groups() {
+        let map_types = vec![
+            SliceGroupMapType::Interleaved { run_length_minus1: vec![3, 0] },
+            SliceGroupMapType::Foreground { rectangles: vec![(0, 12), (5, 30), (1, 2)] },
+            SliceGroupMapType::Explicit { slice_group_id: vec![] },
+            SliceGroupMapType::Explicit { slice_group_id: vec![0, 3] },
+        ];
+        for slice_group_map_type in map_types {
+            let slice_groups = SliceGroups { num_slice_groups_minus1: 2, slice_group_map_type };
+            let mut rbsp = Vec::new();
+            let err = slice_groups.write(&mut BitWriter::endian(&mut rbsp, BigEndian)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_slice_group_change_cycle_bits() {
+        let slice_groups = |slice_group_change_rate_minus1| SliceGroups {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: SliceGroupMapType::BoxOut(SliceGroupChange {
+                slice_group_change_direction_flag: false,
+                slice_group_change_rate_minus1,
+            }),
+        };
+        // 99 / 1 + 1 = 100 -> 7 bits
+        assert_eq!(slice_groups(0).slice_group_change_cycle_bits(99), Some(7));
+        // 99 / 3 + 1 = 34 -> 6 bits
+        assert_eq!(slice_groups(2).slice_group_change_cycle_bits(99), Some(6));
+        // 96 / 32 + 1 = 4 -> 2 bits
+        assert_eq!(slice_groups(31).slice_group_change_cycle_bits(96), Some(2));
+        // 97 / 32 + 1 > 4 -> 3 bits
+        assert_eq!(slice_groups(31).slice_group_change_cycle_bits(97), Some(3));
+    }
 }
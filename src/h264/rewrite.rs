@@ -0,0 +1,417 @@
+use crate::h264::{PicOrderCntType, SliceHeader, Sps};
+use std::borrow::Cow;
+
+/// Largest log2_max_pic_order_cnt_lsb_minus4 allowed by 7.4.2.1.1
+const MAX_LOG2_MAX_PIC_ORDER_CNT_LSB_MINUS4: u8 = 12;
+
+/// Rewrites parameter sets and slice headers so that NAL units taken from different videos
+/// and loop positions form one consistent output stream.
+///
+/// All SPS sent out get the same log2_max_frame_num_minus4 and, for pic_order_cnt_type 0,
+/// the same log2_max_pic_order_cnt_lsb_minus4, taken from the first SPS written.
+/// If picture order counts are rewritten, log2_max_pic_order_cnt_lsb_minus4 is the maximum of 12
+/// instead, see `rewrite_pic_order_cnt`.
+/// Slices are then written with those field widths, whichever video they come from.
+pub struct StreamRewriter {
+    rewrite_frame_nums: bool,
+    rewrite_pic_order_cnts: bool,
+    log2_max_frame_num_minus4: Option<u8>,
+    log2_max_pic_order_cnt_lsb_minus4: Option<u8>,
+
+    frame_num: u32,
+    last_picture_was_reference: bool,
+    pic_order_cnt: u32,
+}
+
+impl StreamRewriter {
+    pub fn new(rewrite_frame_nums: bool, rewrite_pic_order_cnts: bool) -> Self {
+        Self {
+            rewrite_frame_nums,
+            rewrite_pic_order_cnts,
+            log2_max_frame_num_minus4: None,
+            log2_max_pic_order_cnt_lsb_minus4: None,
+            frame_num: 0,
+            last_picture_was_reference: false,
+            pic_order_cnt: 0,
+        }
+    }
+
+    /// Adapts a SPS that is about to be sent out to the output stream.
+    ///
+    /// Returns whether the SPS was changed.
+    pub fn rewrite_sps(&mut self, sps: &mut Sps) -> bool {
+        self.log2_max_frame_num_minus4
+            .get_or_insert(sps.log2_max_frame_num_minus4);
+        if let PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) = sps.pic_order_cnt_type {
+            self.log2_max_pic_order_cnt_lsb_minus4.get_or_insert(if self.rewrite_pic_order_cnts {
+                MAX_LOG2_MAX_PIC_ORDER_CNT_LSB_MINUS4
+            } else {
+                log2_max_pic_order_cnt_lsb_minus4
+            });
+        }
+        self.adapt_sps(sps)
+    }
+
+    /// Sets the shared field widths in `sps`. Returns whether anything changed.
+    fn adapt_sps(&self, sps: &mut Sps) -> bool {
+        let mut changed = false;
+        if let Some(log2) = self.log2_max_frame_num_minus4 {
+            if sps.log2_max_frame_num_minus4 != log2 {
+                sps.log2_max_frame_num_minus4 = log2;
+                changed = true;
+            }
+        }
+        if let (Some(log2), PicOrderCntType::Type0(sps_log2)) = (
+            self.log2_max_pic_order_cnt_lsb_minus4,
+            &mut sps.pic_order_cnt_type,
+        ) {
+            if *sps_log2 != log2 {
+                *sps_log2 = log2;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// The SPS slices referring to `sps` have to be written with
+    pub fn output_sps<'a>(&self, sps: &'a Sps) -> Cow<'a, Sps> {
+        let mut output = sps.clone();
+        if self.adapt_sps(&mut output) {
+            Cow::Owned(output)
+        } else {
+            Cow::Borrowed(sps)
+        }
+    }
+
+    /// Whether slices referring to `sps` need to be rewritten even if nothing else changes
+    pub fn needs_rewrite(&self, sps: &Sps) -> bool {
+        self.rewrite_frame_nums
+            || self.rewrite_pic_order_cnts
+            || matches!(self.output_sps(sps), Cow::Owned(_))
+    }
+
+    /// Rewrites the fields of a slice header which have to be continuous in the output stream.
+    ///
+    /// `sps` is the SPS the slice was parsed with.
+    pub fn rewrite_slice_header(&mut self, header: &mut SliceHeader, sps: &Sps) {
+        let output_sps = self.output_sps(sps);
+        let max_frame_num = output_sps.max_frame_num();
+        let max_pic_order_cnt_lsb = output_sps.max_pic_order_cnt_lsb();
+        drop(output_sps);
+
+        self.rewrite_frame_num(header, max_frame_num);
+        self.rewrite_pic_order_cnt(header, sps, max_pic_order_cnt_lsb);
+    }
+
+    fn rewrite_frame_num(&mut self, header: &mut SliceHeader, max_frame_num: u32) {
+        if !self.rewrite_frame_nums {
+            header.frame_num %= max_frame_num;
+            return;
+        }
+
+        // All slices of a picture have the same frame_num.
+        // Only count up at the first slice of a picture.
+        if header.first_mb_in_slice == 0 {
+            if header.idr_pic_id.is_some() {
+                self.frame_num = 0;
+            } else if self.last_picture_was_reference {
+                // frame_num only counts reference pictures.
+                // A picture following a non reference picture gets the same frame_num.
+                self.frame_num = (self.frame_num + 1) % max_frame_num;
+            }
+            self.frame_num %= max_frame_num;
+            self.last_picture_was_reference = header.dec_ref_pic_marking.is_some();
+        }
+        header.frame_num = self.frame_num;
+    }
+
+    /// Makes the picture order count increase with every picture in decoding order.
+    ///
+    /// Pictures are then displayed in the order they are sent out,
+    /// so B frames of the source end up displayed in decoding order as well.
+    ///
+    /// Decoders derive the picture order count relative to the last reference picture, which
+    /// only works while the distance is below MaxPicOrderCntLsb / 2. The widest
+    /// pic_order_cnt_lsb keeps that up for runs of 16383 non reference pictures.
+    fn rewrite_pic_order_cnt(
+        &mut self,
+        header: &mut SliceHeader,
+        sps: &Sps,
+        max_pic_order_cnt_lsb: Option<u32>,
+    ) {
+        match sps.pic_order_cnt_type {
+            PicOrderCntType::Type0(_) => {
+                // Only None if the first SPS sent out did not have pic_order_cnt_type 0
+                let max_pic_order_cnt_lsb = max_pic_order_cnt_lsb.unwrap_or(u32::MAX);
+                if !self.rewrite_pic_order_cnts {
+                    header.pic_order_cnt_lsb %= max_pic_order_cnt_lsb;
+                    return;
+                }
+                if header.first_mb_in_slice == 0 {
+                    if header.idr_pic_id.is_some() {
+                        self.pic_order_cnt = 0;
+                    } else {
+                        // Steps of 2 leave room for the bottom field, like most encoders do
+                        self.pic_order_cnt = (self.pic_order_cnt + 2) % max_pic_order_cnt_lsb;
+                    }
+                }
+                header.pic_order_cnt_lsb = self.pic_order_cnt;
+                header.delta_pic_order_cnt_bottom = 0;
+            }
+            PicOrderCntType::Type1 { .. } => {
+                // The expected picture order count is derived from frame_num,
+                // which is continuous already if frame_nums are rewritten.
+                if self.rewrite_pic_order_cnts {
+                    header.delta_pic_order_cnt = [0, 0];
+                }
+            }
+            PicOrderCntType::Type2 => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::test_sps;
+    use crate::h264::{
+        DecRefPicMarking, NALUnitType, ParameterSets, PicOrderCntType, Pps, PpsMoreData,
+    };
+    use bitstream_io::{BigEndian, BitWrite, BitWriter};
+
+    fn test_parameter_sets(sps: Sps) -> ParameterSets {
+        let pps = Pps {
+            pic_parameter_set_id: 0,
+            seq_parameter_set_id: 0,
+            entropy_coding_mode_flag: false,
+            bottom_field_pic_order_in_frame_present_flag: false,
+            slice_groups: None,
+            num_ref_idx_l0_default_active_minus1: 0,
+            num_ref_idx_l1_default_active_minus1: 0,
+            weighted_pred_flag: false,
+            weighted_bipred_idc: 0,
+            pic_init_qp_minus26: 0,
+            pic_init_qs_minus26: 0,
+            chroma_qp_index_offset: 0,
+            deblocking_filter_control_present_flag: false,
+            constrained_intra_pred_flag: false,
+            redundant_pic_cnt_present_flag: false,
+            pps_more_data: Some(PpsMoreData {
+                transform_8x8_mode_flag: false,
+                pic_scaling_matrix: None,
+                second_chroma_qp_index_offset: 0,
+            }),
+        };
+        let mut parameter_sets = ParameterSets::new();
+        parameter_sets.insert_sps(sps);
+        parameter_sets.insert_pps(pps);
+        parameter_sets
+    }
+
+    /// Builds a P slice with a frame_num of the width given in the SPS
+    fn p_slice(parameter_sets: &ParameterSets, frame_num: u32, reference: bool) -> SliceHeader {
+        p_slice_with_poc(parameter_sets, frame_num, 0, reference)
+    }
+
+    fn p_slice_with_poc(
+        parameter_sets: &ParameterSets,
+        frame_num: u32,
+        pic_order_cnt_lsb: u32,
+        reference: bool,
+    ) -> SliceHeader {
+        let (sps, _) = parameter_sets.get(0).unwrap();
+        let mut rbsp = Vec::new();
+        let mut writer = BitWriter::endian(&mut rbsp, BigEndian);
+        // first_mb_in_slice = 0, slice_type = 0, pic_parameter_set_id = 0
+        writer.write(3, 0b111u8).unwrap();
+        writer.write(sps.frame_num_bits(), frame_num).unwrap();
+        if let PicOrderCntType::Type0(log2) = sps.pic_order_cnt_type {
+            writer.write(log2 as u32 + 4, pic_order_cnt_lsb).unwrap();
+        }
+        writer.write_bit(false).unwrap(); // num_ref_idx_active_override_flag
+        writer.write_bit(false).unwrap(); // ref_pic_list_modification_flag_l0
+        if reference {
+            writer.write_bit(false).unwrap(); // adaptive_ref_pic_marking_mode_flag
+        }
+        writer.write_bit(true).unwrap(); // slice_qp_delta = 0
+        writer.write(8, 0xffu8).unwrap();
+        writer.write_bit(true).unwrap();
+        writer.byte_align().unwrap();
+        let nal_ref_idc = if reference { 2 } else { 0 };
+        SliceHeader::from_bytes(&rbsp, NALUnitType::CodedSliceNonIdr, nal_ref_idc, parameter_sets)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_frame_num_wraps_at_max_frame_num() {
+        let mut sps = test_sps();
+        sps.log2_max_frame_num_minus4 = 1;
+        let parameter_sets = test_parameter_sets(sps.clone());
+
+        let mut rewriter = StreamRewriter::new(true, false);
+        assert!(!rewriter.rewrite_sps(&mut sps.clone()));
+
+        let frame_nums: Vec<u32> = (0..40)
+            .map(|_| {
+                let mut header = p_slice(&parameter_sets, 7, true);
+                rewriter.rewrite_slice_header(&mut header, &sps);
+                header.frame_num
+            })
+            .collect();
+        let expected: Vec<u32> = (0..40).map(|i| i % 32).collect();
+        assert_eq!(frame_nums, expected);
+    }
+
+    #[test]
+    fn test_non_reference_pictures_share_frame_num() {
+        let sps = test_sps();
+        let parameter_sets = test_parameter_sets(sps.clone());
+        let mut rewriter = StreamRewriter::new(true, false);
+
+        let mut frame_nums = Vec::new();
+        for reference in [true, false, false, true, true].iter() {
+            let mut header = p_slice(&parameter_sets, 0, *reference);
+            rewriter.rewrite_slice_header(&mut header, &sps);
+            frame_nums.push(header.frame_num);
+        }
+        assert_eq!(frame_nums, vec![0, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_idr_resets_frame_num() {
+        let sps = test_sps();
+        let parameter_sets = test_parameter_sets(sps.clone());
+        let mut rewriter = StreamRewriter::new(true, false);
+
+        for _ in 0..5 {
+            let mut header = p_slice(&parameter_sets, 0, true);
+            rewriter.rewrite_slice_header(&mut header, &sps);
+        }
+        let mut header = p_slice(&parameter_sets, 0, true);
+        header.idr_pic_id = Some(0);
+        header.dec_ref_pic_marking = Some(DecRefPicMarking::Idr {
+            no_output_of_prior_pics_flag: false,
+            long_term_reference_flag: false,
+        });
+        rewriter.rewrite_slice_header(&mut header, &sps);
+        assert_eq!(header.frame_num, 0);
+    }
+
+    #[test]
+    fn test_shared_frame_num_width() {
+        let first_sps = test_sps();
+        let mut second_sps = test_sps();
+        second_sps.log2_max_frame_num_minus4 = 4;
+        let parameter_sets = test_parameter_sets(second_sps.clone());
+
+        let mut rewriter = StreamRewriter::new(false, false);
+        assert!(!rewriter.rewrite_sps(&mut first_sps.clone()));
+
+        let mut rewritten_sps = second_sps.clone();
+        assert!(rewriter.rewrite_sps(&mut rewritten_sps));
+        assert_eq!(rewritten_sps.log2_max_frame_num_minus4, first_sps.log2_max_frame_num_minus4);
+        assert!(rewriter.needs_rewrite(&second_sps));
+
+        // Slices of the second video are written with the frame_num width of the first
+        let mut header = p_slice(&parameter_sets, 200, true);
+        rewriter.rewrite_slice_header(&mut header, &second_sps);
+        assert_eq!(header.frame_num, 200 % 16);
+
+        let (_, pps) = parameter_sets.get(0).unwrap();
+        let rbsp = header
+            .to_bytes_with(&rewriter.output_sps(&second_sps), pps)
+            .unwrap();
+        let reparsed = SliceHeader::from_bytes(
+            &rbsp,
+            NALUnitType::CodedSliceNonIdr,
+            2,
+            &test_parameter_sets(first_sps),
+        )
+        .unwrap();
+        assert_eq!(reparsed.frame_num, 200 % 16);
+        assert_eq!(reparsed.slice_qp_delta, 0);
+    }
+
+    #[test]
+    fn test_pic_order_cnt_monotonic() {
+        let mut sps = test_sps();
+        sps.pic_order_cnt_type = PicOrderCntType::Type0(0);
+        let parameter_sets = test_parameter_sets(sps.clone());
+        let mut rewriter = StreamRewriter::new(true, true);
+        let mut output_sps = sps.clone();
+        assert!(rewriter.rewrite_sps(&mut output_sps));
+        assert_eq!(output_sps.pic_order_cnt_type, PicOrderCntType::Type0(12));
+        let max_pic_order_cnt_lsb = i64::from(output_sps.max_pic_order_cnt_lsb().unwrap());
+
+        // Source POCs of two spliced videos
+        let pic_order_cnts: Vec<u32> = [4, 0, 2, 10, 6, 8, 0, 14, 12]
+            .iter()
+            .map(|lsb| {
+                let mut header = p_slice_with_poc(&parameter_sets, 0, *lsb, true);
+                rewriter.rewrite_slice_header(&mut header, &sps);
+                header.pic_order_cnt_lsb
+            })
+            .collect();
+        assert_eq!(pic_order_cnts, vec![2, 4, 6, 8, 10, 12, 14, 16, 18]);
+
+        // A long run of non reference pictures, decoded as in 8.2.1.1
+        let (mut prev_msb, mut prev_lsb, mut prev_poc) = (0, 18, 18);
+        for i in 0..40000 {
+            let reference = i % 10000 == 9999;
+            let mut header = p_slice_with_poc(&parameter_sets, 0, 0, reference);
+            rewriter.rewrite_slice_header(&mut header, &sps);
+            let lsb = i64::from(header.pic_order_cnt_lsb);
+            let msb = if lsb < prev_lsb && prev_lsb - lsb >= max_pic_order_cnt_lsb / 2 {
+                prev_msb + max_pic_order_cnt_lsb
+            } else if lsb > prev_lsb && lsb - prev_lsb > max_pic_order_cnt_lsb / 2 {
+                prev_msb - max_pic_order_cnt_lsb
+            } else {
+                prev_msb
+            };
+            assert!(msb + lsb > prev_poc);
+            prev_poc = msb + lsb;
+            if reference {
+                prev_msb = msb;
+                prev_lsb = lsb;
+            }
+        }
+
+        let mut header = p_slice_with_poc(&parameter_sets, 0, 6, true);
+        header.idr_pic_id = Some(0);
+        rewriter.rewrite_slice_header(&mut header, &sps);
+        assert_eq!(header.pic_order_cnt_lsb, 0);
+    }
+
+    #[test]
+    fn test_shared_pic_order_cnt_lsb_width() {
+        let mut first_sps = test_sps();
+        first_sps.pic_order_cnt_type = PicOrderCntType::Type0(0);
+        let mut second_sps = test_sps();
+        second_sps.pic_order_cnt_type = PicOrderCntType::Type0(4);
+        let parameter_sets = test_parameter_sets(second_sps.clone());
+
+        let mut rewriter = StreamRewriter::new(false, false);
+        assert!(!rewriter.rewrite_sps(&mut first_sps.clone()));
+        let mut rewritten_sps = second_sps.clone();
+        assert!(rewriter.rewrite_sps(&mut rewritten_sps));
+        assert_eq!(rewritten_sps.pic_order_cnt_type, PicOrderCntType::Type0(0));
+
+        let mut header = p_slice_with_poc(&parameter_sets, 0, 100, true);
+        rewriter.rewrite_slice_header(&mut header, &second_sps);
+        assert_eq!(header.pic_order_cnt_lsb, 100 % 16);
+
+        let (_, pps) = parameter_sets.get(0).unwrap();
+        let rbsp = header
+            .to_bytes_with(&rewriter.output_sps(&second_sps), pps)
+            .unwrap();
+        let reparsed = SliceHeader::from_bytes(
+            &rbsp,
+            NALUnitType::CodedSliceNonIdr,
+            2,
+            &test_parameter_sets(first_sps),
+        )
+        .unwrap();
+        assert_eq!(reparsed.pic_order_cnt_lsb, 100 % 16);
+        assert_eq!(reparsed.slice_qp_delta, 0);
+    }
+}
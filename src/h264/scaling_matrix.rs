@@ -0,0 +1,145 @@
+use crate::h264::{read_se, write_se, ParseError};
+use bitstream_io::{BitRead, BitWrite};
+use visit_diff::Diff;
+
+/// One scaling_list() as defined in 7.3.2.1.1.1
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub enum ScalingList {
+    /// scaling_list_present_flag is 0, fall-back rule A or B applies
+    NotPresent,
+    /// useDefaultScalingMatrixFlag is set
+    UseDefault,
+    /// Weights in zig-zag scan order, 16 for 4x4 and 64 for 8x8 lists
+    Explicit(Vec<u8>),
+}
+
+impl ScalingList {
+    fn read(reader: &mut impl BitRead, size: usize) -> Result<Self, ParseError> {
+        if !reader.read_bit()? {
+            return Ok(ScalingList::NotPresent);
+        }
+
+        let mut values = Vec::with_capacity(size);
+        let mut last_scale: i32 = 8;
+        let mut next_scale: i32 = 8;
+        for j in 0..size {
+            if next_scale != 0 {
+                let delta_scale: i32 = read_se(reader)?;
+                if !(-128..=127).contains(&delta_scale) {
+                    return Err(ParseError::InvalidData);
+                }
+                next_scale = (last_scale + delta_scale + 256) % 256;
+                if j == 0 && next_scale == 0 {
+                    return Ok(ScalingList::UseDefault);
+                }
+            }
+            if next_scale != 0 {
+                last_scale = next_scale;
+            }
+            values.push(last_scale as u8);
+        }
+        Ok(ScalingList::Explicit(values))
+    }
+
+    /// Writes the list, ending it early if all remaining values repeat the last one
+    fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        let values = match self {
+            ScalingList::NotPresent => return writer.write_bit(false),
+            ScalingList::UseDefault => {
+                writer.write_bit(true)?;
+                // nextScale becomes 0 at the first position
+                return write_se(writer, -8);
+            }
+            ScalingList::Explicit(values) => values,
+        };
+
+        writer.write_bit(true)?;
+        let mut last_scale: u8 = 8;
+        for (j, value) in values.iter().enumerate() {
+            if j > 0 && values[j..].iter().all(|v| *v == last_scale) {
+                write_se(writer, wrap_delta(0, last_scale))?;
+                return Ok(());
+            }
+            write_se(writer, wrap_delta(*value, last_scale))?;
+            last_scale = *value;
+        }
+        Ok(())
+    }
+}
+
+/// Smallest delta_scale which leads from `last` to `next` modulo 256
+fn wrap_delta(next: u8, last: u8) -> i32 {
+    next.wrapping_sub(last) as i8 as i32
+}
+
+/// The scaling lists of a SPS or PPS.
+///
+/// There are always 6 4x4 lists. The number of 8x8 lists depends on chroma_format_idc
+/// and, in a PPS, on transform_8x8_mode_flag.
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub struct ScalingMatrix {
+    pub scaling_lists_4x4: Vec<ScalingList>,
+    pub scaling_lists_8x8: Vec<ScalingList>,
+}
+
+impl ScalingMatrix {
+    pub fn read(reader: &mut impl BitRead, num_8x8_lists: usize) -> Result<Self, ParseError> {
+        let scaling_lists_4x4 = (0..6)
+            .map(|_| ScalingList::read(reader, 16))
+            .collect::<Result<_, _>>()?;
+        let scaling_lists_8x8 = (0..num_8x8_lists)
+            .map(|_| ScalingList::read(reader, 64))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            scaling_lists_4x4,
+            scaling_lists_8x8,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        for list in self.scaling_lists_4x4.iter() {
+            list.write(writer)?;
+        }
+        for list in self.scaling_lists_8x8.iter() {
+            list.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitstream_io::{BigEndian, BitReader, BitWriter};
+
+    #[test]
+    fn test_scaling_matrix_reencode() {
+        let mut ramp: Vec<u8> = (0..64).map(|i| 4 + i * 3).collect();
+        // Trailing repeated values are written with an early end of the list
+        for v in ramp[40..].iter_mut() {
+            *v = 200;
+        }
+        let matrix = ScalingMatrix {
+            scaling_lists_4x4: vec![
+                ScalingList::NotPresent,
+                ScalingList::UseDefault,
+                ScalingList::Explicit(vec![16; 16]),
+                ScalingList::Explicit((0..16).map(|i| 255 - i * 16).collect()),
+                ScalingList::Explicit((1..17).collect()),
+                ScalingList::NotPresent,
+            ],
+            scaling_lists_8x8: vec![ScalingList::Explicit(ramp), ScalingList::UseDefault],
+        };
+
+        let mut data = Vec::new();
+        let mut writer = BitWriter::endian(&mut data, BigEndian);
+        matrix.write(&mut writer).unwrap();
+        writer.write_bit(true).unwrap();
+        writer.byte_align().unwrap();
+
+        let mut reader = BitReader::endian(data.as_slice(), BigEndian);
+        let reread = ScalingMatrix::read(&mut reader, 2).unwrap();
+        assert_eq!(matrix, reread);
+        assert!(reader.read_bit().unwrap());
+    }
+}
@@ -0,0 +1,483 @@
+use crate::h264::{
+    read_rbsp_trailing_bits, read_ue, write_rbsp_trailing_bits, write_ue, NALUnitType, NalUnit,
+    ParseError, Sps,
+};
+use bitstream_io::{BigEndian, BitRead, BitReader, BitWrite, BitWriter};
+use std::fmt;
+use std::io;
+
+/// Recovery point SEI message as defined in D.1.8
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecoveryPoint {
+    pub recovery_frame_cnt: u32,
+    pub exact_match_flag: bool,
+    pub broken_link_flag: bool,
+    pub changing_slice_group_idc: u8,
+}
+
+impl RecoveryPoint {
+    pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
+        Ok(Self {
+            recovery_frame_cnt: read_ue(reader)?,
+            exact_match_flag: reader.read_bit()?,
+            broken_link_flag: reader.read_bit()?,
+            changing_slice_group_idc: reader.read(2)?,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl BitWrite) -> io::Result<()> {
+        write_ue(writer, self.recovery_frame_cnt)?;
+        writer.write_bit(self.exact_match_flag)?;
+        writer.write_bit(self.broken_link_flag)?;
+        writer.write(2, self.changing_slice_group_idc)
+    }
+}
+
+/// One clock timestamp of a picture timing SEI message
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockTimestamp {
+    pub ct_type: u8,
+    pub nuit_field_based_flag: bool,
+    pub counting_type: u8,
+    pub full_timestamp_flag: bool,
+    pub discontinuity_flag: bool,
+    pub cnt_dropped_flag: bool,
+    pub n_frames: u8,
+    // With full_timestamp_flag all of them are present,
+    // otherwise minutes need seconds and hours need minutes
+    pub seconds_value: Option<u8>,
+    pub minutes_value: Option<u8>,
+    pub hours_value: Option<u8>,
+    pub time_offset: i32,
+}
+
+impl ClockTimestamp {
+    fn read(reader: &mut impl BitRead, time_offset_length: u32) -> Result<Self, ParseError> {
+        let ct_type = reader.read(2)?;
+        let nuit_field_based_flag = reader.read_bit()?;
+        let counting_type = reader.read(5)?;
+        let full_timestamp_flag = reader.read_bit()?;
+        let discontinuity_flag = reader.read_bit()?;
+        let cnt_dropped_flag = reader.read_bit()?;
+        let n_frames = reader.read(8)?;
+
+        let (mut seconds_value, mut minutes_value, mut hours_value) = (None, None, None);
+        if full_timestamp_flag {
+            seconds_value = Some(reader.read(6)?);
+            minutes_value = Some(reader.read(6)?);
+            hours_value = Some(reader.read(5)?);
+        } else if reader.read_bit()? {
+            seconds_value = Some(reader.read(6)?);
+            if reader.read_bit()? {
+                minutes_value = Some(reader.read(6)?);
+                if reader.read_bit()? {
+                    hours_value = Some(reader.read(5)?);
+                }
+            }
+        }
+
+        let time_offset = if time_offset_length > 0 {
+            reader.read_signed(time_offset_length)?
+        } else {
+            0
+        };
+
+        Ok(Self {
+            ct_type,
+            nuit_field_based_flag,
+            counting_type,
+            full_timestamp_flag,
+            discontinuity_flag,
+            cnt_dropped_flag,
+            n_frames,
+            seconds_value,
+            minutes_value,
+            hours_value,
+            time_offset,
+        })
+    }
+
+    fn write(&self, writer: &mut impl BitWrite, time_offset_length: u32) -> io::Result<()> {
+        writer.write(2, self.ct_type)?;
+        writer.write_bit(self.nuit_field_based_flag)?;
+        writer.write(5, self.counting_type)?;
+        writer.write_bit(self.full_timestamp_flag)?;
+        writer.write_bit(self.discontinuity_flag)?;
+        writer.write_bit(self.cnt_dropped_flag)?;
+        writer.write(8, self.n_frames)?;
+
+        if self.full_timestamp_flag {
+            writer.write(6, self.seconds_value.unwrap_or(0))?;
+            writer.write(6, self.minutes_value.unwrap_or(0))?;
+            writer.write(5, self.hours_value.unwrap_or(0))?;
+        } else {
+            writer.write_bit(self.seconds_value.is_some())?;
+            if let Some(seconds_value) = self.seconds_value {
+                writer.write(6, seconds_value)?;
+                writer.write_bit(self.minutes_value.is_some())?;
+                if let Some(minutes_value) = self.minutes_value {
+                    writer.write(6, minutes_value)?;
+                    writer.write_bit(self.hours_value.is_some())?;
+                    if let Some(hours_value) = self.hours_value {
+                        writer.write(5, hours_value)?;
+                    }
+                }
+            }
+        }
+
+        if time_offset_length > 0 {
+            writer.write_signed(time_offset_length, self.time_offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Picture timing SEI message as defined in D.1.3.
+///
+/// Which fields are present and how long they are is given by the VUI of the active SPS.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PicTiming {
+    pub cpb_removal_delay: u32, // Only present if the VUI has HRD parameters
+    pub dpb_output_delay: u32,
+    pub pic_struct: u8, // Only present if pic_struct_present_flag is set in the VUI
+    pub clock_timestamps: Vec<Option<ClockTimestamp>>, // NumClockTS entries
+}
+
+impl PicTiming {
+    /// NumClockTS from Table D-1
+    pub fn num_clock_ts(pic_struct: u8) -> Result<usize, ParseError> {
+        match pic_struct {
+            0..=2 => Ok(1),
+            3 | 4 | 7 => Ok(2),
+            5 | 6 | 8 => Ok(3),
+            _ => Err(ParseError::InvalidData),
+        }
+    }
+
+    pub fn read(reader: &mut impl BitRead, sps: &Sps) -> Result<Self, ParseError> {
+        let vui_parameters = sps
+            .vui_parameters
+            .as_ref()
+            .ok_or(ParseError::InvalidData)?;
+
+        let mut cpb_removal_delay = 0;
+        let mut dpb_output_delay = 0;
+        if let Some(hrd_parameters) = vui_parameters.hrd_parameters() {
+            cpb_removal_delay =
+                reader.read(hrd_parameters.cpb_removal_delay_length_minus1 as u32 + 1)?;
+            dpb_output_delay =
+                reader.read(hrd_parameters.dpb_output_delay_length_minus1 as u32 + 1)?;
+        }
+
+        let mut pic_struct = 0;
+        let mut clock_timestamps = Vec::new();
+        if vui_parameters.pic_struct_present_flag {
+            let time_offset_length = vui_parameters
+                .hrd_parameters()
+                .map_or(24, |hrd_parameters| hrd_parameters.time_offset_length as u32);
+            pic_struct = reader.read(4)?;
+            for _ in 0..Self::num_clock_ts(pic_struct)? {
+                let clock_timestamp = if reader.read_bit()? {
+                    Some(ClockTimestamp::read(reader, time_offset_length)?)
+                } else {
+                    None
+                };
+                clock_timestamps.push(clock_timestamp);
+            }
+        }
+
+        Ok(Self {
+            cpb_removal_delay,
+            dpb_output_delay,
+            pic_struct,
+            clock_timestamps,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl BitWrite, sps: &Sps) -> io::Result<()> {
+        let vui_parameters = match sps.vui_parameters.as_ref() {
+            Some(vui_parameters) => vui_parameters,
+            None => return Ok(()),
+        };
+
+        if let Some(hrd_parameters) = vui_parameters.hrd_parameters() {
+            writer.write(
+                hrd_parameters.cpb_removal_delay_length_minus1 as u32 + 1,
+                self.cpb_removal_delay,
+            )?;
+            writer.write(
+                hrd_parameters.dpb_output_delay_length_minus1 as u32 + 1,
+                self.dpb_output_delay,
+            )?;
+        }
+
+        if vui_parameters.pic_struct_present_flag {
+            let time_offset_length = vui_parameters
+                .hrd_parameters()
+                .map_or(24, |hrd_parameters| hrd_parameters.time_offset_length as u32);
+            writer.write(4, self.pic_struct)?;
+            for clock_timestamp in self.clock_timestamps.iter() {
+                writer.write_bit(clock_timestamp.is_some())?;
+                if let Some(clock_timestamp) = clock_timestamp {
+                    clock_timestamp.write(writer, time_offset_length)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SeiPayload {
+    PicTiming(PicTiming),
+    UserDataUnregistered { uuid: [u8; 16], data: Vec<u8> },
+    RecoveryPoint(RecoveryPoint),
+    /// Payloads which are not decoded, kept as they are
+    Other { payload_type: u32, data: Vec<u8> },
+}
+
+impl SeiPayload {
+    pub const PIC_TIMING: u32 = 1;
+    pub const USER_DATA_UNREGISTERED: u32 = 5;
+    pub const RECOVERY_POINT: u32 = 6;
+
+    pub fn payload_type(&self) -> u32 {
+        match self {
+            SeiPayload::PicTiming(_) => Self::PIC_TIMING,
+            SeiPayload::UserDataUnregistered { .. } => Self::USER_DATA_UNREGISTERED,
+            SeiPayload::RecoveryPoint(_) => Self::RECOVERY_POINT,
+            SeiPayload::Other { payload_type, .. } => *payload_type,
+        }
+    }
+
+    /// Decodes a payload from its bytes.
+    ///
+    /// Picture timing can only be decoded with the active SPS and is kept as `Other` without it.
+    pub fn from_bytes(payload_type: u32, data: &[u8], sps: Option<&Sps>) -> Result<Self, ParseError> {
+        let mut reader = BitReader::endian(data, BigEndian);
+        match (payload_type, sps) {
+            (Self::PIC_TIMING, Some(sps)) => Ok(SeiPayload::PicTiming(PicTiming::read(&mut reader, sps)?)),
+            (Self::USER_DATA_UNREGISTERED, _) => {
+                if data.len() < 16 {
+                    return Err(ParseError::InvalidData);
+                }
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(&data[..16]);
+                Ok(SeiPayload::UserDataUnregistered {
+                    uuid,
+                    data: data[16..].to_vec(),
+                })
+            }
+            (Self::RECOVERY_POINT, _) => Ok(SeiPayload::RecoveryPoint(RecoveryPoint::read(&mut reader)?)),
+            _ => Ok(SeiPayload::Other {
+                payload_type,
+                data: data.to_vec(),
+            }),
+        }
+    }
+
+    /// Encodes the payload, including the alignment bits at its end
+    pub fn to_bytes(&self, sps: Option<&Sps>) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut writer = BitWriter::endian(&mut data, BigEndian);
+        match self {
+            SeiPayload::PicTiming(pic_timing) => {
+                let sps = sps.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Picture timing needs the SPS")
+                })?;
+                pic_timing.write(&mut writer, sps)?;
+            }
+            SeiPayload::UserDataUnregistered { uuid, data } => {
+                writer.write_bytes(uuid)?;
+                writer.write_bytes(data)?;
+            }
+            SeiPayload::RecoveryPoint(recovery_point) => recovery_point.write(&mut writer)?,
+            SeiPayload::Other { data, .. } => writer.write_bytes(data)?,
+        }
+        if !writer.byte_aligned() {
+            // bit_equal_to_one followed by bit_equal_to_zero until aligned
+            writer.write_bit(true)?;
+            writer.byte_align()?;
+        }
+        Ok(data)
+    }
+}
+
+impl fmt::Display for SeiPayload {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeiPayload::UserDataUnregistered { uuid, data } => {
+                write!(fmt, "UserDataUnregistered {{ uuid: ")?;
+                for b in uuid.iter() {
+                    write!(fmt, "{:02x}", b)?;
+                }
+                write!(fmt, ", data: {:?} }}", String::from_utf8_lossy(data))
+            }
+            SeiPayload::Other { payload_type, data } => {
+                write!(fmt, "Other {{ payload_type: {}, {} bytes }}", payload_type, data.len())
+            }
+            _ => write!(fmt, "{:?}", self),
+        }
+    }
+}
+
+/// Writes payloadType or payloadSize as a sequence of 0xFF bytes and a last byte
+fn write_ff_coded(writer: &mut impl BitWrite, mut value: u32) -> io::Result<()> {
+    while value >= 0xff {
+        writer.write(8, 0xffu8)?;
+        value -= 0xff;
+    }
+    writer.write(8, value)
+}
+
+fn read_ff_coded(bytes: &[u8], pos: &mut usize) -> Result<u32, ParseError> {
+    let mut value = 0u32;
+    loop {
+        let byte = *bytes.get(*pos).ok_or(ParseError::InvalidData)?;
+        *pos += 1;
+        value = value.checked_add(byte as u32).ok_or(ParseError::InvalidData)?;
+        if byte != 0xff {
+            return Ok(value);
+        }
+    }
+}
+
+/// The SEI messages of one SEI NAL unit
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sei {
+    pub messages: Vec<SeiPayload>,
+}
+
+impl Sei {
+    /// `sps` is the active SPS, needed to decode picture timing messages
+    pub fn from_bytes(rbsp: &[u8], sps: Option<&Sps>) -> Result<Self, ParseError> {
+        let mut messages = Vec::new();
+        let mut pos = 0;
+        // The last byte holds the rbsp_trailing_bits
+        while pos + 1 < rbsp.len() {
+            let payload_type = read_ff_coded(rbsp, &mut pos)?;
+            let payload_size = read_ff_coded(rbsp, &mut pos)? as usize;
+            let data = rbsp
+                .get(pos..pos + payload_size)
+                .ok_or(ParseError::InvalidData)?;
+            messages.push(SeiPayload::from_bytes(payload_type, data, sps)?);
+            pos += payload_size;
+        }
+        read_rbsp_trailing_bits(&mut BitReader::endian(&rbsp[pos..], BigEndian))?;
+        Ok(Self { messages })
+    }
+
+    pub fn to_bytes(&self, sps: Option<&Sps>) -> io::Result<Vec<u8>> {
+        let mut rbsp = Vec::new();
+        let mut writer = BitWriter::endian(&mut rbsp, BigEndian);
+        for message in self.messages.iter() {
+            let data = message.to_bytes(sps)?;
+            write_ff_coded(&mut writer, message.payload_type())?;
+            write_ff_coded(&mut writer, data.len() as u32)?;
+            writer.write_bytes(&data)?;
+        }
+        write_rbsp_trailing_bits(&mut writer)?;
+        Ok(rbsp)
+    }
+
+    pub fn to_nal_unit(&self, sps: Option<&Sps>) -> io::Result<NalUnit> {
+        Ok(NalUnit::new(0, NALUnitType::Sei, self.to_bytes(sps)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::test_sps;
+    use crate::h264::{CpbSpec, HrdParameters};
+
+    #[test]
+    fn test_parse_x264_user_data() {
+        // Start of the SEI x264 writes, cut short
+        let mut rbsp = vec![0x05, 0x1c];
+        rbsp.extend_from_slice(&[
+            0xdc, 0x45, 0xe9, 0xbd, 0xe6, 0xd9, 0x48, 0xb7, 0x96, 0x2c, 0xd8, 0x20, 0xd9, 0x23,
+            0xee, 0xef,
+        ]);
+        rbsp.extend_from_slice(b"x264 - core");
+        rbsp.extend_from_slice(&[0x00, 0x80]);
+
+        let sei = Sei::from_bytes(&rbsp, None).unwrap();
+        assert_eq!(sei.messages.len(), 1);
+        match &sei.messages[0] {
+            SeiPayload::UserDataUnregistered { uuid, data } => {
+                assert_eq!(uuid[0], 0xdc);
+                assert_eq!(data.as_slice(), b"x264 - core\0");
+            }
+            _ => panic!("Expected user data"),
+        }
+        assert_eq!(sei.to_bytes(None).unwrap(), rbsp);
+    }
+
+    #[test]
+    fn test_messages_reencode() {
+        let mut sps = test_sps();
+        let vui_parameters = sps.vui_parameters.as_mut().unwrap();
+        vui_parameters.pic_struct_present_flag = true;
+        vui_parameters.nal_hrd_parameters = Some(HrdParameters {
+            bit_rate_scale: 0,
+            cpb_size_scale: 0,
+            cpb_specs: vec![CpbSpec {
+                bit_rate_value_minus1: 0,
+                cpb_size_value_minus1: 0,
+                cbr_flag: false,
+            }],
+            initial_cpb_removal_delay_length_minus1: 23,
+            cpb_removal_delay_length_minus1: 15,
+            dpb_output_delay_length_minus1: 4,
+            time_offset_length: 0,
+        });
+
+        let sei = Sei {
+            messages: vec![
+                SeiPayload::RecoveryPoint(RecoveryPoint {
+                    recovery_frame_cnt: 3,
+                    exact_match_flag: true,
+                    broken_link_flag: false,
+                    changing_slice_group_idc: 0,
+                }),
+                SeiPayload::PicTiming(PicTiming {
+                    cpb_removal_delay: 1000,
+                    dpb_output_delay: 2,
+                    pic_struct: 3,
+                    clock_timestamps: vec![
+                        None,
+                        Some(ClockTimestamp {
+                            ct_type: 1,
+                            nuit_field_based_flag: true,
+                            counting_type: 0,
+                            full_timestamp_flag: false,
+                            discontinuity_flag: false,
+                            cnt_dropped_flag: false,
+                            n_frames: 12,
+                            seconds_value: Some(30),
+                            minutes_value: Some(2),
+                            hours_value: None,
+                            time_offset: 0,
+                        }),
+                    ],
+                }),
+                SeiPayload::Other {
+                    payload_type: 300,
+                    data: vec![7; 400],
+                },
+            ],
+        };
+
+        let rbsp = sei.to_bytes(Some(&sps)).unwrap();
+        assert_eq!(Sei::from_bytes(&rbsp, Some(&sps)).unwrap(), sei);
+
+        // Without the SPS picture timing is kept as it is
+        let undecoded = Sei::from_bytes(&rbsp, None).unwrap();
+        assert!(matches!(
+            undecoded.messages[1],
+            SeiPayload::Other { payload_type: SeiPayload::PIC_TIMING, .. }
+        ));
+        assert_eq!(undecoded.to_bytes(None).unwrap(), rbsp);
+    }
+}
@@ -1,20 +1,339 @@
-use crate::h264::write_ue;
-use crate::h264::ParseError;
-use crate::h264::read_ue;
+use crate::h264::{
+    read_se, read_ue, write_se, write_ue, NALUnitType, ParameterSets, ParseError,
+    PicOrderCntType, Pps, Sps,
+};
+use enum_primitive::*;
+use std::convert::TryInto;
 use std::io;
 use std::fmt;
-use io::{Cursor, SeekFrom};
+use io::Cursor;
 use bitstream_io::{BigEndian, BitWriter, BitWrite, BitReader, BitRead};
 
+enum_from_primitive! {
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum SliceType {
+        P  = 0,
+        B  = 1,
+        I  = 2,
+        SP = 3,
+        SI = 4,
+    }
+}
+
+impl SliceType {
+    /// slice_type values 5 to 9 are the same as 0 to 4, but signal that all slices of the
+    /// picture have the same type
+    pub fn from_slice_type(slice_type: u32) -> Option<Self> {
+        Self::from_u32(slice_type % 5)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RefPicListModification {
+    SubtractAbsDiffPicNum(u32), // abs_diff_pic_num_minus1
+    AddAbsDiffPicNum(u32),      // abs_diff_pic_num_minus1
+    LongTermPicNum(u32),
+}
+
+impl RefPicListModification {
+    fn read_list(reader: &mut impl BitRead) -> Result<Option<Vec<Self>>, ParseError> {
+        // ref_pic_list_modification_flag_lX
+        if !reader.read_bit()? {
+            return Ok(None);
+        }
+        let mut modifications = Vec::new();
+        loop {
+            let modification_of_pic_nums_idc: u32 = read_ue(reader)?;
+            modifications.push(match modification_of_pic_nums_idc {
+                0 => Self::SubtractAbsDiffPicNum(read_ue(reader)?),
+                1 => Self::AddAbsDiffPicNum(read_ue(reader)?),
+                2 => Self::LongTermPicNum(read_ue(reader)?),
+                3 => break,
+                _ => return Err(ParseError::InvalidData),
+            });
+        }
+        Ok(Some(modifications))
+    }
+
+    fn write_list(writer: &mut impl BitWrite, list: &Option<Vec<Self>>) -> io::Result<()> {
+        writer.write_bit(list.is_some())?;
+        if let Some(list) = list {
+            for modification in list {
+                match modification {
+                    Self::SubtractAbsDiffPicNum(v) => {
+                        write_ue(writer, 0)?;
+                        write_ue(writer, *v)?;
+                    }
+                    Self::AddAbsDiffPicNum(v) => {
+                        write_ue(writer, 1)?;
+                        write_ue(writer, *v)?;
+                    }
+                    Self::LongTermPicNum(v) => {
+                        write_ue(writer, 2)?;
+                        write_ue(writer, *v)?;
+                    }
+                }
+            }
+            write_ue(writer, 3)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PredWeight {
+    pub luma: Option<(i32, i32)>,        // luma_weight, luma_offset
+    pub chroma: Option<[(i32, i32); 2]>, // chroma_weight, chroma_offset for Cb and Cr
+}
 
-#[derive(Clone, Debug)]
+impl PredWeight {
+    fn read(reader: &mut impl BitRead, chroma: bool) -> Result<Self, ParseError> {
+        let luma = if reader.read_bit()? {
+            Some((read_se(reader)?, read_se(reader)?))
+        } else {
+            None
+        };
+        let chroma = if chroma && reader.read_bit()? {
+            Some([
+                (read_se(reader)?, read_se(reader)?),
+                (read_se(reader)?, read_se(reader)?),
+            ])
+        } else {
+            None
+        };
+        Ok(Self { luma, chroma })
+    }
+
+    fn write(&self, writer: &mut impl BitWrite, chroma: bool) -> io::Result<()> {
+        writer.write_bit(self.luma.is_some())?;
+        if let Some((weight, offset)) = self.luma {
+            write_se(writer, weight)?;
+            write_se(writer, offset)?;
+        }
+        if chroma {
+            writer.write_bit(self.chroma.is_some())?;
+            if let Some(chroma) = self.chroma {
+                for (weight, offset) in chroma.iter() {
+                    write_se(writer, *weight)?;
+                    write_se(writer, *offset)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PredWeightTable {
+    pub luma_log2_weight_denom: u32,
+    pub chroma_log2_weight_denom: Option<u32>, // Only present if ChromaArrayType != 0
+    pub l0: Vec<PredWeight>,
+    pub l1: Vec<PredWeight>,
+}
+
+impl PredWeightTable {
+    fn read(
+        reader: &mut impl BitRead,
+        chroma_array_type: u8,
+        num_l0: u32,
+        num_l1: Option<u32>,
+    ) -> Result<Self, ParseError> {
+        let luma_log2_weight_denom = read_ue(reader)?;
+        let chroma_log2_weight_denom = if chroma_array_type != 0 {
+            Some(read_ue(reader)?)
+        } else {
+            None
+        };
+        let chroma = chroma_log2_weight_denom.is_some();
+        let l0 = (0..num_l0)
+            .map(|_| PredWeight::read(reader, chroma))
+            .collect::<Result<_, _>>()?;
+        let l1 = (0..num_l1.unwrap_or(0))
+            .map(|_| PredWeight::read(reader, chroma))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            luma_log2_weight_denom,
+            chroma_log2_weight_denom,
+            l0,
+            l1,
+        })
+    }
+
+    fn write(&self, writer: &mut impl BitWrite) -> io::Result<()> {
+        write_ue(writer, self.luma_log2_weight_denom)?;
+        if let Some(denom) = self.chroma_log2_weight_denom {
+            write_ue(writer, denom)?;
+        }
+        let chroma = self.chroma_log2_weight_denom.is_some();
+        for weight in self.l0.iter().chain(self.l1.iter()) {
+            weight.write(writer, chroma)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MemoryManagementControlOperation {
+    MarkShortTermUnused {
+        difference_of_pic_nums_minus1: u32,
+    },
+    MarkLongTermUnused {
+        long_term_pic_num: u32,
+    },
+    ShortTermToLongTerm {
+        difference_of_pic_nums_minus1: u32,
+        long_term_frame_idx: u32,
+    },
+    MaxLongTermFrameIdx {
+        max_long_term_frame_idx_plus1: u32,
+    },
+    MarkAllUnused,
+    CurrentToLongTerm {
+        long_term_frame_idx: u32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecRefPicMarking {
+    Idr {
+        no_output_of_prior_pics_flag: bool,
+        long_term_reference_flag: bool,
+    },
+    SlidingWindow,
+    Adaptive(Vec<MemoryManagementControlOperation>),
+}
+
+impl DecRefPicMarking {
+    fn read(reader: &mut impl BitRead, idr_pic: bool) -> Result<Self, ParseError> {
+        use MemoryManagementControlOperation::*;
+
+        if idr_pic {
+            return Ok(Self::Idr {
+                no_output_of_prior_pics_flag: reader.read_bit()?,
+                long_term_reference_flag: reader.read_bit()?,
+            });
+        }
+
+        // adaptive_ref_pic_marking_mode_flag
+        if !reader.read_bit()? {
+            return Ok(Self::SlidingWindow);
+        }
+
+        let mut operations = Vec::new();
+        loop {
+            let memory_management_control_operation: u32 = read_ue(reader)?;
+            operations.push(match memory_management_control_operation {
+                0 => break,
+                1 => MarkShortTermUnused {
+                    difference_of_pic_nums_minus1: read_ue(reader)?,
+                },
+                2 => MarkLongTermUnused {
+                    long_term_pic_num: read_ue(reader)?,
+                },
+                3 => ShortTermToLongTerm {
+                    difference_of_pic_nums_minus1: read_ue(reader)?,
+                    long_term_frame_idx: read_ue(reader)?,
+                },
+                4 => MaxLongTermFrameIdx {
+                    max_long_term_frame_idx_plus1: read_ue(reader)?,
+                },
+                5 => MarkAllUnused,
+                6 => CurrentToLongTerm {
+                    long_term_frame_idx: read_ue(reader)?,
+                },
+                _ => return Err(ParseError::InvalidData),
+            });
+        }
+        Ok(Self::Adaptive(operations))
+    }
+
+    fn write(&self, writer: &mut impl BitWrite) -> io::Result<()> {
+        use MemoryManagementControlOperation::*;
+
+        match self {
+            Self::Idr {
+                no_output_of_prior_pics_flag,
+                long_term_reference_flag,
+            } => {
+                writer.write_bit(*no_output_of_prior_pics_flag)?;
+                writer.write_bit(*long_term_reference_flag)?;
+            }
+            Self::SlidingWindow => writer.write_bit(false)?,
+            Self::Adaptive(operations) => {
+                writer.write_bit(true)?;
+                for operation in operations {
+                    match operation {
+                        MarkShortTermUnused {
+                            difference_of_pic_nums_minus1,
+                        } => {
+                            write_ue(writer, 1)?;
+                            write_ue(writer, *difference_of_pic_nums_minus1)?;
+                        }
+                        MarkLongTermUnused { long_term_pic_num } => {
+                            write_ue(writer, 2)?;
+                            write_ue(writer, *long_term_pic_num)?;
+                        }
+                        ShortTermToLongTerm {
+                            difference_of_pic_nums_minus1,
+                            long_term_frame_idx,
+                        } => {
+                            write_ue(writer, 3)?;
+                            write_ue(writer, *difference_of_pic_nums_minus1)?;
+                            write_ue(writer, *long_term_frame_idx)?;
+                        }
+                        MaxLongTermFrameIdx {
+                            max_long_term_frame_idx_plus1,
+                        } => {
+                            write_ue(writer, 4)?;
+                            write_ue(writer, *max_long_term_frame_idx_plus1)?;
+                        }
+                        MarkAllUnused => write_ue(writer, 5)?,
+                        CurrentToLongTerm { long_term_frame_idx } => {
+                            write_ue(writer, 6)?;
+                            write_ue(writer, *long_term_frame_idx)?;
+                        }
+                    }
+                }
+                write_ue(writer, 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct SliceHeader {
     // Section 7.3.3
+    // Fields which are not present in the bitstream hold their inferred value or zero.
 
     pub first_mb_in_slice : u32,
     pub slice_type : u32,
-    pub pic_parameter_set_id : u32,
+    pub pic_parameter_set_id : u8,
+    pub colour_plane_id : u8,
     pub frame_num : u32,
+    pub field_pic_flag : bool,
+    pub bottom_field_flag : bool,
+    pub idr_pic_id : Option<u32>, // Only present in IDR pictures
+    pub pic_order_cnt_lsb : u32,
+    pub delta_pic_order_cnt_bottom : i32,
+    pub delta_pic_order_cnt : [i32; 2],
+    pub redundant_pic_cnt : u32,
+    pub direct_spatial_mv_pred_flag : bool,
+    pub num_ref_idx_active_override_flag : bool,
+    pub num_ref_idx_l0_active_minus1 : u32,
+    pub num_ref_idx_l1_active_minus1 : u32,
+    pub ref_pic_list_modification_l0 : Option<Vec<RefPicListModification>>,
+    pub ref_pic_list_modification_l1 : Option<Vec<RefPicListModification>>,
+    pub pred_weight_table : Option<PredWeightTable>,
+    pub dec_ref_pic_marking : Option<DecRefPicMarking>, // Only present if nal_ref_idc != 0
+    pub cabac_init_idc : u32,
+    pub slice_qp_delta : i32,
+    pub sp_for_switch_flag : bool,
+    pub slice_qs_delta : i32,
+    pub disable_deblocking_filter_idc : u32,
+    pub slice_alpha_c0_offset_div2 : i32,
+    pub slice_beta_offset_div2 : i32,
+    pub slice_group_change_cycle : u32,
 
     pub data: Vec<u8>,
     data_offset: u64,
@@ -28,70 +347,701 @@ impl fmt::Display for SliceHeader {
             .field("slice_type", &self.slice_type)
             .field("pic_parameter_set_id", &self.pic_parameter_set_id)
             .field("frame_num", &self.frame_num)
+            .field("idr_pic_id", &self.idr_pic_id)
+            .field("pic_order_cnt_lsb", &self.pic_order_cnt_lsb)
+            .field("slice_qp_delta", &self.slice_qp_delta)
             .finish()
     }
 }
 
 impl SliceHeader {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
-        let mut reader = BitReader::endian(Cursor::new(bytes), BigEndian);
-        let first_mb_in_slice = read_ue(&mut reader)?;
-        let slice_type = read_ue(&mut reader)?;
-        let pic_parameter_set_id = read_ue(&mut reader)?;
+    pub fn slice_type(&self) -> Option<SliceType> {
+        SliceType::from_slice_type(self.slice_type)
+    }
+
+    fn has_ref_lists(&self) -> bool {
+        !matches!(self.slice_type(), Some(SliceType::I) | Some(SliceType::SI))
+    }
 
-        let separate_colour_plane_flag = false; //TODO actually get this from SPS
-        if separate_colour_plane_flag {
-            let _colour_plane_id = reader.read::<u8>(2)?;
+    fn has_pred_weight_table(&self, pps: &Pps) -> bool {
+        match self.slice_type() {
+            Some(SliceType::P) | Some(SliceType::SP) => pps.weighted_pred_flag,
+            Some(SliceType::B) => pps.weighted_bipred_idc == 1,
+            _ => false,
         }
+    }
 
-        let frame_num_bits = 4; //TODO actually get this from SPS
-        let frame_num = reader.read(frame_num_bits)?;
+    pub fn read<R: BitRead>(
+        reader: &mut R,
+        nal_unit_type: NALUnitType,
+        nal_ref_idc: u8,
+        parameter_sets: &ParameterSets,
+    ) -> Result<Self, ParseError> {
+        let first_mb_in_slice = read_ue(reader)?;
+        let slice_type = read_ue(reader)?;
+        let pic_parameter_set_id = read_ue(reader)?;
 
-        Ok(Self {
+        let (sps, pps) = parameter_sets.get(pic_parameter_set_id)?;
+
+        let mut header = Self {
             first_mb_in_slice,
             slice_type,
             pic_parameter_set_id,
-            frame_num,
-            data : bytes.into(),
-            data_offset : reader.position_in_bits()?,
-        })
+            colour_plane_id: 0,
+            frame_num: 0,
+            field_pic_flag: false,
+            bottom_field_flag: false,
+            idr_pic_id: None,
+            pic_order_cnt_lsb: 0,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0, 0],
+            redundant_pic_cnt: 0,
+            direct_spatial_mv_pred_flag: false,
+            num_ref_idx_active_override_flag: false,
+            num_ref_idx_l0_active_minus1: pps.num_ref_idx_l0_default_active_minus1.into(),
+            num_ref_idx_l1_active_minus1: pps.num_ref_idx_l1_default_active_minus1.into(),
+            ref_pic_list_modification_l0: None,
+            ref_pic_list_modification_l1: None,
+            pred_weight_table: None,
+            dec_ref_pic_marking: None,
+            cabac_init_idc: 0,
+            slice_qp_delta: 0,
+            sp_for_switch_flag: false,
+            slice_qs_delta: 0,
+            disable_deblocking_filter_idc: 0,
+            slice_alpha_c0_offset_div2: 0,
+            slice_beta_offset_div2: 0,
+            slice_group_change_cycle: 0,
+            data: Vec::new(),
+            data_offset: 0,
+        };
+        let slice_kind = header.slice_type().ok_or(ParseError::InvalidData)?;
+
+        if sps.separate_colour_plane_flag {
+            header.colour_plane_id = reader.read(2)?;
+        }
+
+        header.frame_num = reader.read(sps.frame_num_bits())?;
+
+        if !sps.frame_mbs_only_flag {
+            header.field_pic_flag = reader.read_bit()?;
+            if header.field_pic_flag {
+                header.bottom_field_flag = reader.read_bit()?;
+            }
+        }
+
+        let idr_pic = nal_unit_type == NALUnitType::CodedSliceIdr;
+        if idr_pic {
+            header.idr_pic_id = Some(read_ue(reader)?);
+        }
+
+        match sps.pic_order_cnt_type {
+            PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) => {
+                header.pic_order_cnt_lsb = reader.read(log2_max_pic_order_cnt_lsb_minus4 as u32 + 4)?;
+                if pps.bottom_field_pic_order_in_frame_present_flag && !header.field_pic_flag {
+                    header.delta_pic_order_cnt_bottom = read_se(reader)?;
+                }
+            }
+            PicOrderCntType::Type1 { delta_pic_order_always_zero_flag: false, .. } => {
+                header.delta_pic_order_cnt[0] = read_se(reader)?;
+                if pps.bottom_field_pic_order_in_frame_present_flag && !header.field_pic_flag {
+                    header.delta_pic_order_cnt[1] = read_se(reader)?;
+                }
+            }
+            _ => {}
+        }
+
+        if pps.redundant_pic_cnt_present_flag {
+            header.redundant_pic_cnt = read_ue(reader)?;
+        }
+
+        if slice_kind == SliceType::B {
+            header.direct_spatial_mv_pred_flag = reader.read_bit()?;
+        }
+
+        if header.has_ref_lists() {
+            header.num_ref_idx_active_override_flag = reader.read_bit()?;
+            if header.num_ref_idx_active_override_flag {
+                header.num_ref_idx_l0_active_minus1 = read_ue(reader)?;
+                if slice_kind == SliceType::B {
+                    header.num_ref_idx_l1_active_minus1 = read_ue(reader)?;
+                }
+            }
+        }
+
+        // ref_pic_list_mvc_modification for nal_unit_type 20 and 21 is not supported
+        if header.has_ref_lists() {
+            header.ref_pic_list_modification_l0 = RefPicListModification::read_list(reader)?;
+        }
+        if slice_kind == SliceType::B {
+            header.ref_pic_list_modification_l1 = RefPicListModification::read_list(reader)?;
+        }
+
+        if header.has_pred_weight_table(pps) {
+            let num_l1 = if slice_kind == SliceType::B {
+                Some(header.num_ref_idx_l1_active_minus1 + 1)
+            } else {
+                None
+            };
+            header.pred_weight_table = Some(PredWeightTable::read(
+                reader,
+                sps.chroma_array_type(),
+                header.num_ref_idx_l0_active_minus1 + 1,
+                num_l1,
+            )?);
+        }
+
+        if nal_ref_idc != 0 {
+            header.dec_ref_pic_marking = Some(DecRefPicMarking::read(reader, idr_pic)?);
+        }
+
+        if pps.entropy_coding_mode_flag && header.has_ref_lists() {
+            header.cabac_init_idc = read_ue(reader)?;
+        }
+
+        header.slice_qp_delta = read_se(reader)?;
+
+        if slice_kind == SliceType::SP || slice_kind == SliceType::SI {
+            if slice_kind == SliceType::SP {
+                header.sp_for_switch_flag = reader.read_bit()?;
+            }
+            header.slice_qs_delta = read_se(reader)?;
+        }
+
+        if pps.deblocking_filter_control_present_flag {
+            header.disable_deblocking_filter_idc = read_ue(reader)?;
+            if header.disable_deblocking_filter_idc != 1 {
+                header.slice_alpha_c0_offset_div2 = read_se(reader)?;
+                header.slice_beta_offset_div2 = read_se(reader)?;
+            }
+        }
+
+        if let Some(bits) = Self::slice_group_change_cycle_bits(sps, pps) {
+            header.slice_group_change_cycle = reader.read(bits)?;
+        }
+
+        if pps.entropy_coding_mode_flag {
+            // cabac_alignment_one_bit
+            // Counted as part of the header so the slice data always starts byte aligned
+            while !reader.byte_aligned() {
+                if !reader.read_bit()? {
+                    return Err(ParseError::InvalidData);
+                }
+            }
+        }
+
+        Ok(header)
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut vec = Vec::new();
-        let mut writer = BitWriter::endian(&mut vec, BigEndian);
+    fn slice_group_change_cycle_bits(sps: &Sps, pps: &Pps) -> Option<u32> {
+        pps.slice_groups
+            .as_ref()?
+            .slice_group_change_cycle_bits(sps.pic_size_in_map_units())
+    }
 
-        write_ue(&mut writer, self.first_mb_in_slice).unwrap();
-        write_ue(&mut writer, self.slice_type).unwrap();
-        write_ue(&mut writer, self.pic_parameter_set_id).unwrap();
+    pub fn write<W: BitWrite>(&self, writer: &mut W, sps: &Sps, pps: &Pps) -> io::Result<()> {
+        let slice_kind = self.slice_type().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid slice_type")
+        })?;
 
-        //TODO colour plane
+        write_ue(writer, self.first_mb_in_slice)?;
+        write_ue(writer, self.slice_type)?;
+        write_ue(writer, self.pic_parameter_set_id.into())?;
 
-        //TODO variable size
-        writer.write(4, self.frame_num).unwrap();
+        if sps.separate_colour_plane_flag {
+            writer.write(2, self.colour_plane_id)?;
+        }
 
-        // TODO this is probably highly inefficient
-        let mut reader = BitReader::endian(Cursor::new(&self.data), BigEndian);
-        reader.seek_bits(SeekFrom::Start(self.data_offset)).unwrap();
-        loop {
-            match reader.read_bit() {
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => panic!("Read failed from vec: {}", e),
-                Ok(bit) => writer.write_bit(bit).unwrap(),
+        writer.write(sps.frame_num_bits(), self.frame_num)?;
+
+        if !sps.frame_mbs_only_flag {
+            writer.write_bit(self.field_pic_flag)?;
+            if self.field_pic_flag {
+                writer.write_bit(self.bottom_field_flag)?;
             }
         }
 
-        writer.byte_align().unwrap();
+        if let Some(idr_pic_id) = self.idr_pic_id {
+            write_ue(writer, idr_pic_id)?;
+        }
 
-        vec
+        match sps.pic_order_cnt_type {
+            PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) => {
+                writer.write(log2_max_pic_order_cnt_lsb_minus4 as u32 + 4, self.pic_order_cnt_lsb)?;
+                if pps.bottom_field_pic_order_in_frame_present_flag && !self.field_pic_flag {
+                    write_se(writer, self.delta_pic_order_cnt_bottom)?;
+                }
+            }
+            PicOrderCntType::Type1 { delta_pic_order_always_zero_flag: false, .. } => {
+                write_se(writer, self.delta_pic_order_cnt[0])?;
+                if pps.bottom_field_pic_order_in_frame_present_flag && !self.field_pic_flag {
+                    write_se(writer, self.delta_pic_order_cnt[1])?;
+                }
+            }
+            _ => {}
+        }
+
+        if pps.redundant_pic_cnt_present_flag {
+            write_ue(writer, self.redundant_pic_cnt)?;
+        }
+
+        if slice_kind == SliceType::B {
+            writer.write_bit(self.direct_spatial_mv_pred_flag)?;
+        }
+
+        if self.has_ref_lists() {
+            writer.write_bit(self.num_ref_idx_active_override_flag)?;
+            if self.num_ref_idx_active_override_flag {
+                write_ue(writer, self.num_ref_idx_l0_active_minus1)?;
+                if slice_kind == SliceType::B {
+                    write_ue(writer, self.num_ref_idx_l1_active_minus1)?;
+                }
+            }
+        }
+
+        if self.has_ref_lists() {
+            RefPicListModification::write_list(writer, &self.ref_pic_list_modification_l0)?;
+        }
+        if slice_kind == SliceType::B {
+            RefPicListModification::write_list(writer, &self.ref_pic_list_modification_l1)?;
+        }
+
+        if self.has_pred_weight_table(pps) {
+            self.pred_weight_table
+                .as_ref()
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Missing pred_weight_table")
+                })?
+                .write(writer)?;
+        }
+
+        if let Some(dec_ref_pic_marking) = &self.dec_ref_pic_marking {
+            dec_ref_pic_marking.write(writer)?;
+        }
+
+        if pps.entropy_coding_mode_flag && self.has_ref_lists() {
+            write_ue(writer, self.cabac_init_idc)?;
+        }
+
+        write_se(writer, self.slice_qp_delta)?;
+
+        if slice_kind == SliceType::SP || slice_kind == SliceType::SI {
+            if slice_kind == SliceType::SP {
+                writer.write_bit(self.sp_for_switch_flag)?;
+            }
+            write_se(writer, self.slice_qs_delta)?;
+        }
+
+        if pps.deblocking_filter_control_present_flag {
+            write_ue(writer, self.disable_deblocking_filter_idc)?;
+            if self.disable_deblocking_filter_idc != 1 {
+                write_se(writer, self.slice_alpha_c0_offset_div2)?;
+                write_se(writer, self.slice_beta_offset_div2)?;
+            }
+        }
+
+        if let Some(bits) = Self::slice_group_change_cycle_bits(sps, pps) {
+            writer.write(bits, self.slice_group_change_cycle)?;
+        }
+
+        if pps.entropy_coding_mode_flag {
+            // cabac_alignment_one_bit
+            while !writer.byte_aligned() {
+                writer.write_bit(true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses the slice header from the RBSP of a slice NAL unit and keeps the slice data
+    pub fn from_bytes(
+        bytes: &[u8],
+        nal_unit_type: NALUnitType,
+        nal_ref_idc: u8,
+        parameter_sets: &ParameterSets,
+    ) -> Result<Self, ParseError> {
+        let mut reader = BitReader::endian(Cursor::new(bytes), BigEndian);
+        let mut header = Self::read(&mut reader, nal_unit_type, nal_ref_idc, parameter_sets)?;
+        header.data = bytes.into();
+        header.data_offset = reader.position_in_bits()?;
+        Ok(header)
+    }
+
+    /// Parses the slice header like `from_bytes` without copying the slice data.
+    /// Set `data` before writing the header with `to_bytes_with`, `rewrite_prefix` works without.
+    pub fn from_bytes_without_data(
+        bytes: &[u8],
+        nal_unit_type: NALUnitType,
+        nal_ref_idc: u8,
+        parameter_sets: &ParameterSets,
+    ) -> Result<Self, ParseError> {
+        let mut reader = BitReader::endian(Cursor::new(bytes), BigEndian);
+        let mut header = Self::read(&mut reader, nal_unit_type, nal_ref_idc, parameter_sets)?;
+        header.data_offset = reader.position_in_bits()?;
+        Ok(header)
+    }
+
+    /// Serializes the header over the beginning of `rbsp`, the RBSP the header was parsed from.
+    /// Returns the bytes up to the end of the header, the last one is completed with slice data.
+    /// Returns None if the header changed its length, then the slice data has to be shifted with
+    /// `to_bytes_with`.
+    pub fn rewrite_prefix(&self, sps: &Sps, pps: &Pps, rbsp: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut prefix = Vec::with_capacity(self.data_offset.div_ceil(8) as usize);
+        let mut writer = BitWriter::endian(&mut prefix, BigEndian);
+        self.write(&mut writer, sps, pps)?;
+        let (bits, value) = writer.into_unwritten();
+
+        if prefix.len() as u64 * 8 + bits as u64 != self.data_offset {
+            return Ok(None);
+        }
+        if bits > 0 {
+            let data_mask = 0xff >> bits;
+            prefix.push((value << (8 - bits)) | (rbsp[prefix.len()] & data_mask));
+        }
+        Ok(Some(prefix))
+    }
+
+    /// Serializes the header followed by the slice data to RBSP.
+    /// The header may differ in length from the one that was parsed.
+    pub fn to_bytes(&self, parameter_sets: &ParameterSets) -> Result<Vec<u8>, ParseError> {
+        let (sps, pps) = parameter_sets.get(self.pic_parameter_set_id)?;
+        Ok(self.to_bytes_with(sps, pps)?)
+    }
+
+    /// Like `to_bytes`, but with explicitly given parameter sets.
+    /// These may differ from the ones the header was parsed with, e.g. in the frame_num width.
+    pub fn to_bytes_with(&self, sps: &Sps, pps: &Pps) -> io::Result<Vec<u8>> {
+        let mut vec = Vec::with_capacity(self.data.len() + 8);
+        let mut writer = BitWriter::endian(&mut vec, BigEndian);
+
+        self.write(&mut writer, sps, pps)?;
+        let (bits, value) = writer.into_unwritten();
+
+        // Copy slice data up to and including the rbsp_stop_one_bit.
+        // Trailing alignment bits and cabac_zero_words are dropped and the alignment is redone.
+        let data_end = match self.data.iter().rposition(|b| *b != 0) {
+            Some(i) => i as u64 * 8 + 8 - self.data[i].trailing_zeros() as u64,
+            None => self.data_offset,
+        };
+        splice_bits(&mut vec, bits, value, &self.data, self.data_offset, data_end);
+
+        Ok(vec)
+    }
+}
+
+/// Appends the `bits` low bits of `pending` followed by the bits `from..to` of `src` to `dest`.
+/// The last byte is padded with zero bits.
+fn splice_bits(dest: &mut Vec<u8>, bits: u32, pending: u8, src: &[u8], mut from: u64, to: u64) {
+    let to = to.max(from);
+    dest.reserve((bits as u64 + to - from).div_ceil(8) as usize);
+    if bits > 0 {
+        let take = (8 - bits).min((to - from) as u32);
+        let mut byte = pending << (8 - bits);
+        if take > 0 {
+            let index = (from / 8) as usize;
+            let window = u16::from_be_bytes([src[index], src.get(index + 1).copied().unwrap_or(0)]);
+            let taken = (window << (from % 8)) >> (16 - take);
+            byte |= (taken as u8) << (8 - bits - take);
+        }
+        dest.push(byte);
+        from += take as u64;
+    }
+    if from == to {
+        return;
+    }
+
+    let start = (from / 8) as usize;
+    let end = start + (to - from).div_ceil(8) as usize;
+    let shift = (from % 8) as u32;
+    if shift == 0 {
+        dest.extend_from_slice(&src[start..end]);
+    } else {
+        // Shift eight bytes at a time while a ninth byte is there to fill the low bits
+        let mut i = start;
+        while i + 8 <= end && i + 9 <= src.len() {
+            let word = u64::from_be_bytes(src[i..i + 8].try_into().unwrap());
+            let word = (word << shift) | (src[i + 8] >> (8 - shift)) as u64;
+            dest.extend_from_slice(&word.to_be_bytes());
+            i += 8;
+        }
+        for i in i..end {
+            let next = src.get(i + 1).copied().unwrap_or(0);
+            dest.push((src[i] << shift) | (next >> (8 - shift)));
+        }
+    }
+
+    let tail_bits = ((to - from) % 8) as u32;
+    if tail_bits > 0 {
+        *dest.last_mut().unwrap() &= !(0xff >> tail_bits);
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::h264::{NalIterator, NalUnit, NALUnitType};
-    use std::io::Read;
+    use crate::h264::test_data::test_sps;
+    use crate::h264::{
+        write_rbsp_trailing_bits, NalIterator, NalUnit, PpsMoreData, SliceGroupChange,
+        SliceGroupMapType, SliceGroups,
+    };
+    use proptest::prelude::*;
+    use std::io::{Read, SeekFrom};
+
+    fn test_parameter_sets(entropy_coding_mode_flag: bool) -> ParameterSets {
+        let sps = test_sps();
+
+        let pps = Pps {
+            pic_parameter_set_id: 0,
+            seq_parameter_set_id: 0,
+            entropy_coding_mode_flag,
+            bottom_field_pic_order_in_frame_present_flag: false,
+            slice_groups: None,
+            num_ref_idx_l0_default_active_minus1: 0,
+            num_ref_idx_l1_default_active_minus1: 0,
+            weighted_pred_flag: true,
+            weighted_bipred_idc: 1,
+            pic_init_qp_minus26: 0,
+            pic_init_qs_minus26: 0,
+            chroma_qp_index_offset: 0,
+            deblocking_filter_control_present_flag: true,
+            constrained_intra_pred_flag: false,
+            redundant_pic_cnt_present_flag: false,
+            pps_more_data: Some(PpsMoreData {
+                transform_8x8_mode_flag: true,
+                pic_scaling_matrix: None,
+                second_chroma_qp_index_offset: 0,
+            }),
+        };
+
+        let mut parameter_sets = ParameterSets::new();
+        parameter_sets.insert_sps(sps);
+        parameter_sets.insert_pps(pps);
+        parameter_sets
+    }
+
+    fn test_header(slice_type: u32) -> SliceHeader {
+        SliceHeader {
+            first_mb_in_slice: 0,
+            slice_type,
+            pic_parameter_set_id: 0,
+            colour_plane_id: 0,
+            frame_num: 3,
+            field_pic_flag: false,
+            bottom_field_flag: false,
+            idr_pic_id: None,
+            pic_order_cnt_lsb: 0,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0, 0],
+            redundant_pic_cnt: 0,
+            direct_spatial_mv_pred_flag: false,
+            num_ref_idx_active_override_flag: false,
+            num_ref_idx_l0_active_minus1: 0,
+            num_ref_idx_l1_active_minus1: 0,
+            ref_pic_list_modification_l0: None,
+            ref_pic_list_modification_l1: None,
+            pred_weight_table: None,
+            dec_ref_pic_marking: None,
+            cabac_init_idc: 0,
+            slice_qp_delta: -3,
+            sp_for_switch_flag: false,
+            slice_qs_delta: 0,
+            disable_deblocking_filter_idc: 0,
+            slice_alpha_c0_offset_div2: 1,
+            slice_beta_offset_div2: -1,
+            slice_group_change_cycle: 0,
+            data: Vec::new(),
+            data_offset: 0,
+        }
+    }
+
+    /// Writes the header followed by some slice data and parses it again
+    fn roundtrip(
+        header: &SliceHeader,
+        nal_unit_type: NALUnitType,
+        nal_ref_idc: u8,
+        parameter_sets: &ParameterSets,
+    ) {
+        let (sps, pps) = parameter_sets.get(header.pic_parameter_set_id).unwrap();
+        let mut rbsp = Vec::new();
+        let mut writer = BitWriter::endian(&mut rbsp, BigEndian);
+        header.write(&mut writer, sps, pps).unwrap();
+        writer.write(16, 0xabcdu32).unwrap();
+        write_rbsp_trailing_bits(&mut writer).unwrap();
+
+        let parsed = SliceHeader::from_bytes(&rbsp, nal_unit_type, nal_ref_idc, parameter_sets).unwrap();
+        let mut expected = header.clone();
+        expected.data = parsed.data.clone();
+        expected.data_offset = parsed.data_offset;
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.to_bytes(parameter_sets).unwrap(), rbsp);
+    }
+
+    #[test]
+    fn test_idr_roundtrip() {
+        let mut header = test_header(7);
+        header.frame_num = 0;
+        header.idr_pic_id = Some(1);
+        header.dec_ref_pic_marking = Some(DecRefPicMarking::Idr {
+            no_output_of_prior_pics_flag: false,
+            long_term_reference_flag: true,
+        });
+        for cabac in [false, true].iter() {
+            roundtrip(&header, NALUnitType::CodedSliceIdr, 3, &test_parameter_sets(*cabac));
+        }
+    }
+
+    #[test]
+    fn test_p_roundtrip() {
+        let mut header = test_header(0);
+        header.num_ref_idx_active_override_flag = true;
+        header.num_ref_idx_l0_active_minus1 = 1;
+        header.ref_pic_list_modification_l0 = Some(vec![
+            RefPicListModification::SubtractAbsDiffPicNum(2),
+            RefPicListModification::LongTermPicNum(0),
+        ]);
+        header.pred_weight_table = Some(PredWeightTable {
+            luma_log2_weight_denom: 5,
+            chroma_log2_weight_denom: Some(3),
+            l0: vec![
+                PredWeight { luma: Some((32, -2)), chroma: None },
+                PredWeight { luma: None, chroma: Some([(8, 1), (8, -1)]) },
+            ],
+            l1: vec![],
+        });
+        header.dec_ref_pic_marking = Some(DecRefPicMarking::Adaptive(vec![
+            MemoryManagementControlOperation::MarkShortTermUnused { difference_of_pic_nums_minus1: 0 },
+            MemoryManagementControlOperation::ShortTermToLongTerm {
+                difference_of_pic_nums_minus1: 1,
+                long_term_frame_idx: 0,
+            },
+            MemoryManagementControlOperation::MarkAllUnused,
+        ]));
+        header.cabac_init_idc = 2;
+        for cabac in [false, true].iter() {
+            let mut header = header.clone();
+            if !cabac {
+                header.cabac_init_idc = 0;
+            }
+            roundtrip(&header, NALUnitType::CodedSliceNonIdr, 2, &test_parameter_sets(*cabac));
+        }
+    }
+
+    #[test]
+    fn test_slice_group_change_cycle_roundtrip() {
+        let mut parameter_sets = test_parameter_sets(false);
+        let mut pps = parameter_sets.pps(0).unwrap().clone();
+        pps.slice_groups = Some(SliceGroups {
+            num_slice_groups_minus1: 1,
+            slice_group_map_type: SliceGroupMapType::Wipe(SliceGroupChange {
+                slice_group_change_direction_flag: false,
+                slice_group_change_rate_minus1: 4,
+            }),
+        });
+        parameter_sets.insert_pps(pps);
+
+        let mut header = test_header(2);
+        header.slice_group_change_cycle = 13;
+        roundtrip(&header, NALUnitType::CodedSliceNonIdr, 0, &parameter_sets);
+    }
+
+    #[test]
+    fn test_b_roundtrip() {
+        let mut header = test_header(1);
+        header.direct_spatial_mv_pred_flag = true;
+        header.ref_pic_list_modification_l1 = Some(vec![RefPicListModification::AddAbsDiffPicNum(4)]);
+        header.pred_weight_table = Some(PredWeightTable {
+            luma_log2_weight_denom: 0,
+            chroma_log2_weight_denom: Some(0),
+            l0: vec![PredWeight { luma: Some((1, 0)), chroma: None }],
+            l1: vec![PredWeight { luma: None, chroma: None }],
+        });
+        header.disable_deblocking_filter_idc = 1;
+        header.slice_alpha_c0_offset_div2 = 0;
+        header.slice_beta_offset_div2 = 0;
+        roundtrip(&header, NALUnitType::CodedSliceNonIdr, 0, &test_parameter_sets(false));
+    }
+
+    #[test]
+    fn test_rewrite_header_length() {
+        let parameter_sets = test_parameter_sets(false);
+        let (sps, pps) = parameter_sets.get(0).unwrap();
+        let header = test_header(2);
+        let mut rbsp = Vec::new();
+        let mut writer = BitWriter::endian(&mut rbsp, BigEndian);
+        header.write(&mut writer, sps, pps).unwrap();
+        writer.write(16, 0xabcdu32).unwrap();
+        write_rbsp_trailing_bits(&mut writer).unwrap();
+
+        let mut parsed = SliceHeader::from_bytes(&rbsp, NALUnitType::CodedSliceNonIdr, 0, &parameter_sets).unwrap();
+        assert_eq!(parsed.to_bytes(&parameter_sets).unwrap(), rbsp);
+        parsed.first_mb_in_slice = 1000;
+        let rewritten = parsed.to_bytes(&parameter_sets).unwrap();
+        let reparsed = SliceHeader::from_bytes(&rewritten, NALUnitType::CodedSliceNonIdr, 0, &parameter_sets).unwrap();
+        assert_eq!(reparsed.first_mb_in_slice, 1000);
+        assert_eq!(reparsed.slice_qp_delta, header.slice_qp_delta);
+
+        let mut reader = BitReader::endian(Cursor::new(&rewritten), BigEndian);
+        reader.seek_bits(SeekFrom::Start(reparsed.data_offset)).unwrap();
+        assert_eq!(reader.read::<u32>(16).unwrap(), 0xabcd);
+    }
+
+    #[test]
+    fn test_rewrite_prefix() {
+        let parameter_sets = test_parameter_sets(false);
+        let (sps, pps) = parameter_sets.get(0).unwrap();
+        let mut rbsp = Vec::new();
+        let mut writer = BitWriter::endian(&mut rbsp, BigEndian);
+        test_header(2).write(&mut writer, sps, pps).unwrap();
+        writer.write(16, 0xabcdu32).unwrap();
+        write_rbsp_trailing_bits(&mut writer).unwrap();
+
+        let mut parsed = SliceHeader::from_bytes(&rbsp, NALUnitType::CodedSliceNonIdr, 0, &parameter_sets).unwrap();
+        let mut without_data = SliceHeader::from_bytes_without_data(&rbsp, NALUnitType::CodedSliceNonIdr, 0, &parameter_sets).unwrap();
+        assert!(without_data.data.is_empty());
+
+        // frame_num has a fixed width
+        parsed.frame_num = 3;
+        without_data.frame_num = 3;
+        let prefix = without_data.rewrite_prefix(sps, pps, &rbsp).unwrap().unwrap();
+        let mut rewritten = prefix.clone();
+        rewritten.extend_from_slice(&rbsp[prefix.len()..]);
+        assert_eq!(rewritten, parsed.to_bytes(&parameter_sets).unwrap());
+
+        without_data.first_mb_in_slice = 1000;
+        assert_eq!(without_data.rewrite_prefix(sps, pps, &rbsp).unwrap(), None);
+    }
+
+    /// The bit by bit copy `splice_bits` replaces
+    fn splice_bits_reference(bits: u32, pending: u8, src: &[u8], from: u64, to: u64) -> Vec<u8> {
+        let mut vec = Vec::new();
+        let mut writer = BitWriter::endian(&mut vec, BigEndian);
+        writer.write(bits, pending).unwrap();
+        let mut reader = BitReader::endian(Cursor::new(src), BigEndian);
+        reader.seek_bits(SeekFrom::Start(from)).unwrap();
+        for _ in from..to {
+            writer.write_bit(reader.read_bit().unwrap()).unwrap();
+        }
+        writer.byte_align().unwrap();
+        vec
+    }
+
+    proptest! {
+        #[test]
+        fn test_splice_bits(
+            src in proptest::collection::vec(any::<u8>(), 0..64),
+            bits in 0u32..8,
+            pending: u8,
+            range in (0u64..=512, 0u64..=512),
+        ) {
+            let pending = pending & (0xff >> (8 - bits)) as u8;
+            let len = src.len() as u64 * 8;
+            let (from, to) = (range.0.min(range.1).min(len), range.0.max(range.1).min(len));
+            let mut spliced = Vec::new();
+            splice_bits(&mut spliced, bits, pending, &src, from, to);
+            prop_assert_eq!(spliced, splice_bits_reference(bits, pending, &src, from, to));
+        }
+    }
 
     #[test]
     fn smoke_test() {
@@ -99,11 +1049,19 @@ mod test {
         let file = std::io::BufReader::new(file);
         let it = NalIterator::new(file.bytes().map(|x| x.unwrap()));
         let it = it.map(move |x| NalUnit::from_bytes(&x));
+        let mut parameter_sets = ParameterSets::new();
         for unit in it {
             let unit = unit.unwrap();
             match unit.nal_unit_type {
+                NALUnitType::Sps => {
+                    parameter_sets.insert_sps(Sps::read(&mut BitReader::endian(unit.rbsp(), BigEndian)).unwrap());
+                },
+                NALUnitType::Pps => {
+                    let pps = Pps::read(&mut BitReader::endian(unit.rbsp(), BigEndian), &parameter_sets).unwrap();
+                    parameter_sets.insert_pps(pps);
+                },
                 NALUnitType::CodedSliceIdr | NALUnitType::CodedSliceNonIdr => {
-                    let header = SliceHeader::from_bytes(&unit.rbsp).unwrap();
+                    SliceHeader::from_bytes(unit.rbsp(), unit.nal_unit_type, unit.nal_ref_idc, &parameter_sets).unwrap();
                 },
                 _ => {},
             }
@@ -1,17 +1,74 @@
 use crate::h264::{
-    read_optional, read_optional_unimplemented, read_rbsp_trailing_bits, read_ue, write_optional,
-    write_rbsp_trailing_bits, write_ue, ParseError,
+    read_optional, read_rbsp_trailing_bits, read_se, read_ue,
+    write_optional, write_rbsp_trailing_bits, write_se, write_ue, ParseError, ScalingMatrix,
 };
-use bitstream_io::{BitRead, BitWrite};
+use bitstream_io::{BigEndian, BitRead, BitWrite, BitWriter};
 use visit_diff::Diff;
 
 #[derive(Clone, Debug, Diff, PartialEq)]
 pub enum PicOrderCntType {
     Type0(u8), // log2_max_pic_order_cnt_lsb_minus4// 0 to 12
-    //Type1, //unimplemented
+    Type1 {
+        delta_pic_order_always_zero_flag: bool,
+        offset_for_non_ref_pic: i32,
+        offset_for_top_to_bottom_field: i32,
+        offset_for_ref_frame: Vec<i32>, // num_ref_frames_in_pic_order_cnt_cycle entries, 0 to 255
+    },
     Type2,
 }
 
+impl PicOrderCntType {
+    pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
+        let pic_order_cnt_type = read_ue(reader)?;
+        Ok(match pic_order_cnt_type {
+            0 => PicOrderCntType::Type0(read_ue(reader)?),
+            1 => {
+                let delta_pic_order_always_zero_flag = reader.read_bit()?;
+                let offset_for_non_ref_pic = read_se(reader)?;
+                let offset_for_top_to_bottom_field = read_se(reader)?;
+                let num_ref_frames_in_pic_order_cnt_cycle: u8 = read_ue(reader)?;
+                let offset_for_ref_frame = (0..num_ref_frames_in_pic_order_cnt_cycle)
+                    .map(|_| read_se(reader))
+                    .collect::<Result<_, _>>()?;
+                PicOrderCntType::Type1 {
+                    delta_pic_order_always_zero_flag,
+                    offset_for_non_ref_pic,
+                    offset_for_top_to_bottom_field,
+                    offset_for_ref_frame,
+                }
+            }
+            2 => PicOrderCntType::Type2,
+            _ => return Err(ParseError::InvalidData),
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        match self {
+            PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) => {
+                write_ue(writer, 0)?;
+                write_ue(writer, (*log2_max_pic_order_cnt_lsb_minus4).into())?;
+            }
+            PicOrderCntType::Type1 {
+                delta_pic_order_always_zero_flag,
+                offset_for_non_ref_pic,
+                offset_for_top_to_bottom_field,
+                offset_for_ref_frame,
+            } => {
+                write_ue(writer, 1)?;
+                writer.write_bit(*delta_pic_order_always_zero_flag)?;
+                write_se(writer, *offset_for_non_ref_pic)?;
+                write_se(writer, *offset_for_top_to_bottom_field)?;
+                write_ue(writer, offset_for_ref_frame.len() as u32)?;
+                for offset in offset_for_ref_frame {
+                    write_se(writer, *offset)?;
+                }
+            }
+            PicOrderCntType::Type2 => write_ue(writer, 2)?,
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Diff, PartialEq)]
 pub struct Sps {
     pub profile_idc: u8,
@@ -28,15 +85,9 @@ pub struct Sps {
     pub bit_depth_luma_minus8: u8,   // 0 to 6, default is 0
     pub bit_depth_chroma_minus8: u8, // 0 to 6, default is 0
     pub qpprime_y_zero_transform_bypass_flag: bool,
-    //pub seq_scaling_matrix_present_flag: bool, //unimplemented
-    //pub seq_scaling_list_present_flag: Vec<bool>,
+    pub seq_scaling_matrix: Option<ScalingMatrix>,
     pub log2_max_frame_num_minus4: u8, // 0 to 12
     pub pic_order_cnt_type: PicOrderCntType,
-    //pub log2_max_pic_order_cnt_lsb_minus4 : u8, // 0 to 12
-    //pub delta_pic_order_always_zero_flag: , //unimplemented
-    //pub offset_for_non_ref_pic: ,
-    //pub offset_for_top_to_bottom_field:,
-    //pub offset_for_ref_frame: Vec<>,
     pub max_num_ref_frames: u32, // 0 to MaxDpbFrames (as specified in clause A.3.1 or A.3.2)
     pub gaps_in_frame_num_value_allowed_flag: bool,
     pub pic_width_in_mbs_minus1: u32,
@@ -49,6 +100,67 @@ pub struct Sps {
 }
 
 impl Sps {
+    /// Number of bits used for frame_num in slice headers
+    pub fn frame_num_bits(&self) -> u32 {
+        self.log2_max_frame_num_minus4 as u32 + 4
+    }
+
+    /// MaxFrameNum, frame_num wraps around at this value
+    pub fn max_frame_num(&self) -> u32 {
+        1 << self.frame_num_bits()
+    }
+
+    pub fn to_rbsp(&self) -> Vec<u8> {
+        let mut rbsp = Vec::new();
+        self.write(&mut BitWriter::endian(&mut rbsp, BigEndian))
+            .expect("Writing to Vec failed");
+        rbsp
+    }
+
+    /// PicSizeInMapUnits
+    pub fn pic_size_in_map_units(&self) -> u32 {
+        (self.pic_width_in_mbs_minus1 + 1) * (self.pic_height_in_map_units_minus1 + 1)
+    }
+
+    /// MaxPicOrderCntLsb for pic_order_cnt_type 0
+    pub fn max_pic_order_cnt_lsb(&self) -> Option<u32> {
+        match self.pic_order_cnt_type {
+            PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) => {
+                Some(1 << (log2_max_pic_order_cnt_lsb_minus4 as u32 + 4))
+            }
+            _ => None,
+        }
+    }
+
+    /// ChromaArrayType as defined in 7.4.2.1.1
+    pub fn chroma_array_type(&self) -> u8 {
+        if self.separate_colour_plane_flag {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    /// Width and height of the decoded frames after cropping, see 7.4.2.1.1
+    pub fn dimensions(&self) -> (u32, u32) {
+        let (sub_width_c, sub_height_c) = match self.chroma_array_type() {
+            0 => (1, 1), // Monochrome or separately coded colour planes
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let frame_height_factor = if self.frame_mbs_only_flag { 1 } else { 2 };
+        let width = (self.pic_width_in_mbs_minus1 + 1) * 16;
+        let height = frame_height_factor * (self.pic_height_in_map_units_minus1 + 1) * 16;
+        match self.frame_crop_offset {
+            Some((left, right, top, bottom)) => (
+                width.saturating_sub(sub_width_c * (left + right)),
+                height.saturating_sub(sub_height_c * frame_height_factor * (top + bottom)),
+            ),
+            None => (width, height),
+        }
+    }
+
     pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
         let profile_idc = reader.read(8)?;
         let constraint_set0_flag = reader.read_bit()?;
@@ -69,6 +181,7 @@ impl Sps {
         let mut bit_depth_luma_minus8: u8 = 0;
         let mut bit_depth_chroma_minus8: u8 = 0;
         let mut qpprime_y_zero_transform_bypass_flag = false;
+        let mut seq_scaling_matrix = None;
 
         match profile_idc {
             100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135 => {
@@ -80,20 +193,15 @@ impl Sps {
                 bit_depth_chroma_minus8 = read_ue(reader)?;
                 qpprime_y_zero_transform_bypass_flag = reader.read_bit()?;
 
-                // seq_scaling_matrix_present_flag
-                read_optional_unimplemented(reader)?;
+                let num_8x8_lists = if chroma_format_idc != 3 { 2 } else { 6 };
+                seq_scaling_matrix =
+                    read_optional(reader, |r| ScalingMatrix::read(r, num_8x8_lists))?;
             }
             _ => {}
         }
 
         let log2_max_frame_num_minus4: u8 = read_ue(reader)?;
-        let pic_order_cnt_type = read_ue(reader)?;
-        let pic_order_cnt_type = match pic_order_cnt_type {
-            0 => PicOrderCntType::Type0(read_ue(reader)?),
-            1 => return Err(ParseError::Unimplemented),
-            2 => PicOrderCntType::Type2,
-            _ => return Err(ParseError::InvalidData),
-        };
+        let pic_order_cnt_type = PicOrderCntType::read(reader)?;
         let max_num_ref_frames: u32 = read_ue(reader)?;
         let gaps_in_frame_num_value_allowed_flag: bool = reader.read_bit()?;
         let pic_width_in_mbs_minus1: u32 = read_ue(reader)?;
@@ -128,8 +236,7 @@ impl Sps {
             bit_depth_luma_minus8,
             bit_depth_chroma_minus8,
             qpprime_y_zero_transform_bypass_flag,
-            //seq_scaling_matrix_present_flag,
-            //pub seq_scaling_list_present_flag,
+            seq_scaling_matrix,
             log2_max_frame_num_minus4,
             pic_order_cnt_type,
             max_num_ref_frames,
@@ -166,27 +273,17 @@ impl Sps {
                 if self.chroma_format_idc == 3 {
                     writer.write_bit(self.separate_colour_plane_flag)?;
                 }
-                write_ue(writer, self.bit_depth_chroma_minus8.into())?;
+                write_ue(writer, self.bit_depth_luma_minus8.into())?;
                 write_ue(writer, self.bit_depth_chroma_minus8.into())?;
                 writer.write_bit(self.qpprime_y_zero_transform_bypass_flag)?;
 
-                // seq_scaling_matrix_present_flag
-                writer.write_bit(false)?;
+                write_optional(writer, &self.seq_scaling_matrix, |w, m| m.write(w))?;
             }
             _ => {}
         }
 
         write_ue(writer, self.log2_max_frame_num_minus4.into())?;
-        write_ue(
-            writer,
-            match self.pic_order_cnt_type {
-                PicOrderCntType::Type0(_) => 0,
-                PicOrderCntType::Type2 => 2,
-            },
-        )?;
-        if let PicOrderCntType::Type0(log2_max_pic_order_cnt_lsb_minus4) = self.pic_order_cnt_type {
-            write_ue(writer, log2_max_pic_order_cnt_lsb_minus4.into())?;
-        }
+        self.pic_order_cnt_type.write(writer)?;
         write_ue(writer, self.max_num_ref_frames)?;
         writer.write_bit(self.gaps_in_frame_num_value_allowed_flag)?;
         write_ue(writer, self.pic_width_in_mbs_minus1)?;
@@ -361,61 +458,166 @@ impl BitstreamRestriction {
     }
 }
 
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub enum AspectRatio {
+    Idc(u8), // aspect_ratio_idc from Table E-1
+    ExtendedSar { sar_width: u16, sar_height: u16 },
+}
+
+impl AspectRatio {
+    const EXTENDED_SAR: u8 = 255;
+
+    pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
+        let idc = reader.read::<u8>(8)?;
+        if idc == Self::EXTENDED_SAR {
+            Ok(AspectRatio::ExtendedSar {
+                sar_width: reader.read(16)?,
+                sar_height: reader.read(16)?,
+            })
+        } else {
+            Ok(AspectRatio::Idc(idc))
+        }
+    }
+
+    /// Fails for `Idc(255)`, extended SARs have to be given as `ExtendedSar`
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        match self {
+            AspectRatio::Idc(Self::EXTENDED_SAR) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Extended_SAR without sar_width and sar_height",
+            )),
+            AspectRatio::Idc(idc) => writer.write(8, *idc),
+            AspectRatio::ExtendedSar {
+                sar_width,
+                sar_height,
+            } => {
+                writer.write(8, Self::EXTENDED_SAR)?;
+                writer.write(16, *sar_width)?;
+                writer.write(16, *sar_height)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub struct CpbSpec {
+    pub bit_rate_value_minus1: u32,
+    pub cpb_size_value_minus1: u32,
+    pub cbr_flag: bool,
+}
+
+/// hrd_parameters() as defined in E.1.2
+#[derive(Clone, Debug, Diff, PartialEq)]
+pub struct HrdParameters {
+    pub bit_rate_scale: u8,
+    pub cpb_size_scale: u8,
+    pub cpb_specs: Vec<CpbSpec>, // cpb_cnt_minus1 + 1 entries, 1 to 32
+    pub initial_cpb_removal_delay_length_minus1: u8,
+    pub cpb_removal_delay_length_minus1: u8,
+    pub dpb_output_delay_length_minus1: u8,
+    pub time_offset_length: u8,
+}
+
+impl HrdParameters {
+    pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
+        let cpb_cnt_minus1: u32 = read_ue(reader)?;
+        if cpb_cnt_minus1 > 31 {
+            return Err(ParseError::InvalidData);
+        }
+        let bit_rate_scale = reader.read(4)?;
+        let cpb_size_scale = reader.read(4)?;
+        let cpb_specs = (0..=cpb_cnt_minus1)
+            .map(|_| {
+                Ok(CpbSpec {
+                    bit_rate_value_minus1: read_ue(reader)?,
+                    cpb_size_value_minus1: read_ue(reader)?,
+                    cbr_flag: reader.read_bit()?,
+                })
+            })
+            .collect::<Result<_, ParseError>>()?;
+        Ok(Self {
+            bit_rate_scale,
+            cpb_size_scale,
+            cpb_specs,
+            initial_cpb_removal_delay_length_minus1: reader.read(5)?,
+            cpb_removal_delay_length_minus1: reader.read(5)?,
+            dpb_output_delay_length_minus1: reader.read(5)?,
+            time_offset_length: reader.read(5)?,
+        })
+    }
+
+    /// Fails unless there are 1 to 32 `cpb_specs`
+    pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
+        if !(1..=32).contains(&self.cpb_specs.len()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} CPB specifications, 1 to 32 are allowed", self.cpb_specs.len()),
+            ));
+        }
+        write_ue(writer, self.cpb_specs.len() as u32 - 1)?;
+        writer.write(4, self.bit_rate_scale)?;
+        writer.write(4, self.cpb_size_scale)?;
+        for cpb_spec in self.cpb_specs.iter() {
+            write_ue(writer, cpb_spec.bit_rate_value_minus1)?;
+            write_ue(writer, cpb_spec.cpb_size_value_minus1)?;
+            writer.write_bit(cpb_spec.cbr_flag)?;
+        }
+        writer.write(5, self.initial_cpb_removal_delay_length_minus1)?;
+        writer.write(5, self.cpb_removal_delay_length_minus1)?;
+        writer.write(5, self.dpb_output_delay_length_minus1)?;
+        writer.write(5, self.time_offset_length)?;
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Diff, PartialEq)]
 pub struct VuiParameters {
-    pub aspect_ratio_idc: Option<u8>,
+    pub aspect_ratio: Option<AspectRatio>,
     pub overscan_appropriate_flag: Option<bool>,
     pub video_signal_type: Option<VideoSignalType>,
     pub chroma_loc_info: Option<ChromaLocInfo>,
     pub timing_info: Option<TimingInfo>,
+    pub nal_hrd_parameters: Option<HrdParameters>,
+    pub vcl_hrd_parameters: Option<HrdParameters>,
+    pub low_delay_hrd_flag: bool, // Only present if there are NAL or VCL HRD parameters
     pub pic_struct_present_flag: bool,
     pub bitstream_restriction: Option<BitstreamRestriction>,
 }
 
 impl VuiParameters {
     pub fn read(reader: &mut impl BitRead) -> Result<Self, ParseError> {
-        let aspect_ratio_idc = read_optional(reader, |r| {
-            let idc = r.read::<u8>(8)?;
-            if idc == 255 {
-                return Err(ParseError::Unimplemented); // Extended_SAR
-            }
-            Ok(idc)
-        })?;
-
+        let aspect_ratio = read_optional(reader, |r| AspectRatio::read(r))?;
         let overscan_appropriate_flag = read_optional(reader, |r| Ok(r.read_bit()?))?;
         let video_signal_type = read_optional(reader, |r| VideoSignalType::read(r))?;
         let chroma_loc_info = read_optional(reader, |r| ChromaLocInfo::read(r))?;
         let timing_info = read_optional(reader, |r| TimingInfo::read(r))?;
 
-        //TODO
-        //nal_hrd_parameters_present_flag
-        read_optional_unimplemented(reader)?;
-
-        //vcl_hrd_parameters_present_flag
-        read_optional_unimplemented(reader)?;
-
-        //if( nal_hrd_parameters_present_flag || vcl_hrd_parameters_present_flag ) low_delay_hrd_flag
+        let nal_hrd_parameters = read_optional(reader, |r| HrdParameters::read(r))?;
+        let vcl_hrd_parameters = read_optional(reader, |r| HrdParameters::read(r))?;
+        let mut low_delay_hrd_flag = false;
+        if nal_hrd_parameters.is_some() || vcl_hrd_parameters.is_some() {
+            low_delay_hrd_flag = reader.read_bit()?;
+        }
 
         let pic_struct_present_flag = reader.read_bit()?;
         let bitstream_restriction = read_optional(reader, |r| BitstreamRestriction::read(r))?;
 
         Ok(Self {
-            aspect_ratio_idc,
+            aspect_ratio,
             overscan_appropriate_flag,
             video_signal_type,
             chroma_loc_info,
             timing_info,
+            nal_hrd_parameters,
+            vcl_hrd_parameters,
+            low_delay_hrd_flag,
             pic_struct_present_flag,
             bitstream_restriction,
         })
     }
+
     pub fn write(&self, writer: &mut impl BitWrite) -> std::io::Result<()> {
-        write_optional(writer, &self.aspect_ratio_idc, |w, aspect| {
-            if *aspect == 255 {
-                unimplemented!(); // Extended_SAR
-            }
-            w.write(8, *aspect)
-        })?;
+        write_optional(writer, &self.aspect_ratio, |w, v| v.write(w))?;
 
         write_optional(writer, &self.overscan_appropriate_flag, |w, v| {
             w.write_bit(*v)
@@ -425,14 +627,11 @@ impl VuiParameters {
         write_optional(writer, &self.chroma_loc_info, |w, v| v.write(w))?;
         write_optional(writer, &self.timing_info, |w, v| v.write(w))?;
 
-        //TODO
-        //nal_hrd_parameters_present_flag
-        writer.write_bit(false)?;
-
-        //vcl_hrd_parameters_present_flag
-        writer.write_bit(false)?;
-
-        //if( nal_hrd_parameters_present_flag || vcl_hrd_parameters_present_flag ) low_delay_hrd_flag
+        write_optional(writer, &self.nal_hrd_parameters, |w, v| v.write(w))?;
+        write_optional(writer, &self.vcl_hrd_parameters, |w, v| v.write(w))?;
+        if self.nal_hrd_parameters.is_some() || self.vcl_hrd_parameters.is_some() {
+            writer.write_bit(self.low_delay_hrd_flag)?;
+        }
 
         writer.write_bit(self.pic_struct_present_flag)?;
 
@@ -440,22 +639,120 @@ impl VuiParameters {
 
         Ok(())
     }
+
+    /// CpbDpbDelaysPresentFlag, picture timing SEI messages then contain the delays
+    pub fn hrd_parameters(&self) -> Option<&HrdParameters> {
+        self.nal_hrd_parameters
+            .as_ref()
+            .or(self.vcl_hrd_parameters.as_ref())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use bitstream_io::{BigEndian, BitReader, BitWriter};
+    use crate::h264::test_data::SPS_RBSP;
+    use crate::h264::ScalingList;
+    use bitstream_io::BitReader;
 
     #[test]
     fn test_sps_reencode() {
-        let rbsp: &[u8] = &[
-            100, 0, 40, 172, 180, 3, 192, 17, 63, 44, 32, 0, 0, 0, 32, 0, 0, 6, 1, 227, 6, 84,
-        ];
+        let rbsp = SPS_RBSP;
         let sps = Sps::read(&mut BitReader::endian(rbsp, BigEndian)).unwrap();
         let mut rbsp_reencode = Vec::new();
         sps.write(&mut BitWriter::endian(&mut rbsp_reencode, BigEndian))
             .unwrap();
         assert_eq!(rbsp, rbsp_reencode);
     }
+
+    #[test]
+    fn test_sps_bit_depth_reencode() {
+        let rbsp = SPS_RBSP;
+        let mut sps = Sps::read(&mut BitReader::endian(rbsp, BigEndian)).unwrap();
+        sps.bit_depth_luma_minus8 = 2;
+        let rbsp_reencode = sps.to_rbsp();
+        let sps_reencode = Sps::read(&mut BitReader::endian(rbsp_reencode.as_slice(), BigEndian)).unwrap();
+        assert_eq!(sps, sps_reencode);
+    }
+
+    #[test]
+    fn test_pic_order_cnt_type1_reencode() {
+        let rbsp = SPS_RBSP;
+        let mut sps = Sps::read(&mut BitReader::endian(rbsp, BigEndian)).unwrap();
+        sps.pic_order_cnt_type = PicOrderCntType::Type1 {
+            delta_pic_order_always_zero_flag: false,
+            offset_for_non_ref_pic: -2,
+            offset_for_top_to_bottom_field: 1,
+            offset_for_ref_frame: vec![2, 2, -4],
+        };
+        let rbsp_reencode = sps.to_rbsp();
+        let sps_reencode = Sps::read(&mut BitReader::endian(rbsp_reencode.as_slice(), BigEndian)).unwrap();
+        assert_eq!(sps, sps_reencode);
+        assert_eq!(rbsp_reencode, sps_reencode.to_rbsp());
+    }
+
+    #[test]
+    fn test_seq_scaling_matrix_reencode() {
+        let rbsp = SPS_RBSP;
+        let mut sps = Sps::read(&mut BitReader::endian(rbsp, BigEndian)).unwrap();
+        sps.seq_scaling_matrix = Some(ScalingMatrix {
+            scaling_lists_4x4: vec![ScalingList::Explicit(vec![24; 16]); 6],
+            scaling_lists_8x8: vec![ScalingList::UseDefault, ScalingList::NotPresent],
+        });
+        let rbsp_reencode = sps.to_rbsp();
+        let sps_reencode = Sps::read(&mut BitReader::endian(rbsp_reencode.as_slice(), BigEndian)).unwrap();
+        assert_eq!(sps, sps_reencode);
+    }
+
+    #[test]
+    fn test_vui_hrd_reencode() {
+        let rbsp = SPS_RBSP;
+        let mut sps = Sps::read(&mut BitReader::endian(rbsp, BigEndian)).unwrap();
+        let hrd_parameters = HrdParameters {
+            bit_rate_scale: 4,
+            cpb_size_scale: 3,
+            cpb_specs: vec![
+                CpbSpec {
+                    bit_rate_value_minus1: 31249,
+                    cpb_size_value_minus1: 124999,
+                    cbr_flag: false,
+                },
+                CpbSpec {
+                    bit_rate_value_minus1: 62499,
+                    cpb_size_value_minus1: 249999,
+                    cbr_flag: true,
+                },
+            ],
+            initial_cpb_removal_delay_length_minus1: 23,
+            cpb_removal_delay_length_minus1: 23,
+            dpb_output_delay_length_minus1: 23,
+            time_offset_length: 24,
+        };
+        let vui_parameters = sps.vui_parameters.as_mut().unwrap();
+        vui_parameters.aspect_ratio = Some(AspectRatio::ExtendedSar {
+            sar_width: 4,
+            sar_height: 3,
+        });
+        vui_parameters.nal_hrd_parameters = Some(hrd_parameters.clone());
+        vui_parameters.vcl_hrd_parameters = Some(hrd_parameters.clone());
+        vui_parameters.low_delay_hrd_flag = true;
+
+        let rbsp_reencode = sps.to_rbsp();
+        let sps_reencode = Sps::read(&mut BitReader::endian(rbsp_reencode.as_slice(), BigEndian)).unwrap();
+        assert_eq!(sps, sps_reencode);
+        assert_eq!(rbsp_reencode, sps_reencode.to_rbsp());
+
+        // Extended_SAR needs its sar_width and sar_height
+        let mut rbsp = Vec::new();
+        let err = AspectRatio::Idc(255).write(&mut BitWriter::endian(&mut rbsp, BigEndian)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // cpb_cnt_minus1 is 0 to 31
+        for count in [0, 33] {
+            let cpb_specs = vec![hrd_parameters.cpb_specs[0].clone(); count];
+            let hrd_parameters = HrdParameters { cpb_specs, ..hrd_parameters.clone() };
+            let err = hrd_parameters.write(&mut BitWriter::endian(&mut rbsp, BigEndian)).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
 }
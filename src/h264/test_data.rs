@@ -0,0 +1,28 @@
+//! Parameter sets and helpers shared by the tests
+
+use crate::h264::{NALUnitType, NalUnit, Sps};
+use bitstream_io::{BigEndian, BitReader};
+
+/// RBSP of a 1080p high profile SPS
+pub const SPS_RBSP: &[u8] = &[
+    100, 0, 40, 172, 180, 3, 192, 17, 63, 44, 32, 0, 0, 0, 32, 0, 0, 6, 1, 227, 6, 84,
+];
+
+/// RBSP of a PPS referring to `SPS_RBSP`, CAVLC
+pub const PPS_RBSP: &[u8] = &[0xce, 0x38, 0x80];
+
+pub fn nal_unit(bytes: &[u8]) -> NalUnit {
+    NalUnit::from_bytes(bytes).unwrap()
+}
+
+pub fn test_sps() -> Sps {
+    Sps::read(&mut BitReader::endian(SPS_RBSP, BigEndian)).unwrap()
+}
+
+pub fn sps_nal_unit() -> NalUnit {
+    NalUnit::new(3, NALUnitType::Sps, SPS_RBSP.to_vec())
+}
+
+pub fn pps_nal_unit() -> NalUnit {
+    NalUnit::new(3, NALUnitType::Pps, PPS_RBSP.to_vec())
+}
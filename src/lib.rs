@@ -1,4 +1,11 @@
 pub mod h264;
+pub mod mp4;
+pub mod mux;
+pub mod output;
+pub mod rtp;
+pub mod rtsp;
+pub mod player;
+pub mod engine;
 pub mod beat_predictor;
 pub mod fps_loop;
 pub mod thumbnail_server;
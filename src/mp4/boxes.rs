@@ -0,0 +1,129 @@
+use crate::h264::ParseError;
+use bitstream_io::{BigEndian, BitRead, BitReader};
+
+pub type BoxType = [u8; 4];
+
+/// Version, flags and a reader over the rest of a full box
+pub type FullBox<'a> = (u8, u32, BitReader<&'a [u8], BigEndian>);
+
+/// An ISO base media file format box, only the payload after the header is kept
+#[derive(Clone, Copy, Debug)]
+pub struct Mp4Box<'a> {
+    pub box_type: BoxType,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Mp4Box<'a> {
+    /// The boxes contained in this box
+    pub fn children(&self) -> BoxIterator<'a> {
+        BoxIterator::new(self.payload)
+    }
+
+    /// Like `children`, for boxes which have a header of `skip` bytes before their children
+    pub fn children_after(&self, skip: usize) -> Result<BoxIterator<'a>, ParseError> {
+        let payload = self.payload.get(skip..).ok_or(ParseError::InvalidData)?;
+        Ok(BoxIterator::new(payload))
+    }
+
+    /// First child box of the given type
+    pub fn child(&self, box_type: &BoxType) -> Result<Option<Mp4Box<'a>>, ParseError> {
+        find_box(self.payload, box_type)
+    }
+
+    /// First child box of the given type, missing boxes are an error
+    pub fn expect_child(&self, box_type: &BoxType) -> Result<Mp4Box<'a>, ParseError> {
+        self.child(box_type)?.ok_or(ParseError::InvalidData)
+    }
+
+    /// Reader over the payload of a full box, after version and flags
+    pub fn full_box_reader(&self) -> Result<FullBox<'a>, ParseError> {
+        let mut reader = BitReader::endian(self.payload, BigEndian);
+        let version = reader.read(8)?;
+        let flags = reader.read(24)?;
+        Ok((version, flags, reader))
+    }
+}
+
+/// Iterates over the boxes stored one after another in `data`
+pub struct BoxIterator<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BoxIterator<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn read_box(&mut self) -> Result<Mp4Box<'a>, ParseError> {
+        let mut reader = BitReader::endian(self.data, BigEndian);
+        let size: u32 = reader.read(32)?;
+        let mut box_type = [0; 4];
+        reader.read_bytes(&mut box_type)?;
+
+        let (header_size, size) = match size {
+            // Box extends to the end of the file
+            0 => (8, self.data.len() as u64),
+            1 => (16, reader.read::<u64>(64)?),
+            size => (8, size as u64),
+        };
+        if size < header_size || size > self.data.len() as u64 {
+            return Err(ParseError::InvalidData);
+        }
+        let size = size as usize;
+
+        let payload = &self.data[header_size as usize..size];
+        self.data = &self.data[size..];
+        Ok(Mp4Box { box_type, payload })
+    }
+}
+
+impl<'a> Iterator for BoxIterator<'a> {
+    type Item = Result<Mp4Box<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let result = self.read_box();
+        if result.is_err() {
+            // Do not try to continue after broken boxes
+            self.data = &[];
+        }
+        Some(result)
+    }
+}
+
+/// First box of the given type in `data`
+pub fn find_box<'a>(data: &'a [u8], box_type: &BoxType) -> Result<Option<Mp4Box<'a>>, ParseError> {
+    for b in BoxIterator::new(data) {
+        let b = b?;
+        if &b.box_type == box_type {
+            return Ok(Some(b));
+        }
+    }
+    Ok(None)
+}
+
+/// Appends a box, `write_payload` appends the payload and the size is filled in afterwards
+pub fn write_box<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, box_type: &BoxType, write_payload: F) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(box_type);
+    write_payload(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Like `write_box`, with the version and flags of a full box
+pub fn write_full_box<F: FnOnce(&mut Vec<u8>)>(
+    out: &mut Vec<u8>,
+    box_type: &BoxType,
+    version: u8,
+    flags: u32,
+    write_payload: F,
+) {
+    write_box(out, box_type, |out| {
+        out.extend_from_slice(&((version as u32) << 24 | flags).to_be_bytes());
+        write_payload(out);
+    });
+}
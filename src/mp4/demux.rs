@@ -0,0 +1,413 @@
+use crate::h264::{split_avcc_nal_units, AvcDecoderConfigurationRecord, NalUnit, ParseError};
+use crate::mp4::{find_box, Mp4Box};
+use bitstream_io::{BigEndian, BitRead, BitReader};
+use std::convert::TryFrom;
+
+/// Where a sample is stored and when it is presented
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    pub decode_time: u64, // In units of the track timescale
+    pub composition_offset: i32,
+    pub is_sync: bool,
+}
+
+/// The first H.264 video track of a MP4/MOV file
+#[derive(Clone, Debug)]
+pub struct Mp4VideoTrack {
+    pub timescale: u32,
+    pub avc_config: AvcDecoderConfigurationRecord,
+    pub samples: Vec<Sample>,
+}
+
+fn read_table<'a, T, F>(table: &Mp4Box<'a>, mut read_entry: F) -> Result<Vec<T>, ParseError>
+where
+    F: FnMut(&mut BitReader<&'a [u8], BigEndian>, u8) -> Result<T, ParseError>,
+{
+    let (version, _flags, mut reader) = table.full_box_reader()?;
+    let entry_count: u32 = reader.read(32)?;
+    // Every entry takes at least 4 bytes, don't trust the count for preallocation
+    if entry_count as usize > table.payload.len() / 4 {
+        return Err(ParseError::InvalidData);
+    }
+    (0..entry_count)
+        .map(|_| read_entry(&mut reader, version))
+        .collect()
+}
+
+/// The sample sizes of the stsz or stz2 box, a constant size is not expanded
+enum SampleSizes {
+    Constant { size: u32, count: usize },
+    Table(Vec<u32>),
+}
+
+impl SampleSizes {
+    fn len(&self) -> usize {
+        match self {
+            SampleSizes::Constant { count, .. } => *count,
+            SampleSizes::Table(sizes) => sizes.len(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len()).map(move |i| match self {
+            SampleSizes::Constant { size, .. } => *size,
+            SampleSizes::Table(sizes) => sizes[i],
+        })
+    }
+}
+
+/// `file_len` bounds the sample count of a constant sample size
+fn read_sample_sizes(stbl: &Mp4Box, file_len: usize) -> Result<SampleSizes, ParseError> {
+    if let Some(stsz) = stbl.child(b"stsz")? {
+        let (_version, _flags, mut reader) = stsz.full_box_reader()?;
+        let sample_size: u32 = reader.read(32)?;
+        let sample_count: u32 = reader.read(32)?;
+        if sample_size != 0 {
+            // All samples have to be in the file
+            if sample_count as u64 * sample_size as u64 > file_len as u64 {
+                return Err(ParseError::InvalidData);
+            }
+            return Ok(SampleSizes::Constant { size: sample_size, count: sample_count as usize });
+        }
+        if sample_count as usize > stsz.payload.len() / 4 {
+            return Err(ParseError::InvalidData);
+        }
+        (0..sample_count).map(|_| Ok(reader.read(32)?)).collect::<Result<_, _>>().map(SampleSizes::Table)
+    } else {
+        let stz2 = stbl.expect_child(b"stz2")?;
+        let (_version, _flags, mut reader) = stz2.full_box_reader()?;
+        reader.skip(24)?;
+        let field_size: u32 = reader.read(8)?;
+        if ![4, 8, 16].contains(&field_size) {
+            return Err(ParseError::InvalidData);
+        }
+        let sample_count: u32 = reader.read(32)?;
+        if sample_count as u64 * field_size as u64 > stz2.payload.len() as u64 * 8 {
+            return Err(ParseError::InvalidData);
+        }
+        (0..sample_count).map(|_| Ok(reader.read(field_size)?)).collect::<Result<_, _>>().map(SampleSizes::Table)
+    }
+}
+
+fn read_chunk_offsets(stbl: &Mp4Box) -> Result<Vec<u64>, ParseError> {
+    if let Some(stco) = stbl.child(b"stco")? {
+        read_table(&stco, |r, _| Ok(r.read::<u32>(32)? as u64))
+    } else {
+        read_table(&stbl.expect_child(b"co64")?, |r, _| Ok(r.read(64)?))
+    }
+}
+
+/// Offsets of all samples, from the sample-to-chunk table
+fn sample_offsets(stbl: &Mp4Box, sizes: &SampleSizes) -> Result<Vec<u64>, ParseError> {
+    let chunk_offsets = read_chunk_offsets(stbl)?;
+    // (first_chunk, samples_per_chunk)
+    let sample_to_chunk = read_table(&stbl.expect_child(b"stsc")?, |r, _| {
+        let first_chunk: u32 = r.read(32)?;
+        let samples_per_chunk: u32 = r.read(32)?;
+        let _sample_description_index: u32 = r.read(32)?;
+        Ok((first_chunk, samples_per_chunk))
+    })?;
+
+    let mut offsets = Vec::with_capacity(sizes.len());
+    let mut sizes = sizes.iter();
+    for (i, run) in sample_to_chunk.iter().enumerate() {
+        let (first_chunk, samples_per_chunk) = *run;
+        let last_chunk = match sample_to_chunk.get(i + 1) {
+            Some((next_first_chunk, _)) => *next_first_chunk,
+            None => chunk_offsets.len() as u32 + 1,
+        };
+        if first_chunk == 0 || last_chunk < first_chunk {
+            return Err(ParseError::InvalidData);
+        }
+        for chunk in first_chunk..last_chunk {
+            let mut offset = *chunk_offsets
+                .get(chunk as usize - 1)
+                .ok_or(ParseError::InvalidData)?;
+            for _ in 0..samples_per_chunk {
+                let size = sizes.next().ok_or(ParseError::InvalidData)?;
+                offsets.push(offset);
+                offset = offset.checked_add(size as u64).ok_or(ParseError::InvalidData)?;
+            }
+        }
+    }
+    if sizes.next().is_some() {
+        return Err(ParseError::InvalidData);
+    }
+    Ok(offsets)
+}
+
+/// Expands a run length coded table, (count, value) entries
+fn expand_runs<T: Copy>(runs: &[(u32, T)], len: usize) -> Vec<T> {
+    runs.iter()
+        .flat_map(|(count, value)| std::iter::repeat_n(*value, *count as usize))
+        .take(len)
+        .collect()
+}
+
+fn read_samples(stbl: &Mp4Box, file_len: usize) -> Result<Vec<Sample>, ParseError> {
+    let sizes = read_sample_sizes(stbl, file_len)?;
+    let offsets = sample_offsets(stbl, &sizes)?;
+
+    let time_to_sample = read_table(&stbl.expect_child(b"stts")?, |r, _| {
+        Ok((r.read::<u32>(32)?, r.read::<u32>(32)?))
+    })?;
+    let deltas = expand_runs(&time_to_sample, sizes.len());
+
+    let composition_offsets = match stbl.child(b"ctts")? {
+        Some(ctts) => {
+            let runs = read_table(&ctts, |r, version| {
+                let count = r.read::<u32>(32)?;
+                let offset = if version == 0 {
+                    r.read::<u32>(32)? as i32
+                } else {
+                    r.read_signed::<i32>(32)?
+                };
+                Ok((count, offset))
+            })?;
+            expand_runs(&runs, sizes.len())
+        }
+        None => Vec::new(),
+    };
+
+    // Without a sync sample table all samples are sync samples
+    let sync_samples = match stbl.child(b"stss")? {
+        Some(stss) => Some(read_table(&stss, |r, _| Ok(r.read::<u32>(32)?))?),
+        None => None,
+    };
+
+    let mut decode_time = 0;
+    let samples = sizes
+        .iter()
+        .zip(offsets)
+        .enumerate()
+        .map(|(i, (size, offset))| {
+            let sample = Sample {
+                offset,
+                size,
+                decode_time,
+                composition_offset: composition_offsets.get(i).copied().unwrap_or(0),
+                is_sync: sync_samples
+                    .as_ref()
+                    .is_none_or(|s| s.binary_search(&(i as u32 + 1)).is_ok()),
+            };
+            decode_time += deltas.get(i).copied().unwrap_or(0) as u64;
+            sample
+        })
+        .collect();
+    Ok(samples)
+}
+
+/// The avcC box of the first AVC sample entry in the sample description box
+fn read_avc_config(stsd: &Mp4Box) -> Result<Option<AvcDecoderConfigurationRecord>, ParseError> {
+    // version, flags and entry_count
+    for entry in stsd.children_after(8)? {
+        let entry = entry?;
+        if &entry.box_type != b"avc1" && &entry.box_type != b"avc3" {
+            continue;
+        }
+        // The VisualSampleEntry fields come before the child boxes
+        if let Some(avcc) = entry.children_after(78)?.find_map(|b| match b {
+            Ok(b) if &b.box_type == b"avcC" => Some(Ok(b)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        }) {
+            return Ok(Some(AvcDecoderConfigurationRecord::from_bytes(avcc?.payload)?));
+        }
+    }
+    Ok(None)
+}
+
+impl Mp4VideoTrack {
+    /// Finds the first H.264 video track in a complete MP4/MOV file
+    pub fn read(file: &[u8]) -> Result<Self, ParseError> {
+        let moov = find_box(file, b"moov")?.ok_or(ParseError::InvalidData)?;
+        if moov.child(b"mvex")?.is_some() {
+            // Fragmented files keep their samples in moof boxes
+            return Err(ParseError::Unimplemented);
+        }
+
+        for trak in moov.children() {
+            let trak = trak?;
+            if &trak.box_type != b"trak" {
+                continue;
+            }
+            let mdia = trak.expect_child(b"mdia")?;
+
+            let (_version, _flags, mut hdlr) = mdia.expect_child(b"hdlr")?.full_box_reader()?;
+            let _pre_defined: u32 = hdlr.read(32)?;
+            let mut handler_type = [0; 4];
+            hdlr.read_bytes(&mut handler_type)?;
+            if &handler_type != b"vide" {
+                continue;
+            }
+
+            let stbl = mdia.expect_child(b"minf")?.expect_child(b"stbl")?;
+            let avc_config = match read_avc_config(&stbl.expect_child(b"stsd")?)? {
+                Some(avc_config) => avc_config,
+                None => continue,
+            };
+
+            let (version, _flags, mut mdhd) = mdia.expect_child(b"mdhd")?.full_box_reader()?;
+            // creation_time and modification_time
+            mdhd.skip(if version == 1 { 128 } else { 64 })?;
+            let timescale = mdhd.read(32)?;
+
+            return Ok(Self {
+                timescale,
+                avc_config,
+                samples: read_samples(&stbl, file.len())?,
+            });
+        }
+        Err(ParseError::InvalidData)
+    }
+
+    /// The data of a sample, a sequence of length prefixed NAL units
+    pub fn sample_data<'a>(&self, file: &'a [u8], sample: &Sample) -> Result<&'a [u8], ParseError> {
+        let start = usize::try_from(sample.offset).map_err(|_| ParseError::InvalidData)?;
+        let end = start.checked_add(sample.size as usize).ok_or(ParseError::InvalidData)?;
+        file.get(start..end).ok_or(ParseError::InvalidData)
+    }
+
+    /// All NAL units of the track, starting with the parameter sets from the avcC box
+    pub fn nal_units<'a>(&'a self, file: &'a [u8]) -> impl Iterator<Item = Result<NalUnit, ParseError>> + 'a {
+        let parameter_sets = self
+            .avc_config
+            .sequence_parameter_sets
+            .iter()
+            .chain(&self.avc_config.picture_parameter_sets)
+            .map(|data| NalUnit::from_bytes(data));
+
+        let length_size = self.avc_config.length_size();
+        let samples = self.samples.iter().flat_map(move |sample| {
+            let nal_units: Vec<Result<NalUnit, ParseError>> = match self.sample_data(file, sample) {
+                Ok(data) => split_avcc_nal_units(data, length_size)
+                    .map(|nal| nal.and_then(NalUnit::from_bytes))
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+            nal_units
+        });
+
+        parameter_sets.chain(samples)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::NALUnitType;
+
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn full_box(box_type: &[u8; 4], version: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![version, 0, 0, 0];
+        data.extend_from_slice(payload);
+        mp4_box(box_type, &data)
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect()
+    }
+
+    /// A file with three samples, two in the first chunk and one in the second
+    fn test_file() -> Vec<u8> {
+        let sps: &[u8] = &[0x67, 0x64, 0x00, 0x28];
+        let pps: &[u8] = &[0x68, 0xce, 0x38, 0x80];
+        let mut avcc = vec![1, 0x64, 0x00, 0x28, 0xff, 0xe1, 0x00, 0x04];
+        avcc.extend_from_slice(sps);
+        avcc.extend_from_slice(&[1, 0x00, 0x04]);
+        avcc.extend_from_slice(pps);
+
+        let mut avc1 = vec![0; 78];
+        avc1.extend(mp4_box(b"avcC", &avcc));
+        let mut stsd = u32s(&[1]);
+        stsd.extend(mp4_box(b"avc1", &avc1));
+
+        // Two NAL units in the first sample, one in the others
+        let samples: Vec<Vec<u8>> = vec![
+            vec![0, 0, 0, 2, 0x06, 0x80, 0, 0, 0, 3, 0x65, 0x88, 0x80],
+            vec![0, 0, 0, 3, 0x41, 0x9a, 0x80],
+            vec![0, 0, 0, 6, 0x01, 0x9e, 0x00, 0x00, 0x03, 0x01],
+        ];
+
+        let mdat_payload_offset = 8;
+        let chunk_offsets = [
+            mdat_payload_offset,
+            mdat_payload_offset + samples[0].len() as u32 + samples[1].len() as u32,
+        ];
+        let sizes: Vec<u32> = samples.iter().map(|s| s.len() as u32).collect();
+
+        let mut stbl = full_box(b"stsd", 0, &stsd);
+        stbl.extend(full_box(b"stts", 0, &u32s(&[2, 1, 512, 2, 256])));
+        stbl.extend(full_box(b"ctts", 0, &u32s(&[1, 3, 512])));
+        stbl.extend(full_box(b"stss", 0, &u32s(&[1, 1])));
+        stbl.extend(full_box(b"stsc", 0, &u32s(&[2, 1, 2, 1, 2, 1, 1])));
+        stbl.extend(full_box(b"stsz", 0, &u32s(&[&[0, 3][..], &sizes].concat())));
+        stbl.extend(full_box(b"stco", 0, &u32s(&[&[2][..], &chunk_offsets].concat())));
+
+        let mut hdlr = u32s(&[0]);
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend(vec![0; 13]);
+        let mut mdia = full_box(b"mdhd", 0, &u32s(&[0, 0, 12800, 1024, 0]));
+        mdia.extend(full_box(b"hdlr", 0, &hdlr));
+        mdia.extend(mp4_box(b"minf", &mp4_box(b"stbl", &stbl)));
+        let moov = mp4_box(b"moov", &mp4_box(b"trak", &mp4_box(b"mdia", &mdia)));
+
+        let mut file = mp4_box(b"mdat", &samples.concat());
+        file.extend(moov);
+        file
+    }
+
+    #[test]
+    fn test_read_video_track() {
+        let file = test_file();
+        let track = Mp4VideoTrack::read(&file).unwrap();
+        assert_eq!(track.timescale, 12800);
+        assert_eq!(track.avc_config.length_size(), 4);
+        assert_eq!(track.samples.len(), 3);
+        assert_eq!(track.samples[1].offset, 8 + 13);
+        assert_eq!(track.samples[2].offset, 8 + 13 + 7);
+        let decode_times: Vec<_> = track.samples.iter().map(|s| s.decode_time).collect();
+        assert_eq!(decode_times, vec![0, 512, 768]);
+        let sync: Vec<_> = track.samples.iter().map(|s| s.is_sync).collect();
+        assert_eq!(sync, vec![true, false, false]);
+
+        let nal_units: Vec<_> = track.nal_units(&file).map(|n| n.unwrap()).collect();
+        let types: Vec<_> = nal_units.iter().map(|n| n.nal_unit_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                NALUnitType::Sps,
+                NALUnitType::Pps,
+                NALUnitType::Sei,
+                NALUnitType::CodedSliceIdr,
+                NALUnitType::CodedSliceNonIdr,
+                NALUnitType::CodedSliceNonIdr,
+            ]
+        );
+        // Emulation prevention is removed
+        assert_eq!(nal_units[5].rbsp(), &[0x9e, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let file = test_file();
+        assert!(Mp4VideoTrack::read(&file[..file.len() - 10]).is_err());
+
+        // A constant sample size with a sample count far beyond the file
+        let stbl = mp4_box(b"stbl", &full_box(b"stsz", 0, &u32s(&[3, 0xffffffff])));
+        let stbl = find_box(&stbl, b"stbl").unwrap().unwrap();
+        assert!(read_sample_sizes(&stbl, file.len()).is_err());
+
+        let mut track = Mp4VideoTrack::read(&file).unwrap();
+        track.samples[0].offset = u64::MAX;
+        assert!(track.sample_data(&file, &track.samples[0]).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod boxes;
+pub mod demux;
+
+pub use boxes::*;
+pub use demux::*;
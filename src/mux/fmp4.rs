@@ -0,0 +1,268 @@
+use crate::h264::{AvcDecoderConfigurationRecord, NALUnitType, NalUnit, Sps};
+use crate::mp4::{write_box, write_full_box};
+use crate::mux::{ticks_90khz, Muxer};
+use bitstream_io::{BigEndian, BitReader};
+use std::io::{self, Write};
+use std::time::Duration;
+
+const TIMESCALE: u32 = 90000; // Same as ticks_90khz
+const TRACK_ID: u32 = 1;
+const LENGTH_SIZE: u8 = 4;
+
+// Sample flags of the trun box, see ISO/IEC 14496-12 8.8.3.1
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000; // sample_depends_on = 2
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000; // sample_depends_on = 1, sample_is_non_sync_sample
+
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Fragmented MP4 with one fragment per picture.
+///
+/// Uses the avc3 sample entry so that parameter sets can change in band when switching videos.
+/// The init segment is written with the first access unit, which has to contain an SPS and PPS.
+/// Sample durations are estimated from the previous picture, players use the decode time in
+/// the tfdt box for timing.
+pub struct FragmentedMp4Muxer<W: Write> {
+    writer: W,
+    sequence_number: u32,
+    last_decode_time: Option<u64>,
+    last_duration: u32,
+    pending_nal_units: Vec<NalUnit>,
+}
+
+impl<W: Write> FragmentedMp4Muxer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            sequence_number: 1,
+            last_decode_time: None,
+            last_duration: TIMESCALE / 30,
+            pending_nal_units: Vec::new(),
+        }
+    }
+
+    fn write_init_segment(&mut self, nal_units: &[NalUnit]) -> io::Result<()> {
+        let sps_nal_unit = nal_units
+            .iter()
+            .find(|n| n.nal_unit_type == NALUnitType::Sps)
+            .ok_or_else(|| invalid_input("first access unit has no SPS"))?;
+        let pps: Vec<NalUnit> = nal_units
+            .iter()
+            .filter(|n| n.nal_unit_type == NALUnitType::Pps)
+            .cloned()
+            .collect();
+        let sps = Sps::read(&mut BitReader::endian(sps_nal_unit.rbsp(), BigEndian))
+            .map_err(|_| invalid_input("failed to parse SPS"))?;
+        let avc_config = AvcDecoderConfigurationRecord::new(sps_nal_unit, &pps, LENGTH_SIZE)
+            .map_err(|_| invalid_input("failed to build avcC"))?
+            .to_bytes()?;
+        let (width, height) = sps.dimensions();
+
+        let mut out = Vec::new();
+        write_box(&mut out, b"ftyp", |out| {
+            out.extend_from_slice(b"iso6");
+            out.extend_from_slice(&0u32.to_be_bytes());
+            out.extend_from_slice(b"iso6avc1mp41");
+        });
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                put_u32s(out, &[0, 0, TIMESCALE, 0]); // times and duration
+                put_u32s(out, &[0x0001_0000]); // rate
+                out.extend_from_slice(&[0x01, 0x00]); // volume
+                out.extend_from_slice(&[0; 10]);
+                put_u32s(out, &UNITY_MATRIX);
+                out.extend_from_slice(&[0; 24]);
+                put_u32s(out, &[TRACK_ID + 1]); // next_track_ID
+            });
+            write_box(out, b"trak", |out| {
+                // Track enabled and in movie
+                write_full_box(out, b"tkhd", 0, 0x3, |out| {
+                    put_u32s(out, &[0, 0, TRACK_ID, 0, 0, 0, 0]);
+                    out.extend_from_slice(&[0; 8]); // layer, alternate_group, volume
+                    put_u32s(out, &UNITY_MATRIX);
+                    put_u32s(out, &[width << 16, height << 16]);
+                });
+                write_box(out, b"mdia", |out| {
+                    write_full_box(out, b"mdhd", 0, 0, |out| {
+                        put_u32s(out, &[0, 0, TIMESCALE, 0]);
+                        out.extend_from_slice(&[0x55, 0xc4, 0, 0]); // language "und"
+                    });
+                    write_full_box(out, b"hdlr", 0, 0, |out| {
+                        put_u32s(out, &[0]);
+                        out.extend_from_slice(b"vide");
+                        out.extend_from_slice(&[0; 12]);
+                        out.extend_from_slice(b"h264_glitcher\0");
+                    });
+                    write_box(out, b"minf", |out| {
+                        write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                        write_box(out, b"dinf", |out| {
+                            write_full_box(out, b"dref", 0, 0, |out| {
+                                put_u32s(out, &[1]);
+                                // Media data is in the same file
+                                write_full_box(out, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        write_box(out, b"stbl", |out| {
+                            write_full_box(out, b"stsd", 0, 0, |out| {
+                                put_u32s(out, &[1]);
+                                write_box(out, b"avc3", |out| {
+                                    out.extend_from_slice(&[0; 6]);
+                                    out.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+                                    out.extend_from_slice(&[0; 16]);
+                                    out.extend_from_slice(&(width as u16).to_be_bytes());
+                                    out.extend_from_slice(&(height as u16).to_be_bytes());
+                                    put_u32s(out, &[0x0048_0000, 0x0048_0000, 0]); // 72 dpi
+                                    out.extend_from_slice(&1u16.to_be_bytes()); // frame_count
+                                    out.extend_from_slice(&[0; 32]); // compressorname
+                                    out.extend_from_slice(&[0x00, 0x18, 0xff, 0xff]); // depth, pre_defined
+                                    write_box(out, b"avcC", |out| out.extend_from_slice(&avc_config));
+                                });
+                            });
+                            // The sample tables are empty, all samples are in fragments
+                            write_full_box(out, b"stts", 0, 0, |out| put_u32s(out, &[0]));
+                            write_full_box(out, b"stsc", 0, 0, |out| put_u32s(out, &[0]));
+                            write_full_box(out, b"stsz", 0, 0, |out| put_u32s(out, &[0, 0]));
+                            write_full_box(out, b"stco", 0, 0, |out| put_u32s(out, &[0]));
+                        });
+                    });
+                });
+            });
+            write_box(out, b"mvex", |out| {
+                write_full_box(out, b"trex", 0, 0, |out| put_u32s(out, &[TRACK_ID, 1, 0, 0, 0]));
+            });
+        });
+        self.writer.write_all(&out)
+    }
+
+    fn write_fragment(&mut self, nal_units: &[NalUnit], decode_time: u64, duration: u32) -> io::Result<()> {
+        let is_sync = nal_units.iter().any(|n| n.nal_unit_type == NALUnitType::CodedSliceIdr);
+        let mut sample = Vec::new();
+        for nal_unit in nal_units {
+            let bytes = nal_unit.to_bytes();
+            sample.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            sample.extend_from_slice(&bytes);
+        }
+
+        let mut out = Vec::new();
+        let mut data_offset_pos = 0;
+        write_box(&mut out, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| put_u32s(out, &[self.sequence_number]));
+            write_box(out, b"traf", |out| {
+                // default-base-is-moof
+                write_full_box(out, b"tfhd", 0, 0x02_0000, |out| put_u32s(out, &[TRACK_ID]));
+                write_full_box(out, b"tfdt", 1, 0, |out| out.extend_from_slice(&decode_time.to_be_bytes()));
+                // data-offset, sample-duration, sample-size and sample-flags present
+                write_full_box(out, b"trun", 0, 0x0701, |out| {
+                    put_u32s(out, &[1]);
+                    data_offset_pos = out.len();
+                    let flags = if is_sync { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS };
+                    put_u32s(out, &[0, duration, sample.len() as u32, flags]);
+                });
+            });
+        });
+        // The sample data starts after the mdat header
+        let data_offset = out.len() as u32 + 8;
+        out[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+        write_box(&mut out, b"mdat", |out| out.extend_from_slice(&sample));
+
+        self.sequence_number += 1;
+        self.writer.write_all(&out)
+    }
+}
+
+fn put_u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+impl<W: Write> Muxer for FragmentedMp4Muxer<W> {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        if self.last_decode_time.is_none() && self.pending_nal_units.is_empty() {
+            self.write_init_segment(nal_units)?;
+        }
+
+        // NAL units without a picture are sent with the next picture
+        self.pending_nal_units.extend_from_slice(nal_units);
+        if !nal_units.iter().any(|n| n.nal_unit_type.is_picture_data()) {
+            return Ok(());
+        }
+
+        // Decode times have to increase, pictures could be sent faster than the clock resolution
+        let mut decode_time = ticks_90khz(pts);
+        if let Some(last_decode_time) = self.last_decode_time {
+            decode_time = u64::max(decode_time, last_decode_time + 1);
+            self.last_duration = (decode_time - last_decode_time) as u32;
+        }
+        self.last_decode_time = Some(decode_time);
+
+        let nal_units = std::mem::take(&mut self.pending_nal_units);
+        self.write_fragment(&nal_units, decode_time, self.last_duration)?;
+        self.writer.flush()
+    }
+
+    /// Init segment and a fragment with the IDR picture if there is one
+    fn join_preamble(&self, nal_units: &[NalUnit], pts: Duration) -> io::Result<Vec<u8>> {
+        let mut preamble = Vec::new();
+        FragmentedMp4Muxer::new(&mut preamble).write_access_unit(nal_units, pts)?;
+        Ok(preamble)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{nal_unit, pps_nal_unit, sps_nal_unit};
+    use crate::mp4::{BoxIterator, Mp4Box};
+    use bitstream_io::BitRead;
+
+    fn boxes(data: &[u8]) -> Vec<Mp4Box<'_>> {
+        BoxIterator::new(data).map(|b| b.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut out = Vec::new();
+        let mut muxer = FragmentedMp4Muxer::new(&mut out);
+        let pps = pps_nal_unit();
+        let idr = nal_unit(&[0x65, 0x88, 0x84]);
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+        muxer.write_access_unit(&[sps_nal_unit(), pps, idr], Duration::from_millis(0)).unwrap();
+        muxer.write_access_unit(std::slice::from_ref(&p), Duration::from_millis(40)).unwrap();
+        // Same time as the previous picture
+        muxer.write_access_unit(&[p], Duration::from_millis(40)).unwrap();
+
+        let top_level = boxes(&out);
+        let types: Vec<_> = top_level.iter().map(|b| &b.box_type).collect();
+        assert_eq!(types, vec![b"ftyp", b"moov", b"moof", b"mdat", b"moof", b"mdat", b"moof", b"mdat"]);
+
+        let tkhd = top_level[1].expect_child(b"trak").unwrap().expect_child(b"tkhd").unwrap();
+        assert_eq!(&tkhd.payload[76..84], &[0x07, 0x80, 0, 0, 0x04, 0x38, 0, 0]); // 1920x1080
+
+        let mut decode_times = Vec::new();
+        for fragment in top_level[2..].chunks(2) {
+            let traf = fragment[0].expect_child(b"traf").unwrap();
+            let (_, _, mut tfdt) = traf.expect_child(b"tfdt").unwrap().full_box_reader().unwrap();
+            decode_times.push(tfdt.read::<u64>(64).unwrap());
+
+            let (_, _, mut trun) = traf.expect_child(b"trun").unwrap().full_box_reader().unwrap();
+            let _sample_count: u32 = trun.read(32).unwrap();
+            let data_offset: u32 = trun.read(32).unwrap();
+            let _duration: u32 = trun.read(32).unwrap();
+            let size: u32 = trun.read(32).unwrap();
+            // The sample fills the mdat
+            assert_eq!(data_offset as usize, fragment[0].payload.len() + 16);
+            assert_eq!(size as usize, fragment[1].payload.len());
+        }
+        assert_eq!(decode_times, vec![0, 3600, 3601]);
+    }
+
+    #[test]
+    fn test_first_access_unit_needs_sps() {
+        let mut muxer = FragmentedMp4Muxer::new(Vec::new());
+        assert!(muxer.write_access_unit(&[nal_unit(&[0x41, 0x9a, 0x02])], Duration::ZERO).is_err());
+    }
+}
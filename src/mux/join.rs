@@ -0,0 +1,207 @@
+use crate::h264::{read_ue, NALUnitType, NalUnit};
+use crate::mux::Muxer;
+use crate::output::JoinPreamble;
+use bitstream_io::{BigEndian, BitRead, BitReader};
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+/// What a consumer joining mid-stream needs to start decoding: the latest SPS and PPS of every
+/// id and optionally the last IDR picture.
+#[derive(Clone, Debug, Default)]
+pub struct JoinState {
+    keep_idr: bool,
+    sps: BTreeMap<u32, NalUnit>,
+    pps: BTreeMap<u32, NalUnit>,
+    idr: Vec<NalUnit>,
+}
+
+fn parameter_set_id(nal_unit: &NalUnit) -> Option<u32> {
+    let mut reader = BitReader::endian(nal_unit.rbsp(), BigEndian);
+    if nal_unit.nal_unit_type == NALUnitType::Sps {
+        // profile_idc, constraint flags and level_idc
+        reader.skip(24).ok()?;
+    }
+    read_ue(&mut reader).ok()
+}
+
+impl JoinState {
+    pub fn new(keep_idr: bool) -> Self {
+        Self {
+            keep_idr,
+            ..Self::default()
+        }
+    }
+
+    /// Remembers the parameter sets and IDR picture of an access unit written to the stream.
+    /// Returns whether the preamble changed.
+    pub fn update(&mut self, nal_units: &[NalUnit]) -> bool {
+        let mut changed = false;
+        for nal_unit in nal_units {
+            let sets = match nal_unit.nal_unit_type {
+                NALUnitType::Sps => &mut self.sps,
+                NALUnitType::Pps => &mut self.pps,
+                _ => continue,
+            };
+            match parameter_set_id(nal_unit) {
+                Some(id) if sets.get(&id).is_none_or(|n| n.rbsp() != nal_unit.rbsp()) => {
+                    sets.insert(id, nal_unit.clone());
+                    changed = true;
+                }
+                Some(_) => {}
+                None => eprintln!("Failed to parse parameter set id of {}", nal_unit),
+            }
+        }
+        if self.keep_idr && nal_units.iter().any(|n| n.nal_unit_type == NALUnitType::CodedSliceIdr) {
+            self.idr = nal_units
+                .iter()
+                .filter(|n| n.nal_unit_type == NALUnitType::CodedSliceIdr)
+                .cloned()
+                .collect();
+            changed = true;
+        }
+        changed
+    }
+
+    pub fn has_parameter_sets(&self) -> bool {
+        !self.sps.is_empty() && !self.pps.is_empty()
+    }
+
+    /// All SPS followed by all PPS
+    pub fn parameter_sets(&self) -> Vec<NalUnit> {
+        self.sps.values().chain(self.pps.values()).cloned().collect()
+    }
+
+    /// Parameter sets and the IDR picture as a single access unit
+    pub fn preamble(&self) -> Vec<NalUnit> {
+        let mut nal_units = self.parameter_sets();
+        nal_units.extend_from_slice(&self.idr);
+        nal_units
+    }
+}
+
+/// Inserts the latest parameter sets into access units when they were not sent for `interval`,
+/// so that receivers which can not be told about new consumers (e.g. UDP) can start decoding.
+pub struct ParameterSetRepeater {
+    interval: Duration,
+    state: JoinState,
+    last_sent: Option<Duration>,
+}
+
+impl ParameterSetRepeater {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            state: JoinState::new(false),
+            last_sent: None,
+        }
+    }
+
+    pub fn process(&mut self, nal_units: &mut Vec<NalUnit>, pts: Duration) {
+        self.state.update(nal_units);
+        if nal_units.iter().any(|n| n.nal_unit_type == NALUnitType::Sps) {
+            self.last_sent = Some(pts);
+            return;
+        }
+        let has_picture = nal_units.iter().any(|n| n.nal_unit_type.is_picture_data());
+        let due = self.last_sent.is_none_or(|t| pts >= t + self.interval);
+        if has_picture && due && self.state.has_parameter_sets() {
+            // Parameter sets have to follow the access unit delimiter
+            let position = nal_units.iter().take_while(|n| n.nal_unit_type == NALUnitType::Aud).count();
+            nal_units.splice(position..position, self.state.parameter_sets());
+            self.last_sent = Some(pts);
+        }
+    }
+}
+
+/// Keeps the join preamble of a byte stream up to date in the output format of the wrapped muxer
+pub struct JoinPreambleMuxer<M: Muxer> {
+    inner: M,
+    state: JoinState,
+    preamble: JoinPreamble,
+}
+
+impl<M: Muxer> JoinPreambleMuxer<M> {
+    pub fn new(inner: M, preamble: JoinPreamble, keep_idr: bool) -> Self {
+        Self {
+            inner,
+            state: JoinState::new(keep_idr),
+            preamble,
+        }
+    }
+}
+
+impl<M: Muxer> Muxer for JoinPreambleMuxer<M> {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        self.inner.write_access_unit(nal_units, pts)?;
+        if self.state.update(nal_units) && self.state.has_parameter_sets() {
+            self.preamble.set(self.inner.join_preamble(&self.state.preamble(), pts)?);
+        }
+        Ok(())
+    }
+
+    fn join_preamble(&self, nal_units: &[NalUnit], pts: Duration) -> io::Result<Vec<u8>> {
+        self.inner.join_preamble(nal_units, pts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{nal_unit, pps_nal_unit};
+    use crate::mux::AnnexBMuxer;
+
+    #[test]
+    fn test_join_state() {
+        let sps = nal_unit(&[0x67, 0x64, 0x00, 0x28, 0x80]);
+        let sps_1 = nal_unit(&[0x67, 0x64, 0x00, 0x28, 0x40]);
+        let pps = pps_nal_unit();
+        let idr = nal_unit(&[0x65, 0x88, 0x84]);
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+
+        let mut state = JoinState::new(true);
+        assert!(state.update(&[sps.clone(), pps.clone(), idr.clone()]));
+        assert!(!state.update(std::slice::from_ref(&p)));
+        assert!(!state.update(std::slice::from_ref(&sps)));
+        assert!(state.update(std::slice::from_ref(&sps_1)));
+        let preamble: Vec<_> = state.preamble().iter().map(NalUnit::to_bytes).collect();
+        let expected: Vec<_> = [sps, sps_1, pps, idr].iter().map(NalUnit::to_bytes).collect();
+        assert_eq!(preamble, expected);
+    }
+
+    #[test]
+    fn test_repeat_parameter_sets() {
+        let sps = nal_unit(&[0x67, 0x64, 0x00, 0x28, 0x80]);
+        let pps = pps_nal_unit();
+        let aud = nal_unit(&[0x09, 0xf0]);
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+        let types = |nal_units: &[NalUnit]| nal_units.iter().map(|n| n.nal_unit_type).collect::<Vec<_>>();
+
+        let mut repeater = ParameterSetRepeater::new(Duration::from_secs(1));
+        let mut au = vec![sps, pps, p.clone()];
+        repeater.process(&mut au, Duration::from_millis(0));
+        assert_eq!(au.len(), 3);
+        let mut au = vec![aud.clone(), p.clone()];
+        repeater.process(&mut au, Duration::from_millis(500));
+        assert_eq!(au.len(), 2);
+        let mut au = vec![aud, p];
+        repeater.process(&mut au, Duration::from_millis(1000));
+        assert_eq!(
+            types(&au),
+            vec![NALUnitType::Aud, NALUnitType::Sps, NALUnitType::Pps, NALUnitType::CodedSliceNonIdr]
+        );
+    }
+
+    #[test]
+    fn test_annex_b_preamble() {
+        let sps = nal_unit(&[0x67, 0x64, 0x00, 0x28, 0x80]);
+        let pps = pps_nal_unit();
+        let preamble = JoinPreamble::default();
+        let mut muxer = JoinPreambleMuxer::new(AnnexBMuxer::new(Vec::new()), preamble.clone(), false);
+        muxer.write_access_unit(&[sps, pps], Duration::ZERO).unwrap();
+        assert_eq!(
+            preamble.get(),
+            vec![0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, 0x80, 0, 0, 0, 1, 0x68, 0xce, 0x38, 0x80, 0, 0, 0, 1]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+pub mod fmp4;
+pub mod join;
+pub mod ts;
+
+pub use fmp4::*;
+pub use join::*;
+pub use ts::*;
+
+use crate::h264::NalUnit;
+use std::io::{self, Write};
+use std::time::Duration;
+
+const START_CODE: &[u8] = &[0x00, 0x00, 0x00, 0x01];
+
+/// Converts a presentation time to the 90 kHz clock used by MPEG formats
+pub(crate) fn ticks_90khz(time: Duration) -> u64 {
+    (time.as_nanos() * 90_000 / 1_000_000_000) as u64
+}
+
+/// Writes the pictures produced by the glitcher in some output format
+pub trait Muxer {
+    /// Writes all NAL units of one access unit.
+    /// `pts` is the presentation time relative to the start of the stream.
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()>;
+
+    /// Output which lets a consumer joining mid-stream decode the following access units,
+    /// `nal_units` are the parameter sets and optionally an IDR picture.
+    fn join_preamble(&self, _nal_units: &[NalUnit], _pts: Duration) -> io::Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+}
+
+impl<M: Muxer + ?Sized> Muxer for Box<M> {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        (**self).write_access_unit(nal_units, pts)
+    }
+
+    fn join_preamble(&self, nal_units: &[NalUnit], pts: Duration) -> io::Result<Vec<u8>> {
+        (**self).join_preamble(nal_units, pts)
+    }
+}
+
+/// Writes every access unit to all muxers
+impl<M: Muxer> Muxer for Vec<M> {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        for muxer in self.iter_mut() {
+            muxer.write_access_unit(nal_units, pts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Raw Annex B byte stream, timestamps are dropped.
+///
+/// A start code is written after every NAL unit instead of before it so that decoders
+/// which wait for the next start code can decode the last picture right away.
+/// Every access unit is passed to the writer at once, so sinks which add consumers between
+/// writes never start them in the middle of a NAL unit.
+pub struct AnnexBMuxer<W: Write> {
+    writer: W,
+    started: bool,
+}
+
+impl<W: Write> AnnexBMuxer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            started: false,
+        }
+    }
+}
+
+impl<W: Write> Muxer for AnnexBMuxer<W> {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], _pts: Duration) -> io::Result<()> {
+        let mut out = Vec::new();
+        if !self.started {
+            out.extend_from_slice(START_CODE);
+        }
+        for nal_unit in nal_units {
+            nal_unit.write_to(&mut out)?;
+            out.extend_from_slice(START_CODE);
+        }
+        self.writer.write_all(&out)?;
+        self.started = true;
+        self.writer.flush()
+    }
+
+    fn join_preamble(&self, nal_units: &[NalUnit], pts: Duration) -> io::Result<Vec<u8>> {
+        // Ends with a start code like the stream written so far
+        let mut preamble = Vec::new();
+        AnnexBMuxer::new(&mut preamble).write_access_unit(nal_units, pts)?;
+        Ok(preamble)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{pps_nal_unit, sps_nal_unit};
+
+    /// Keeps the buffer of every write call
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_annex_b_access_unit_written_at_once() {
+        let mut muxer = AnnexBMuxer::new(Writes::default());
+        let (sps, pps) = (sps_nal_unit(), pps_nal_unit());
+        muxer.write_access_unit(&[sps.clone(), pps.clone()], Duration::ZERO).unwrap();
+        muxer.write_access_unit(std::slice::from_ref(&pps), Duration::ZERO).unwrap();
+
+        let first = [START_CODE, &sps.to_bytes(), START_CODE, &pps.to_bytes(), START_CODE].concat();
+        let second = [pps.to_bytes(), START_CODE.to_vec()].concat();
+        assert_eq!(muxer.writer.0, vec![first, second]);
+    }
+}
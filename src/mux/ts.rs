@@ -0,0 +1,296 @@
+use crate::h264::{NALUnitType, NalUnit};
+use crate::mux::{ticks_90khz, Muxer};
+use std::io::{self, Write};
+use std::time::Duration;
+
+// See ISO/IEC 13818-1 for the transport stream syntax
+
+const PACKET_SIZE: usize = 188;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_ID_VIDEO: u8 = 0xe0;
+
+/// Time between the PCR and the PTS of a picture, gives the decoder some buffer
+const PTS_DELAY: u64 = 90000 / 10;
+/// Maximum time between two repetitions of PAT and PMT
+const PSI_INTERVAL: u64 = 90000 / 10;
+
+/// CRC-32/MPEG-2 used by PSI sections
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A long form PSI section with a single section, version 0
+fn psi_section(table_id: u8, table_id_extension: u16, body: &[u8]) -> Vec<u8> {
+    // Everything after section_length including the CRC
+    let section_length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xb0 | (section_length >> 8) as u8, // section_syntax_indicator, '0', reserved
+        section_length as u8,
+    ];
+    section.extend_from_slice(&table_id_extension.to_be_bytes());
+    section.extend_from_slice(&[0xc1, 0x00, 0x00]); // version 0, current_next_indicator, section numbers
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+fn encode_pts(pts: u64) -> [u8; 5] {
+    [
+        0x21 | ((pts >> 29) & 0x0e) as u8, // '0010' prefix for PTS only
+        (pts >> 22) as u8,
+        ((pts >> 14) & 0xfe) as u8 | 1,
+        (pts >> 7) as u8,
+        ((pts << 1) & 0xfe) as u8 | 1,
+    ]
+}
+
+fn encode_pcr(pcr_base: u64) -> [u8; 6] {
+    [
+        (pcr_base >> 25) as u8,
+        (pcr_base >> 17) as u8,
+        (pcr_base >> 9) as u8,
+        (pcr_base >> 1) as u8,
+        ((pcr_base & 1) << 7) as u8 | 0x7e, // reserved bits, extension is 0
+        0,
+    ]
+}
+
+/// MPEG transport stream with a single H.264 program.
+///
+/// Every access unit is one PES packet which starts with an access unit delimiter and carries
+/// the PCR in its first TS packet. PAT and PMT are repeated before IDR pictures and at least
+/// every 100 ms.
+pub struct TsMuxer<W: Write> {
+    writer: W,
+    pat_continuity_counter: u8,
+    pmt_continuity_counter: u8,
+    video_continuity_counter: u8,
+    last_psi_time: Option<u64>,
+    last_pcr: Option<u64>,
+}
+
+impl<W: Write> TsMuxer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            pat_continuity_counter: 0,
+            pmt_continuity_counter: 0,
+            video_continuity_counter: 0,
+            last_psi_time: None,
+            last_pcr: None,
+        }
+    }
+
+    fn continuity_counter(&mut self, pid: u16) -> &mut u8 {
+        match pid {
+            PAT_PID => &mut self.pat_continuity_counter,
+            PMT_PID => &mut self.pmt_continuity_counter,
+            _ => &mut self.video_continuity_counter,
+        }
+    }
+
+    /// Splits `payload` into TS packets, stuffing the last one with an adaptation field
+    fn write_packets(&mut self, pid: u16, payload: &[u8], pcr: Option<u64>) -> io::Result<()> {
+        let mut out = Vec::with_capacity((payload.len() / 184 + 1) * PACKET_SIZE);
+        let mut remaining = payload;
+        let mut first = true;
+        while first || !remaining.is_empty() {
+            // Adaptation field contents after adaptation_field_length
+            let mut adaptation_field = match pcr {
+                Some(pcr) if first => {
+                    let mut field = vec![0x10]; // PCR_flag
+                    field.extend_from_slice(&encode_pcr(pcr));
+                    Some(field)
+                }
+                _ => None,
+            };
+            let adaptation_field_size = adaptation_field.as_ref().map_or(0, |f| f.len() + 1);
+            let space = PACKET_SIZE - 4 - adaptation_field_size;
+            if remaining.len() < space {
+                let stuffing = space - remaining.len();
+                match &mut adaptation_field {
+                    Some(field) => field.resize(field.len() + stuffing, 0xff),
+                    // A single byte is an empty adaptation field with only the length
+                    None if stuffing == 1 => adaptation_field = Some(Vec::new()),
+                    None => {
+                        let mut field = vec![0xff; stuffing - 1];
+                        field[0] = 0x00; // No flags
+                        adaptation_field = Some(field);
+                    }
+                }
+            }
+            let (chunk, rest) = remaining.split_at(usize::min(space, remaining.len()));
+
+            let continuity_counter = self.continuity_counter(pid);
+            let adaptation_field_control = if adaptation_field.is_some() { 0x30 } else { 0x10 };
+            out.push(0x47);
+            out.push(if first { 0x40 } else { 0x00 } | (pid >> 8) as u8 & 0x1f);
+            out.push(pid as u8);
+            out.push(adaptation_field_control | *continuity_counter);
+            *continuity_counter = (*continuity_counter + 1) & 0x0f;
+            if let Some(field) = adaptation_field {
+                out.push(field.len() as u8);
+                out.extend_from_slice(&field);
+            }
+            out.extend_from_slice(chunk);
+
+            remaining = rest;
+            first = false;
+        }
+        self.writer.write_all(&out)
+    }
+
+    fn write_psi(&mut self) -> io::Result<()> {
+        let mut pat = vec![0x00]; // pointer_field
+        pat.extend(psi_section(
+            0x00,
+            1, // transport_stream_id
+            &[
+                (PROGRAM_NUMBER >> 8) as u8,
+                PROGRAM_NUMBER as u8,
+                0xe0 | (PMT_PID >> 8) as u8,
+                PMT_PID as u8,
+            ],
+        ));
+        self.write_packets(PAT_PID, &pat, None)?;
+
+        let mut pmt = vec![0x00];
+        pmt.extend(psi_section(
+            0x02,
+            PROGRAM_NUMBER,
+            &[
+                0xe0 | (VIDEO_PID >> 8) as u8, // PCR_PID
+                VIDEO_PID as u8,
+                0xf0, // program_info_length 0
+                0x00,
+                STREAM_TYPE_H264,
+                0xe0 | (VIDEO_PID >> 8) as u8,
+                VIDEO_PID as u8,
+                0xf0, // ES_info_length 0
+                0x00,
+            ],
+        ));
+        self.write_packets(PMT_PID, &pmt, None)
+    }
+}
+
+impl<W: Write> Muxer for TsMuxer<W> {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        // Pictures could be sent faster than the clock resolution
+        let mut pcr = ticks_90khz(pts);
+        if let Some(last_pcr) = self.last_pcr {
+            pcr = u64::max(pcr, last_pcr + 1);
+        }
+        self.last_pcr = Some(pcr);
+        let pts = pcr + PTS_DELAY;
+
+        let is_idr = nal_units.iter().any(|n| n.nal_unit_type == NALUnitType::CodedSliceIdr);
+        if is_idr || self.last_psi_time.is_none_or(|t| pcr >= t + PSI_INTERVAL) {
+            self.write_psi()?;
+            self.last_psi_time = Some(pcr);
+        }
+
+        // PES header with unbounded length, data_alignment_indicator and PTS
+        let mut pes = vec![0x00, 0x00, 0x01, STREAM_ID_VIDEO, 0x00, 0x00, 0x84, 0x80, 0x05];
+        pes.extend_from_slice(&encode_pts(pts));
+        // Every access unit has to start with an access unit delimiter
+        if nal_units.first().map(|n| n.nal_unit_type) != Some(NALUnitType::Aud) {
+            pes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x09, 0xf0]); // primary_pic_type 7
+        }
+        for nal_unit in nal_units {
+            pes.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            nal_unit.write_to(&mut pes)?;
+        }
+
+        self.write_packets(VIDEO_PID, &pes, Some(pcr))?;
+        self.writer.flush()
+    }
+
+    fn join_preamble(&self, nal_units: &[NalUnit], pts: Duration) -> io::Result<Vec<u8>> {
+        // Players tolerate the jump of the continuity counters when the stream continues
+        let mut preamble = Vec::new();
+        TsMuxer::new(&mut preamble).write_access_unit(nal_units, pts)?;
+        Ok(preamble)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{nal_unit, sps_nal_unit};
+
+    fn pid(packet: &[u8]) -> u16 {
+        ((packet[1] as u16 & 0x1f) << 8) | packet[2] as u16
+    }
+
+    /// Payload of a packet after the header and adaptation field
+    fn payload(packet: &[u8]) -> &[u8] {
+        if packet[3] & 0x20 != 0 {
+            &packet[5 + packet[4] as usize..]
+        } else {
+            &packet[4..]
+        }
+    }
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut out = Vec::new();
+        let mut muxer = TsMuxer::new(&mut out);
+        let sps = sps_nal_unit();
+        let idr = nal_unit(&[&[0x65, 0x88][..], &[0x42; 400]].concat());
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+        let sps_len = sps.to_bytes().len();
+        muxer.write_access_unit(&[sps, idr], Duration::from_millis(0)).unwrap();
+        muxer.write_access_unit(&[p], Duration::from_millis(40)).unwrap();
+
+        assert_eq!(out.len() % PACKET_SIZE, 0);
+        let packets: Vec<&[u8]> = out.chunks(PACKET_SIZE).collect();
+        assert!(packets.iter().all(|p| p[0] == 0x47));
+        let pids: Vec<u16> = packets.iter().map(|p| pid(p)).collect();
+        assert_eq!(pids, vec![PAT_PID, PMT_PID, VIDEO_PID, VIDEO_PID, VIDEO_PID, VIDEO_PID]);
+
+        // The CRC over a section including its CRC is 0
+        let pat = &payload(packets[0])[1..];
+        assert_eq!(crc32_mpeg2(&pat[..3 + pat[2] as usize]), 0);
+        let pmt = &payload(packets[1])[1..];
+        assert_eq!(crc32_mpeg2(&pmt[..3 + pmt[2] as usize]), 0);
+
+        let video: Vec<&[u8]> = packets[2..].to_vec();
+        let continuity_counters: Vec<u8> = video.iter().map(|p| p[3] & 0x0f).collect();
+        assert_eq!(continuity_counters, vec![0, 1, 2, 3]);
+
+        // The second picture fits into a single packet with PCR
+        assert_eq!(video[3][1] & 0x40, 0x40);
+        assert_eq!(video[3][5] & 0x10, 0x10);
+        let pes = payload(video[3]);
+        assert_eq!(&pes[..4], &[0x00, 0x00, 0x01, STREAM_ID_VIDEO]);
+        assert_eq!(&pes[9..14], &encode_pts(3600 + PTS_DELAY));
+        assert_eq!(&pes[14..], &[0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x41, 0x9a, 0x02]);
+
+        // The first picture is split over three packets
+        let first: Vec<u8> = video[..3].iter().flat_map(|p| payload(p).to_vec()).collect();
+        assert_eq!(first.len(), 14 + 6 + 4 + sps_len + 4 + 402);
+    }
+}
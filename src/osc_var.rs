@@ -50,15 +50,29 @@ impl<T : PartialEq + OscValue + Copy + OscValue<Target = T>> OscVar<T> {
         self.changed_incoming = false;
     }
 
-    pub fn send(&mut self, socket: &UdpSocket, client_addr: &SocketAddr) {
-        let msg_buf = encoder::encode(&OscPacket::Message(OscMessage {
+    pub fn to_message(&self) -> OscMessage {
+        OscMessage {
             addr: self.address.clone(),
             args: self.value.to_args(),
-        })).unwrap();
+        }
+    }
+
+    pub fn send(&mut self, socket: &UdpSocket, client_addr: &SocketAddr) {
+        let msg_buf = encoder::encode(&OscPacket::Message(self.to_message())).unwrap();
         socket.send_to(&msg_buf, client_addr).unwrap();
         self.changed_outgoing = false;
     }
 
+    /// Returns the message to send if the var changed since the last call
+    pub fn take_changed(&mut self) -> Option<OscMessage> {
+        if self.changed_outgoing {
+            self.changed_outgoing = false;
+            Some(self.to_message())
+        } else {
+            None
+        }
+    }
+
     pub fn send_if_changed(&mut self, socket: &UdpSocket, client_addr: &SocketAddr) {
         if self.changed_outgoing {
             self.send(socket, client_addr);
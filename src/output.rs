@@ -0,0 +1,477 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Destination of the output stream
+pub trait OutputSink: Write + Send {}
+
+impl<W: Write + Send> OutputSink for W {}
+
+/// An output as given on the command line.
+///
+/// `-` or `stdout`, `file:PATH`, `tcp:LISTEN_ADDR`, `udp:ADDR` or `fifo:PATH`
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputSpec {
+    Stdout,
+    File(PathBuf),
+    TcpServer(String),
+    Udp(String),
+    Fifo(PathBuf),
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" || s == "stdout" {
+            return Ok(Self::Stdout);
+        }
+        match s.split_once(':') {
+            Some(("file", path)) => Ok(Self::File(path.into())),
+            Some(("tcp", addr)) => Ok(Self::TcpServer(addr.to_string())),
+            Some(("udp", addr)) => Ok(Self::Udp(addr.to_string())),
+            Some(("fifo", path)) => Ok(Self::Fifo(path.into())),
+            _ => Err(format!(
+                "Invalid output {}, expected -, file:PATH, tcp:LISTEN_ADDR, udp:ADDR or fifo:PATH",
+                s
+            )),
+        }
+    }
+}
+
+impl OutputSpec {
+    /// `preamble` is sent to consumers which connect while the stream is running
+    pub fn open(&self, preamble: &JoinPreamble) -> io::Result<Box<dyn OutputSink>> {
+        Ok(match self {
+            Self::Stdout => Box::new(io::stdout()),
+            Self::File(path) => Box::new(File::create(path)?),
+            Self::TcpServer(addr) => Box::new(TcpServerSink::bind_with_preamble(addr, preamble.clone())?),
+            Self::Udp(addr) => Box::new(UdpSink::connect(addr)?),
+            Self::Fifo(path) => Box::new(FifoSink::open(path, preamble.clone())?),
+        })
+    }
+}
+
+/// Bytes a consumer needs before it can join the running stream, e.g. parameter sets.
+/// Updated by the muxer and shared with the sinks.
+#[derive(Clone, Default)]
+pub struct JoinPreamble(Arc<Mutex<Vec<u8>>>);
+
+impl JoinPreamble {
+    pub fn set(&self, preamble: Vec<u8>) {
+        *self.0.lock().unwrap() = preamble;
+    }
+
+    pub fn get(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Writes to several sinks. Sinks which fail are dropped, writing only fails once all are gone.
+pub struct MultiSink {
+    sinks: Vec<Box<dyn OutputSink>>,
+}
+
+impl MultiSink {
+    pub fn new(sinks: Vec<Box<dyn OutputSink>>) -> Self {
+        Self { sinks }
+    }
+
+    pub fn open(specs: &[OutputSpec], preamble: &JoinPreamble) -> io::Result<Self> {
+        Ok(Self::new(specs.iter().map(|spec| spec.open(preamble)).collect::<io::Result<_>>()?))
+    }
+
+    fn for_each_sink<F: FnMut(&mut dyn OutputSink) -> io::Result<()>>(&mut self, mut f: F) -> io::Result<()> {
+        let mut last_error = None;
+        self.sinks.retain_mut(|sink| match f(sink.as_mut()) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Output failed, removing it: {}", e);
+                last_error = Some(e);
+                false
+            }
+        });
+        match last_error {
+            Some(e) if self.sinks.is_empty() => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for MultiSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.for_each_sink(|sink| sink.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.for_each_sink(|sink| sink.flush())
+    }
+}
+
+/// Clients slower than this are disconnected instead of holding up the stream
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Accepts any number of TCP clients and sends the stream to all of them.
+///
+/// Clients receive the join preamble followed by the data written after they connected.
+/// Having no clients is not an error.
+pub struct TcpServerSink {
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+    local_addr: SocketAddr,
+}
+
+impl TcpServerSink {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::bind_with_preamble(addr, JoinPreamble::default())
+    }
+
+    pub fn bind_with_preamble<A: ToSocketAddrs>(addr: A, preamble: JoinPreamble) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        thread::spawn({
+            let clients = clients.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("Failed to accept client: {}", e);
+                            continue;
+                        }
+                    };
+                    // The lock keeps the stream from being written between preamble and adding the client
+                    let mut clients = clients.lock().unwrap();
+                    let setup = stream
+                        .set_write_timeout(Some(TCP_WRITE_TIMEOUT))
+                        .and_then(|_| stream.set_nodelay(true))
+                        .and_then(|_| (&stream).write_all(&preamble.get()));
+                    match setup {
+                        Ok(()) => {
+                            eprintln!("Client connected: {:?}", stream.peer_addr());
+                            clients.push(stream);
+                        }
+                        Err(e) => eprintln!("Failed to set up client: {}", e),
+                    }
+                }
+            }
+        });
+        Ok(Self { clients, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Write for TcpServerSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.clients.lock().unwrap().retain_mut(|client| match client.write_all(buf) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Client disconnected: {:?} {}", client.peer_addr(), e);
+                false
+            }
+        });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Datagram size used for MPEG-TS over UDP, 7 TS packets
+pub const UDP_PAYLOAD_SIZE: usize = 7 * 188;
+
+/// Sends the stream in datagrams of `UDP_PAYLOAD_SIZE` bytes, the rest is sent on flush
+pub struct UdpSink {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+}
+
+impl UdpSink {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket,
+            buffer: Vec::with_capacity(UDP_PAYLOAD_SIZE),
+        })
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        match self.socket.send(&self.buffer) {
+            // Nobody listening on the other side is fine for UDP
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+            result => {
+                result?;
+            }
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl Write for UdpSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = usize::min(buf.len(), UDP_PAYLOAD_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == UDP_PAYLOAD_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a named pipe if there is nothing at `path` yet
+#[cfg(unix)]
+pub fn create_fifo(path: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if !path.exists() {
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // Safety: c_path is a valid null terminated string
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn create_fifo(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "named pipes are only supported on unix"))
+}
+
+/// Opens a named pipe for writing, creating it if it does not exist.
+/// Blocks until a reader opens the pipe.
+pub fn open_fifo(path: &Path) -> io::Result<File> {
+    create_fifo(path)?;
+    eprintln!("Waiting for a reader on {:?}", path);
+    std::fs::OpenOptions::new().write(true).open(path)
+}
+
+const FIFO_RETRY_DELAY: Duration = Duration::from_millis(100);
+const FIFO_MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Named pipe which survives its reader going away, e.g. a crashing player.
+///
+/// The pipe is opened in the background and reopened when the reader disappears. Data written
+/// while there is no reader is dropped. Every new reader gets the join preamble first.
+pub struct FifoSink {
+    path: PathBuf,
+    preamble: JoinPreamble,
+    file: Arc<Mutex<Option<File>>>,
+}
+
+impl FifoSink {
+    pub fn open(path: &Path, preamble: JoinPreamble) -> io::Result<Self> {
+        create_fifo(path)?;
+        let sink = Self {
+            path: path.to_path_buf(),
+            preamble,
+            file: Arc::new(Mutex::new(None)),
+        };
+        sink.wait_for_reader();
+        Ok(sink)
+    }
+
+    fn wait_for_reader(&self) {
+        let path = self.path.clone();
+        let preamble = self.preamble.clone();
+        let file = self.file.clone();
+        thread::spawn(move || {
+            let mut retry_delay = FIFO_RETRY_DELAY;
+            loop {
+                let mut new_file = match open_fifo(&path) {
+                    Ok(new_file) => new_file,
+                    Err(e) => {
+                        // E.g. the pipe was removed or replaced, try again until the sink is dropped
+                        eprintln!("Failed to open {:?}, retrying in {:?}: {}", path, retry_delay, e);
+                        thread::sleep(retry_delay);
+                        retry_delay = (retry_delay * 2).min(FIFO_MAX_RETRY_DELAY);
+                        if Arc::strong_count(&file) == 1 {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                retry_delay = FIFO_RETRY_DELAY;
+                // The lock keeps the stream from being written before the preamble
+                let mut file = file.lock().unwrap();
+                match new_file.write_all(&preamble.get()) {
+                    Ok(()) => {
+                        eprintln!("Reader connected to {:?}", path);
+                        *file = Some(new_file);
+                        return;
+                    }
+                    Err(e) => eprintln!("Reader of {:?} disappeared: {}", path, e),
+                }
+            }
+        });
+    }
+}
+
+impl Write for FifoSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap();
+        if let Some(Err(e)) = file.as_mut().map(|f| f.write_all(buf)) {
+            eprintln!("Reader of {:?} disappeared: {}", self.path, e);
+            *file = None;
+            self.wait_for_reader();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::fs::FileTypeExt;
+
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_output_spec() {
+        assert_eq!("-".parse(), Ok(OutputSpec::Stdout));
+        assert_eq!("file:out.ts".parse(), Ok(OutputSpec::File("out.ts".into())));
+        assert_eq!("tcp:[::]:9000".parse(), Ok(OutputSpec::TcpServer("[::]:9000".into())));
+        assert_eq!("udp:239.0.0.1:1234".parse(), Ok(OutputSpec::Udp("239.0.0.1:1234".into())));
+        assert!("out.ts".parse::<OutputSpec>().is_err());
+    }
+
+    #[test]
+    fn test_multi_sink_drops_failing_sinks() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        struct SharedSink(Arc<Mutex<Vec<u8>>>);
+        impl Write for SharedSink {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut sink = MultiSink::new(vec![Box::new(FailingSink), Box::new(SharedSink(received.clone()))]);
+        sink.write_all(b"abc").unwrap();
+        sink.write_all(b"def").unwrap();
+        assert_eq!(&*received.lock().unwrap(), b"abcdef");
+
+        let mut sink = MultiSink::new(vec![Box::new(FailingSink)]);
+        assert!(sink.write_all(b"abc").is_err());
+    }
+
+    #[test]
+    fn test_tcp_server_sink() {
+        let preamble = JoinPreamble::default();
+        let mut sink = TcpServerSink::bind_with_preamble("127.0.0.1:0", preamble.clone()).unwrap();
+        sink.write_all(b"before").unwrap();
+        preamble.set(b"preamble ".to_vec());
+        let mut client = TcpStream::connect(sink.local_addr()).unwrap();
+        while sink.clients.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        sink.write_all(b"after").unwrap();
+
+        let mut received = [0; 14];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"preamble after");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo_sink_reconnect() {
+        let path = std::env::temp_dir().join(format!("h264_glitcher_test_{}.fifo", std::process::id()));
+        let preamble = JoinPreamble::default();
+        preamble.set(b"P".to_vec());
+        let mut sink = FifoSink::open(&path, preamble).unwrap();
+        let wait_for_reader = |sink: &FifoSink| {
+            while sink.file.lock().unwrap().is_none() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        let mut reader = File::open(&path).unwrap();
+        wait_for_reader(&sink);
+        sink.write_all(b"a").unwrap();
+        let mut received = [0; 2];
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"Pa");
+
+        // Writing without a reader is not an error
+        drop(reader);
+        sink.write_all(b"b").unwrap();
+        sink.write_all(b"c").unwrap();
+
+        let mut reader = File::open(&path).unwrap();
+        wait_for_reader(&sink);
+        sink.write_all(b"d").unwrap();
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"Pd");
+
+        // The pipe can not be opened for a while, e.g. when it was replaced
+        drop(reader);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        sink.write_all(b"e").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        std::fs::remove_dir(&path).unwrap();
+        while !path.metadata().is_ok_and(|metadata| metadata.file_type().is_fifo()) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut reader = File::open(&path).unwrap();
+        wait_for_reader(&sink);
+        sink.write_all(b"f").unwrap();
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"Pf");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_udp_sink_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = UdpSink::connect(receiver.local_addr().unwrap()).unwrap();
+        sink.write_all(&[0x47; UDP_PAYLOAD_SIZE + 10]).unwrap();
+        sink.flush().unwrap();
+
+        let mut buf = [0; 2048];
+        assert_eq!(receiver.recv(&mut buf).unwrap(), UDP_PAYLOAD_SIZE);
+        assert_eq!(receiver.recv(&mut buf).unwrap(), 10);
+    }
+}
@@ -0,0 +1,241 @@
+use serde_json::{json, Value};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Flags for mpv playing the raw h264 stream as fast as it arrives
+pub const MPV_ANNEX_B_ARGS: &[&str] = &["--no-correct-pts", "--fps=1000", "--no-cache"];
+/// Flags for mpv playing a stream with timestamps (fmp4, ts)
+pub const MPV_TIMED_ARGS: &[&str] = &["--no-cache", "--profile=low-latency"];
+
+/// How to run the player and when to consider it locked up
+#[derive(Clone, Debug)]
+pub struct PlayerConfig {
+    pub program: String,
+    /// Arguments before the IPC server option and the input
+    pub args: Vec<String>,
+    /// Stream the player reads, usually a fifo output of the glitcher
+    pub input: PathBuf,
+    /// mpv JSON IPC socket
+    pub ipc_socket: PathBuf,
+    pub check_interval: Duration,
+    /// The player is restarted if its playback time did not advance for this long
+    pub lockup_timeout: Duration,
+}
+
+impl PlayerConfig {
+    pub fn mpv(args: &[&str], input: &Path, ipc_socket: &Path) -> Self {
+        Self {
+            program: "mpv".to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            input: input.to_path_buf(),
+            ipc_socket: ipc_socket.to_path_buf(),
+            check_interval: Duration::from_secs(1),
+            lockup_timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn spawn(&self) -> io::Result<Child> {
+        Command::new(&self.program)
+            .args(&self.args)
+            .arg(format!("--input-ipc-server={}", self.ipc_socket.display()))
+            .arg(&self.input)
+            .spawn()
+    }
+}
+
+/// Sends a command over mpv's JSON IPC and returns the `data` of the reply
+#[cfg(unix)]
+pub fn mpv_command(ipc_socket: &Path, command: &[&str], timeout: Duration) -> io::Result<Value> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    const REQUEST_ID: u64 = 1;
+    let invalid_data = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut stream = UnixStream::connect(ipc_socket)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = json!({ "command": command, "request_id": REQUEST_ID });
+    stream.write_all(format!("{}\n", request).as_bytes())?;
+
+    // Events are sent on the same connection
+    for line in BufReader::new(stream).lines() {
+        let reply: Value = serde_json::from_str(&line?).map_err(|e| invalid_data(e.to_string()))?;
+        if reply["request_id"] != REQUEST_ID {
+            continue;
+        }
+        return match reply["error"].as_str() {
+            Some("success") => Ok(reply["data"].clone()),
+            error => Err(invalid_data(format!("mpv command {:?} failed: {:?}", command, error))),
+        };
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "mpv closed the IPC connection"))
+}
+
+#[cfg(not(unix))]
+pub fn mpv_command(_ipc_socket: &Path, _command: &[&str], _timeout: Duration) -> io::Result<Value> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "mpv IPC is only supported on unix"))
+}
+
+/// Runs the player and restarts it when it exits or locks up.
+///
+/// Health is checked by polling the playback time over the IPC socket. A player which does not
+/// answer or whose playback time stands still for `lockup_timeout` is killed.
+/// The player is stopped when the supervisor is dropped.
+pub struct PlayerSupervisor {
+    config: Arc<PlayerConfig>,
+    restarts: Arc<AtomicU32>,
+    stop: Arc<AtomicBool>,
+}
+
+impl PlayerSupervisor {
+    pub fn spawn(config: PlayerConfig) -> io::Result<Self> {
+        let config = Arc::new(config);
+        let restarts = Arc::new(AtomicU32::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let child = config.spawn()?;
+        thread::spawn({
+            let config = config.clone();
+            let restarts = restarts.clone();
+            let stop = stop.clone();
+            move || supervise(&config, child, &restarts, &stop)
+        });
+        Ok(Self { config, restarts, stop })
+    }
+
+    /// How often the player had to be restarted
+    pub fn restarts(&self) -> u32 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    pub fn command(&self, command: &[&str]) -> io::Result<Value> {
+        mpv_command(&self.config.ipc_socket, command, self.config.check_interval)
+    }
+
+    pub fn toggle_fullscreen(&self) -> io::Result<()> {
+        self.command(&["cycle", "fullscreen"]).map(|_| ())
+    }
+
+    /// Switches between the OSD with playback statistics and no OSD
+    pub fn toggle_osd(&self) -> io::Result<()> {
+        self.command(&["cycle-values", "osd-level", "3", "0"]).map(|_| ())
+    }
+}
+
+impl Drop for PlayerSupervisor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn supervise(config: &PlayerConfig, mut child: Child, restarts: &AtomicU32, stop: &AtomicBool) {
+    let mut last_progress = Instant::now();
+    let mut last_playback_time = None;
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(config.check_interval);
+
+        let problem = match child.try_wait() {
+            Ok(Some(status)) => Some(format!("exited with {}", status)),
+            Err(e) => Some(format!("failed: {}", e)),
+            Ok(None) => {
+                let playback_time = mpv_command(&config.ipc_socket, &["get_property", "playback-time"], config.check_interval)
+                    .ok()
+                    .and_then(|time| time.as_f64());
+                if playback_time.is_some() && playback_time != last_playback_time {
+                    last_playback_time = playback_time;
+                    last_progress = Instant::now();
+                }
+                (last_progress.elapsed() > config.lockup_timeout).then(|| "locked up".to_string())
+            }
+        };
+
+        if let Some(problem) = problem {
+            eprintln!("Player {}, restarting it", problem);
+            let _ = child.kill();
+            let _ = child.wait();
+            child = loop {
+                match config.spawn() {
+                    Ok(child) => break child,
+                    Err(e) => eprintln!("Failed to start player: {}", e),
+                }
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                thread::sleep(config.check_interval);
+            };
+            restarts.fetch_add(1, Ordering::Relaxed);
+            last_progress = Instant::now();
+            last_playback_time = None;
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+
+    fn stub_config(script: &str, name: &str) -> PlayerConfig {
+        let ipc_socket = std::env::temp_dir().join(format!("h264_glitcher_test_{}_{}.sock", name, std::process::id()));
+        PlayerConfig {
+            program: "sh".to_string(),
+            // The IPC option and input are passed as $0 and $1
+            args: vec!["-c".to_string(), script.to_string()],
+            input: "/dev/null".into(),
+            ipc_socket,
+            check_interval: Duration::from_millis(20),
+            lockup_timeout: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_restart_on_crash_and_lockup() {
+        let crashing = PlayerSupervisor::spawn(stub_config("exit 1", "crash")).unwrap();
+        // A player which never answers on the IPC socket counts as locked up
+        let frozen = PlayerSupervisor::spawn(stub_config("sleep 10", "lockup")).unwrap();
+        thread::sleep(Duration::from_millis(400));
+        assert!(crashing.restarts() >= 2);
+        assert!(frozen.restarts() >= 1);
+    }
+
+    #[test]
+    fn test_ipc_commands() {
+        let config = stub_config("sleep 10", "ipc");
+        let _ = std::fs::remove_file(&config.ipc_socket);
+        let listener = UnixListener::bind(&config.ipc_socket).unwrap();
+        let server = thread::spawn(move || {
+            let mut commands = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                let request: Value = serde_json::from_str(&line).unwrap();
+                commands.push(request["command"].clone());
+                let reply = json!({ "event": "playback-restart" }).to_string()
+                    + "\n"
+                    + &json!({ "request_id": request["request_id"], "error": "success", "data": 1.5 }).to_string()
+                    + "\n";
+                (&stream).write_all(reply.as_bytes()).unwrap();
+            }
+            commands
+        });
+
+        let ipc_socket = config.ipc_socket.clone();
+        let player = PlayerSupervisor { config: Arc::new(config), restarts: Arc::default(), stop: Arc::default() };
+        player.toggle_fullscreen().unwrap();
+        assert_eq!(player.command(&["get_property", "playback-time"]).unwrap(), json!(1.5));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![json!(["cycle", "fullscreen"]), json!(["get_property", "playback-time"])]
+        );
+        std::fs::remove_file(ipc_socket).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use crate::h264::NalUnit;
+use crate::mux::{ticks_90khz, Muxer};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+// H.264 RTP payload format, see RFC 6184
+
+const RTP_VERSION: u8 = 2;
+const RTP_HEADER_SIZE: usize = 12;
+/// Dynamic payload type used for H.264
+pub const H264_PAYLOAD_TYPE: u8 = 96;
+/// Keeps packets below the usual ethernet MTU including IP and UDP headers
+pub const DEFAULT_MTU: usize = 1400;
+
+const STAP_A: u8 = 24;
+const FU_A: u8 = 28;
+
+/// Splits access units into RTP packets.
+///
+/// Small NAL units are aggregated into STAP-A packets, NAL units which do not fit into one
+/// packet are fragmented into FU-A packets. The marker bit is set on the last packet of each
+/// access unit.
+pub struct RtpPacketizer {
+    pub payload_type: u8,
+    pub ssrc: u32,
+    pub sequence_number: u16,
+    /// Maximum size of a packet including the RTP header
+    pub mtu: usize,
+}
+
+impl RtpPacketizer {
+    pub fn new(ssrc: u32, initial_sequence_number: u16) -> Self {
+        Self {
+            payload_type: H264_PAYLOAD_TYPE,
+            ssrc,
+            sequence_number: initial_sequence_number,
+            mtu: DEFAULT_MTU,
+        }
+    }
+
+    fn max_payload_size(&self) -> usize {
+        self.mtu - RTP_HEADER_SIZE
+    }
+
+    fn header(&mut self, timestamp: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(self.mtu);
+        packet.push(RTP_VERSION << 6);
+        packet.push(self.payload_type);
+        packet.extend_from_slice(&self.sequence_number.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        packet
+    }
+
+    /// Single NAL unit packet or STAP-A for the aggregated NAL units
+    fn aggregate(&mut self, nal_units: &[Vec<u8>], timestamp: u32, packets: &mut Vec<Vec<u8>>) {
+        if nal_units.is_empty() {
+            return;
+        }
+        let mut packet = self.header(timestamp);
+        match nal_units {
+            [nal] => packet.extend_from_slice(nal),
+            _ => {
+                let forbidden_zero_bit = nal_units.iter().fold(0, |f, nal| f | nal[0] & 0x80);
+                let nal_ref_idc = nal_units.iter().map(|nal| nal[0] & 0x60).max().unwrap_or(0);
+                packet.push(forbidden_zero_bit | nal_ref_idc | STAP_A);
+                for nal in nal_units {
+                    packet.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+                    packet.extend_from_slice(nal);
+                }
+            }
+        }
+        packets.push(packet);
+    }
+
+    fn fragment(&mut self, nal: &[u8], timestamp: u32, packets: &mut Vec<Vec<u8>>) {
+        let fu_indicator = (nal[0] & 0xe0) | FU_A;
+        let nal_unit_type = nal[0] & 0x1f;
+        let chunks: Vec<&[u8]> = nal[1..].chunks(self.max_payload_size() - 2).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let start = if i == 0 { 0x80 } else { 0x00 };
+            let end = if i == chunks.len() - 1 { 0x40 } else { 0x00 };
+            let mut packet = self.header(timestamp);
+            packet.push(fu_indicator);
+            packet.push(start | end | nal_unit_type);
+            packet.extend_from_slice(chunk);
+            packets.push(packet);
+        }
+    }
+
+    /// RTP packets for one access unit, `timestamp` is in the 90 kHz clock
+    pub fn packetize(&mut self, nal_units: &[NalUnit], timestamp: u32) -> Vec<Vec<u8>> {
+        let max_payload_size = self.max_payload_size();
+        let mut packets = Vec::new();
+        let mut aggregated: Vec<Vec<u8>> = Vec::new();
+        let mut aggregated_size = 1; // STAP-A NAL header
+
+        for nal in nal_units.iter().map(NalUnit::to_bytes) {
+            if aggregated_size + 2 + nal.len() > max_payload_size {
+                self.aggregate(&aggregated, timestamp, &mut packets);
+                aggregated.clear();
+                aggregated_size = 1;
+            }
+            if nal.len() > max_payload_size {
+                self.fragment(&nal, timestamp, &mut packets);
+            } else {
+                aggregated_size += 2 + nal.len();
+                aggregated.push(nal);
+            }
+        }
+        self.aggregate(&aggregated, timestamp, &mut packets);
+
+        if let Some(last) = packets.last_mut() {
+            last[1] |= 0x80; // marker
+        }
+        packets
+    }
+}
+
+/// Sends the stream as RTP over UDP to a unicast or multicast address
+pub struct RtpSender {
+    socket: UdpSocket,
+    packetizer: RtpPacketizer,
+    timestamp_offset: u32,
+}
+
+impl RtpSender {
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        // Random initial values as recommended by RFC 3550
+        Ok(Self {
+            socket,
+            packetizer: RtpPacketizer::new(rand::random(), rand::random()),
+            timestamp_offset: rand::random(),
+        })
+    }
+
+    pub fn packetizer_mut(&mut self) -> &mut RtpPacketizer {
+        &mut self.packetizer
+    }
+}
+
+impl Muxer for RtpSender {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        let timestamp = (ticks_90khz(pts) as u32).wrapping_add(self.timestamp_offset);
+        for packet in self.packetizer.packetize(nal_units, timestamp) {
+            match self.socket.send(&packet) {
+                // There is no receiver yet
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{nal_unit, pps_nal_unit};
+
+    #[test]
+    fn test_packetize() {
+        let mut packetizer = RtpPacketizer::new(0x1234_5678, 0xfffe);
+        packetizer.mtu = 112;
+        let sps = nal_unit(&[0x67, 0x64, 0x00, 0x28]);
+        let pps = pps_nal_unit();
+        let idr = nal_unit(&[&[0x65][..], &[0x42; 250]].concat());
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+
+        let packets = packetizer.packetize(&[sps, pps, idr], 1000);
+        assert_eq!(packets.len(), 4);
+        // STAP-A with SPS and PPS
+        assert_eq!(&packets[0][..12], &[0x80, 96, 0xff, 0xfe, 0, 0, 0x03, 0xe8, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(&packets[0][12..], &[0x60 | STAP_A, 0, 4, 0x67, 0x64, 0x00, 0x28, 0, 4, 0x68, 0xce, 0x38, 0x80]);
+        // IDR split into FU-A with 98 bytes each
+        let fu_headers: Vec<_> = packets[1..].iter().map(|p| (p[12], p[13], p.len())).collect();
+        assert_eq!(
+            fu_headers,
+            vec![(0x60 | FU_A, 0x85, 112), (0x60 | FU_A, 0x05, 112), (0x60 | FU_A, 0x45, 14 + 54)]
+        );
+        let sequence_numbers: Vec<_> = packets.iter().map(|p| u16::from_be_bytes([p[2], p[3]])).collect();
+        assert_eq!(sequence_numbers, vec![0xfffe, 0xffff, 0, 1]);
+        let markers: Vec<_> = packets.iter().map(|p| p[1] & 0x80 != 0).collect();
+        assert_eq!(markers, vec![false, false, false, true]);
+
+        // A single small NAL unit is sent as it is
+        let packets = packetizer.packetize(&[p], 4000);
+        assert_eq!(packets, vec![vec![0x80, 0x80 | 96, 0, 2, 0, 0, 0x0f, 0xa0, 0x12, 0x34, 0x56, 0x78, 0x41, 0x9a, 0x02]]);
+    }
+
+    #[test]
+    fn test_send_to_local_receiver() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut sender = RtpSender::new(receiver.local_addr().unwrap()).unwrap();
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+        sender.write_access_unit(std::slice::from_ref(&p), Duration::from_millis(0)).unwrap();
+        sender.write_access_unit(&[p], Duration::from_millis(40)).unwrap();
+
+        let mut buf = [0; 1500];
+        let mut timestamps = Vec::new();
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[12..len], &[0x41, 0x9a, 0x02]);
+            timestamps.push(u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]));
+        }
+        assert_eq!(timestamps[1].wrapping_sub(timestamps[0]), 3600);
+    }
+}
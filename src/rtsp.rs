@@ -0,0 +1,566 @@
+use crate::h264::{NALUnitType, NalUnit};
+use crate::mux::{ticks_90khz, JoinState, Muxer};
+use crate::rtp::{RtpPacketizer, H264_PAYLOAD_TYPE};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Minimal RTSP 1.0 server, see RFC 2326. Only serves the live stream at RTSP_PATH.
+
+pub const RTSP_PATH: &str = "/glitch";
+const TRACK_CONTROL: &str = "trackID=0";
+/// Clients which can not keep up are dropped instead of holding up the stream
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+enum SessionTransport {
+    Udp {
+        rtp_socket: UdpSocket,
+        // Bound so that the advertised server_port pair is valid, RTCP is ignored
+        _rtcp_socket: UdpSocket,
+    },
+    Interleaved {
+        stream: TcpStream,
+        channel: u8,
+    },
+}
+
+struct Session {
+    id: String,
+    connection_id: u64,
+    transport: SessionTransport,
+    packetizer: RtpPacketizer,
+    timestamp_offset: u32,
+    playing: bool,
+    /// Parameter sets and IDR have to be sent before the next access unit
+    needs_preamble: bool,
+}
+
+impl Session {
+    fn is_interleaved(&self) -> bool {
+        matches!(self.transport, SessionTransport::Interleaved { .. })
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match &mut self.transport {
+            SessionTransport::Udp { rtp_socket, .. } => match rtp_socket.send(packet) {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+                result => result.map(|_| ()),
+            },
+            SessionTransport::Interleaved { stream, channel } => {
+                let mut frame = Vec::with_capacity(packet.len() + 4);
+                frame.push(b'$');
+                frame.push(*channel);
+                frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                frame.extend_from_slice(packet);
+                stream.write_all(&frame)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    parameter_sets: Vec<NalUnit>,
+    join_state: JoinState,
+    sessions: Vec<Session>,
+}
+
+/// RTSP server for the glitched stream, players connect to `rtsp://host:port/glitch`.
+///
+/// Supports RTP over UDP and interleaved in the RTSP connection. Every session gets its
+/// own RTP packetizer and starts with the latest parameter sets sent in band.
+/// Cloned handles share the same sessions.
+#[derive(Clone)]
+pub struct RtspServer {
+    shared: Arc<Mutex<Shared>>,
+    local_addr: SocketAddr,
+}
+
+impl RtspServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let next_connection_id = AtomicU64::new(0);
+        thread::spawn({
+            let shared = shared.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("Failed to accept RTSP client: {}", e);
+                            continue;
+                        }
+                    };
+                    let connection_id = next_connection_id.fetch_add(1, Ordering::Relaxed);
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(&stream, &shared, connection_id) {
+                            eprintln!("RTSP connection failed: {}", e);
+                        }
+                        // Sessions end with their connection
+                        shared.lock().unwrap().sessions.retain(|s| s.connection_id != connection_id);
+                    });
+                }
+            }
+        });
+        Ok(Self { shared, local_addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// SPS and PPS announced in the SDP of new clients
+    pub fn set_parameter_sets(&self, nal_units: &[NalUnit]) {
+        self.shared.lock().unwrap().parameter_sets = nal_units
+            .iter()
+            .filter(|n| n.nal_unit_type == NALUnitType::Sps || n.nal_unit_type == NALUnitType::Pps)
+            .cloned()
+            .collect();
+    }
+
+    /// Also send the last IDR picture to new sessions so they can show something right away
+    pub fn set_join_with_idr(&self, keep_idr: bool) {
+        self.shared.lock().unwrap().join_state = JoinState::new(keep_idr);
+    }
+}
+
+impl Muxer for RtspServer {
+    fn write_access_unit(&mut self, nal_units: &[NalUnit], pts: Duration) -> io::Result<()> {
+        let ticks = ticks_90khz(pts) as u32;
+        let mut shared = self.shared.lock().unwrap();
+        let Shared { join_state, sessions, .. } = &mut *shared;
+        let preamble = join_state.has_parameter_sets().then(|| join_state.preamble());
+        sessions.retain_mut(|session| {
+            if !session.playing {
+                return true;
+            }
+            let timestamp = ticks.wrapping_add(session.timestamp_offset);
+            let mut packets = Vec::new();
+            if session.needs_preamble {
+                if let Some(preamble) = &preamble {
+                    // Sent as its own access unit just before the current one
+                    packets = session.packetizer.packetize(preamble, timestamp.wrapping_sub(1));
+                }
+                session.needs_preamble = false;
+            }
+            packets.extend(session.packetizer.packetize(nal_units, timestamp));
+            for packet in packets {
+                if let Err(e) = session.send(&packet) {
+                    eprintln!("Dropping RTSP session {}: {}", session.id, e);
+                    return false;
+                }
+            }
+            true
+        });
+        join_state.update(nal_units);
+        Ok(())
+    }
+}
+
+struct Request {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Session id without parameters like the timeout
+    fn session(&self) -> Option<&str> {
+        self.header("Session").map(|s| s.split(';').next().unwrap_or("").trim())
+    }
+
+    fn path(&self) -> &str {
+        match self.uri.strip_prefix("rtsp://") {
+            Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => &self.uri,
+        }
+    }
+}
+
+/// Reads the next request, skipping interleaved RTCP packets sent by the client
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if buf[0] != b'$' {
+            break;
+        }
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as u64;
+        io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+    }
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, uri) = match (parts.next(), parts.next()) {
+        (Some(method), Some(uri)) => (method.to_string(), uri.to_string()),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid request line")),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let request = Request { method, uri, headers };
+    // Bodies (e.g. of SET_PARAMETER) are not used
+    let content_length = request.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    io::copy(&mut reader.by_ref().take(content_length), &mut io::sink())?;
+    Ok(Some(request))
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<String>,
+    body: String,
+}
+
+impl Response {
+    fn new(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn header(mut self, header: String) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    fn to_bytes(&self, cseq: Option<&str>) -> Vec<u8> {
+        let mut response = format!("RTSP/1.0 {}\r\n", self.status);
+        if let Some(cseq) = cseq {
+            response.push_str(&format!("CSeq: {}\r\n", cseq));
+        }
+        response.push_str("Server: h264_glitcher\r\n");
+        for header in &self.headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        if !self.body.is_empty() {
+            response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        response.push_str("\r\n");
+        response.push_str(&self.body);
+        response.into_bytes()
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn sdp(parameter_sets: &[NalUnit], local_addr: &SocketAddr) -> String {
+    let address_type = if local_addr.is_ipv4() { "IP4" } else { "IP6" };
+    let mut fmtp = "packetization-mode=1".to_string();
+    let sps = parameter_sets.iter().find(|n| n.nal_unit_type == NALUnitType::Sps);
+    if let Some(profile) = sps.and_then(|sps| sps.rbsp().get(..3)) {
+        fmtp.push_str(&format!(";profile-level-id={:02x}{:02x}{:02x}", profile[0], profile[1], profile[2]));
+    }
+    if !parameter_sets.is_empty() {
+        let sprop: Vec<String> = parameter_sets.iter().map(|n| base64(&n.to_bytes())).collect();
+        fmtp.push_str(&format!(";sprop-parameter-sets={}", sprop.join(",")));
+    }
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN {address_type} {ip}\r\n\
+         s=h264_glitcher\r\n\
+         c=IN {address_type} {ip}\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         m=video 0 RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} H264/90000\r\n\
+         a=fmtp:{pt} {fmtp}\r\n\
+         a=control:{track}\r\n",
+        address_type = address_type,
+        ip = local_addr.ip(),
+        pt = H264_PAYLOAD_TYPE,
+        fmtp = fmtp,
+        track = TRACK_CONTROL,
+    )
+}
+
+/// First number of a range like `5000-5001`
+fn transport_parameter(transport: &str, name: &str) -> Option<u16> {
+    transport
+        .split(';')
+        .find_map(|p| p.trim().strip_prefix(name))
+        .and_then(|range| range.split('-').next())
+        .and_then(|first| first.parse().ok())
+}
+
+fn setup(
+    request: &Request,
+    stream: &TcpStream,
+    shared: &Mutex<Shared>,
+    connection_id: u64,
+) -> io::Result<Response> {
+    let transport_header = match request.header("Transport") {
+        Some(transport) => transport,
+        None => return Ok(Response::new("400 Bad Request")),
+    };
+    // Only the first transport the client offers is considered
+    let transport = transport_header.split(',').next().unwrap_or("");
+    let local_ip = stream.local_addr()?.ip();
+
+    let (session_transport, transport_response) = if transport.starts_with("RTP/AVP/TCP") {
+        // The RTCP channel follows the RTP channel
+        let channel = match u8::try_from(transport_parameter(transport, "interleaved=").unwrap_or(0)) {
+            Ok(channel) if channel < u8::MAX => channel,
+            _ => return Ok(Response::new("461 Unsupported Transport")),
+        };
+        let stream = stream.try_clone()?;
+        (
+            SessionTransport::Interleaved { stream, channel },
+            format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel + 1),
+        )
+    } else if transport.contains("multicast") {
+        return Ok(Response::new("461 Unsupported Transport"));
+    } else {
+        let client_port = match transport_parameter(transport, "client_port=") {
+            Some(port) => port,
+            None => return Ok(Response::new("461 Unsupported Transport")),
+        };
+        let rtp_socket = UdpSocket::bind((local_ip, 0))?;
+        let _rtcp_socket = UdpSocket::bind((local_ip, 0))?;
+        rtp_socket.connect((stream.peer_addr()?.ip(), client_port))?;
+        let transport_response = format!(
+            "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+            client_port,
+            client_port as u32 + 1,
+            rtp_socket.local_addr()?.port(),
+            _rtcp_socket.local_addr()?.port(),
+        );
+        (SessionTransport::Udp { rtp_socket, _rtcp_socket }, transport_response)
+    };
+
+    let session = Session {
+        id: format!("{:016X}", rand::random::<u64>()),
+        connection_id,
+        transport: session_transport,
+        packetizer: RtpPacketizer::new(rand::random(), rand::random()),
+        timestamp_offset: rand::random(),
+        playing: false,
+        needs_preamble: false,
+    };
+    let response = Response::new("200 OK")
+        .header(format!("Transport: {};ssrc={:08X}", transport_response, session.packetizer.ssrc))
+        .header(format!("Session: {};timeout=60", session.id));
+    shared.lock().unwrap().sessions.push(session);
+    Ok(response)
+}
+
+fn handle_request(
+    request: &Request,
+    stream: &TcpStream,
+    shared: &Mutex<Shared>,
+    connection_id: u64,
+) -> io::Result<Response> {
+    let path = request.path();
+    let is_stream_path = path == RTSP_PATH || path.strip_prefix(RTSP_PATH).is_some_and(|rest| rest.starts_with('/'));
+    if request.method != "OPTIONS" && !is_stream_path {
+        return Ok(Response::new("404 Not Found"));
+    }
+    let session_exists = |id: Option<&str>| {
+        let shared = shared.lock().unwrap();
+        shared.sessions.iter().any(|s| Some(s.id.as_str()) == id)
+    };
+
+    Ok(match request.method.as_str() {
+        "OPTIONS" => Response::new("200 OK")
+            .header("Public: OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER".to_string()),
+        "DESCRIBE" => {
+            let parameter_sets = shared.lock().unwrap().parameter_sets.clone();
+            let mut response = Response::new("200 OK")
+                .header("Content-Type: application/sdp".to_string())
+                .header(format!("Content-Base: {}/", request.uri.trim_end_matches('/')));
+            response.body = sdp(&parameter_sets, &stream.local_addr()?);
+            response
+        }
+        "SETUP" => setup(request, stream, shared, connection_id)?,
+        "PLAY" => {
+            let id = request.session();
+            let mut shared = shared.lock().unwrap();
+            match shared.sessions.iter_mut().find(|s| Some(s.id.as_str()) == id) {
+                Some(session) => {
+                    session.needs_preamble |= !session.playing;
+                    session.playing = true;
+                    Response::new("200 OK")
+                        .header(format!("Session: {}", session.id))
+                        .header("Range: npt=0.000-".to_string())
+                }
+                None => Response::new("454 Session Not Found"),
+            }
+        }
+        "TEARDOWN" => {
+            let id = request.session();
+            shared.lock().unwrap().sessions.retain(|s| Some(s.id.as_str()) != id);
+            Response::new("200 OK")
+        }
+        // Used by clients as keep alive
+        "GET_PARAMETER" | "SET_PARAMETER" if session_exists(request.session()) || request.session().is_none() => {
+            Response::new("200 OK")
+        }
+        "GET_PARAMETER" | "SET_PARAMETER" => Response::new("454 Session Not Found"),
+        _ => Response::new("501 Not Implemented"),
+    })
+}
+
+fn handle_connection(stream: &TcpStream, shared: &Mutex<Shared>, connection_id: u64) -> io::Result<()> {
+    // Also applies to the clones used by interleaved sessions
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream.try_clone()?;
+    while let Some(request) = read_request(&mut reader)? {
+        let response = handle_request(&request, stream, shared, connection_id)?;
+        // Interleaved RTP packets are written to the same connection while holding the lock,
+        // other connections do not need to hold up the stream while the response is written
+        let shared = shared.lock().unwrap();
+        let _shared = shared.sessions.iter()
+            .any(|session| session.connection_id == connection_id && session.is_interleaved())
+            .then_some(shared);
+        writer.write_all(&response.to_bytes(request.header("CSeq")))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::h264::test_data::{nal_unit, pps_nal_unit};
+
+    fn request(stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, request: &str) -> (String, String) {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            head.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        let content_length = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .map_or(0, |l| l.parse().unwrap());
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_interleaved_session() {
+        let mut server = RtspServer::bind("127.0.0.1:0").unwrap();
+        let sps = nal_unit(&[0x67, 0x64, 0x00, 0x28, 0xac]);
+        let pps = pps_nal_unit();
+        server.set_parameter_sets(&[sps.clone(), pps.clone()]);
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let url = format!("rtsp://{}{}", server.local_addr(), RTSP_PATH);
+
+        let (head, sdp) = request(&mut stream, &mut reader, &format!("DESCRIBE {} RTSP/1.0\r\nCSeq: 1\r\n\r\n", url));
+        assert!(head.starts_with("RTSP/1.0 200 OK\r\nCSeq: 1\r\n"));
+        assert!(sdp.contains("a=fmtp:96 packetization-mode=1;profile-level-id=640028;sprop-parameter-sets=Z2QAKKw=,aM44gA==\r\n"));
+
+        let (head, _) = request(
+            &mut stream,
+            &mut reader,
+            &format!("SETUP {}/{} RTSP/1.0\r\nCSeq: 2\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n", url, TRACK_CONTROL),
+        );
+        assert!(head.contains("Transport: RTP/AVP/TCP;unicast;interleaved=0-1;ssrc="));
+        let session = head.lines().find_map(|l| l.strip_prefix("Session: ")).unwrap();
+        let session = session.split(';').next().unwrap().to_string();
+
+        // Not playing yet
+        let p = nal_unit(&[0x41, 0x9a, 0x02]);
+        server.write_access_unit(&[sps, pps, p.clone()], Duration::ZERO).unwrap();
+
+        let (head, _) = request(&mut stream, &mut reader, &format!("PLAY {} RTSP/1.0\r\nCSeq: 3\r\nSession: {}\r\n\r\n", url, session));
+        assert!(head.starts_with("RTSP/1.0 200 OK\r\nCSeq: 3\r\n"));
+
+        server.write_access_unit(&[p], Duration::from_millis(40)).unwrap();
+        // Parameter sets seen before in a STAP-A
+        let mut frame = [0; 4 + 26];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame[..4], &[b'$', 0, 0, 26]);
+        assert_eq!(frame[16] & 0x1f, 24);
+        let mut frame = [0; 4 + 15];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame[..4], &[b'$', 0, 0, 15]);
+        assert_eq!(frame[5], 0x80 | 96);
+        assert_eq!(&frame[16..], &[0x41, 0x9a, 0x02]);
+
+        let (head, _) = request(&mut stream, &mut reader, &format!("TEARDOWN {} RTSP/1.0\r\nCSeq: 4\r\nSession: {}\r\n\r\n", url, session));
+        assert!(head.starts_with("RTSP/1.0 200 OK\r\nCSeq: 4\r\n"));
+        assert!(server.shared.lock().unwrap().sessions.is_empty());
+
+        let (head, _) = request(&mut stream, &mut reader, "DESCRIBE rtsp://localhost/other RTSP/1.0\r\nCSeq: 5\r\n\r\n");
+        assert!(head.starts_with("RTSP/1.0 404 Not Found\r\n"));
+        let (head, _) = request(&mut stream, &mut reader, &format!("DESCRIBE {}foo RTSP/1.0\r\nCSeq: 6\r\n\r\n", url));
+        assert!(head.starts_with("RTSP/1.0 404 Not Found\r\n"));
+
+        // There is no channel after 255 for RTCP
+        let (head, _) = request(
+            &mut stream,
+            &mut reader,
+            &format!("SETUP {}/{} RTSP/1.0\r\nCSeq: 7\r\nTransport: RTP/AVP/TCP;unicast;interleaved=255\r\n\r\n", url, TRACK_CONTROL),
+        );
+        assert!(head.starts_with("RTSP/1.0 461 Unsupported Transport\r\n"));
+    }
+}